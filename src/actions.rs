@@ -0,0 +1,132 @@
+// Total-rs actions and API operations
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use crate::http::Response;
+use crate::types::{ActionHandler, ActionOptions, Controller, FrameworkValue};
+use crate::utils::parse_query;
+use crate::{Framework, DEF, F};
+
+impl ActionOptions {
+    /// Returns the result of the action
+    pub fn callback<T: Into<FrameworkValue>>(mut self, value: T) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Ok(value.into()));
+        }
+    }
+
+    /// Returns `{ success: true, value: ... }`
+    pub fn success<T: Into<FrameworkValue>>(self, value: T) {
+        let result = DEF.on_success(value.into());
+        let mut output = HashMap::new();
+        output.insert("success".to_string(), FrameworkValue::Boolean(result.success));
+        output.insert("value".to_string(), result.value);
+        self.callback(FrameworkValue::Object(output));
+    }
+
+    /// Stops the action chain with `400 Bad Request`
+    pub fn invalid(mut self, error: &str) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Err(error.to_string()));
+        }
+    }
+}
+
+impl Framework {
+    /// Registers an action used by `--> Name` chains of routes and API operations
+    pub fn action<H>(&self, name: &str, handler: H)
+    where
+        H: Fn(ActionOptions) + Send + Sync + 'static,
+    {
+        self.actions.write().unwrap().insert(name.to_string(), Arc::new(handler) as ActionHandler);
+    }
+
+    /// Resolves an API request `{ "schema": "operation/{params}?query", "data": { ... } }`
+    /// into the operation route, its params, query and model
+    pub(crate) fn prepare_api(&self, controller: &mut Controller) -> Result<(), u16> {
+        self.stats.write().unwrap().request.operation += 1;
+
+        let (schema, data) = match &controller.body {
+            FrameworkValue::Object(map) => match map.get("schema") {
+                Some(FrameworkValue::String(schema)) => (schema.clone(), map.get("data").cloned().unwrap_or_default()),
+                _ => return Err(400),
+            },
+            _ => return Err(400),
+        };
+
+        let (schema, querystring) = match schema.split_once('?') {
+            Some((schema, querystring)) => (schema.to_string(), querystring.to_string()),
+            None => (schema, String::new()),
+        };
+
+        let (route, params) = self.routes.read().unwrap().find_api(&controller.path, &schema).ok_or(404u16)?;
+
+        controller.query.extend(parse_query(&querystring));
+        controller.route = Some(route);
+        controller.params = params;
+        controller.body = data;
+        Ok(())
+    }
+
+    /// Executes the action chain of the controller's route and responds with the result
+    pub(crate) async fn run_actions(&'static self, controller: Controller) {
+        let route = match controller.route.clone() {
+            Some(route) if !route.actions.is_empty() => route,
+            _ => return controller.send(Response::throw(501)),
+        };
+
+        let respond = route.response.unwrap_or(route.actions.len() - 1);
+        let mut output = FrameworkValue::Null;
+
+        for (index, name) in route.actions.iter().enumerate() {
+            let handler = self.actions.read().unwrap().get(name).cloned();
+            let handler = match handler {
+                Some(handler) => handler,
+                None => return controller.send(Response::throw(501)),
+            };
+
+            let (sender, receiver) = oneshot::channel();
+            let options = ActionOptions {
+                name: name.clone(),
+                ip: controller.ip.clone(),
+                language: controller.language.clone(),
+                params: controller.params.clone(),
+                query: controller.query.clone(),
+                model: controller.body.clone(),
+                user: controller.user.clone(),
+                sender: Some(sender),
+            };
+
+            if std::panic::catch_unwind(AssertUnwindSafe(|| handler(options))).is_err() {
+                return controller.send(Response::throw(500));
+            }
+
+            match receiver.await {
+                Ok(Ok(value)) => {
+                    if index == respond {
+                        output = value;
+                    }
+                }
+                Ok(Err(error)) => return controller.invalid(&error),
+                // The action has been dropped without a result
+                Err(_) => return controller.send(Response::throw(500)),
+            }
+        }
+
+        controller.json(output);
+    }
+}
+
+/// Registers an action on the global framework instance
+#[allow(non_snake_case)]
+pub fn NEWACTION<H>(name: &str, handler: H)
+where
+    H: Fn(ActionOptions) + Send + Sync + 'static,
+{
+    F.action(name, handler)
+}
@@ -0,0 +1,160 @@
+// Total-rs IP bans
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+
+use crate::types::Ban;
+use crate::utils::parse_expire;
+use crate::{Framework, CONF};
+
+/// File in `TPath::databases` with active bans
+const BANS_FILE: &str = "bans.json";
+
+/// Serializes writes of `BANS_FILE` so the last write contains the latest bans
+static SAVING: Mutex<()> = Mutex::const_new(());
+
+impl Framework {
+    fn bans_file(&self) -> PathBuf {
+        self.path.databases(Some(BANS_FILE))
+    }
+
+    /// Writes active bans to `TPath::databases`
+    async fn save_bans(&self) {
+        let _saving = SAVING.lock().await;
+        let bans: Vec<serde_json::Value> = self
+            .bans()
+            .into_iter()
+            .map(|ban| serde_json::json!({ "ip": ban.ip, "expires": ban.expires.to_rfc3339() }))
+            .collect();
+
+        let path = self.bans_file();
+        if let Some(parent) = path.parent() {
+            self.path.verify(parent);
+        }
+
+        if let Err(err) = tokio::fs::write(&path, serde_json::Value::Array(bans).to_string()).await {
+            eprintln!("Failed to save bans to {}: {}", path.display(), err);
+        }
+    }
+
+    /// Restores active bans saved by a previous process
+    pub(crate) fn load_bans(&self) {
+        let data = match std::fs::read_to_string(self.bans_file()) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        let items = match serde_json::from_str::<serde_json::Value>(&data) {
+            Ok(serde_json::Value::Array(items)) => items,
+            _ => return,
+        };
+
+        let now = Utc::now();
+        let mut temporary = self.temporary.write().unwrap();
+
+        for item in items {
+            let ip = item["ip"].as_str().unwrap_or_default();
+            // `parse()` also reads years after 9999 of permanent bans
+            let expires = item["expires"].as_str().and_then(|value| value.parse::<DateTime<Utc>>().ok());
+
+            if let Some(expires) = expires {
+                if !ip.is_empty() && expires > now {
+                    temporary.bans.insert(ip.to_string(), Ban { ip: ip.to_string(), expires });
+                }
+            }
+        }
+    }
+
+    /// Bans the IP for the duration, the ban survives restarts
+    pub async fn ban(&self, ip: &str, duration: Duration) {
+        // Durations beyond the range of `DateTime` (e.g. `Duration::MAX`) are permanent bans
+        let expires = chrono::Duration::from_std(duration)
+            .ok()
+            .and_then(|duration| Utc::now().checked_add_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.temporary.write().unwrap().bans.insert(ip.to_string(), Ban { ip: ip.to_string(), expires });
+        self.save_bans().await;
+    }
+
+    /// Removes the ban of the IP
+    pub async fn unban(&self, ip: &str) -> bool {
+        let removed = self.temporary.write().unwrap().bans.remove(ip).is_some();
+        if removed {
+            self.save_bans().await;
+        }
+        removed
+    }
+
+    /// Returns active bans sorted by their expiration
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = Utc::now();
+        let mut bans: Vec<Ban> = self.temporary.read().unwrap().bans.values().filter(|ban| ban.expires > now).cloned().collect();
+        bans.sort_by_key(|ban| ban.expires);
+        bans
+    }
+
+    /// Checks an active ban of the IP
+    pub fn is_banned(&self, ip: &str) -> bool {
+        self.temporary.read().unwrap().bans.get(ip).is_some_and(|ban| ban.expires > Utc::now())
+    }
+
+    /// Removes expired bans and violation counters, called periodically by the DDOS cleaner
+    pub async fn bans_cleanup(&self) {
+        let now = Utc::now();
+        let expired = {
+            let mut temporary = self.temporary.write().unwrap();
+            temporary.violations.retain(|_, (_, expires)| *expires > now);
+            let count = temporary.bans.len();
+            temporary.bans.retain(|_, ban| ban.expires > now);
+            temporary.bans.len() != count
+        };
+
+        if expired {
+            self.save_bans().await;
+        }
+    }
+
+    /// Counts 401/403/404 responses of the IP, `CONF._banlimit` responses per minute ban the IP for `CONF._banexpire`
+    pub(crate) async fn count_violation(&self, ip: &str, status: u16) {
+        if !matches!(status, 401 | 403 | 404) {
+            return;
+        }
+
+        let (limit, expire) = {
+            let conf = CONF.read().unwrap();
+            (conf._banlimit as u32, conf._banexpire.clone())
+        };
+
+        if limit == 0 {
+            return;
+        }
+
+        let now = Utc::now();
+        let exceeded = {
+            let mut temporary = self.temporary.write().unwrap();
+            let (count, expires) = temporary.violations.entry(ip.to_string()).or_insert((0, now + chrono::Duration::minutes(1)));
+
+            // The entry may outlive its minute until the next cleanup
+            if *expires <= now {
+                *count = 0;
+                *expires = now + chrono::Duration::minutes(1);
+            }
+
+            *count += 1;
+
+            let exceeded = *count >= limit;
+            if exceeded {
+                temporary.violations.remove(ip);
+            }
+            exceeded
+        };
+
+        if exceeded {
+            self.ban(ip, parse_expire(&expire).unwrap_or(Duration::from_secs(15 * 60))).await;
+        }
+    }
+}
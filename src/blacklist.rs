@@ -0,0 +1,155 @@
+// Total-rs IP blacklist
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::net::IpAddr;
+
+use crate::types::{Blacklist, FrameworkValue};
+use crate::{Framework, CONF};
+
+/// An IP address or a CIDR range, e.g. `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse::<u8>().ok()?)),
+            None => (value, None),
+        };
+
+        let network = address.parse::<IpAddr>().ok()?.to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+
+        (prefix <= bits).then_some(Self { network, prefix })
+    }
+
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a list of IPs and ranges separated by commas, semicolons or whitespace, invalid entries are skipped
+pub(crate) fn parse_blacklist(value: &str) -> Vec<IpRange> {
+    value
+        .split([',', ';', ' ', '\n', '\t'])
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(IpRange::parse)
+        .collect()
+}
+
+impl Framework {
+    /// Blocks an IP or a CIDR range until it's unblocked, returns `false` for an invalid entry
+    pub fn block(&self, value: &str) -> bool {
+        if IpRange::parse(value).is_none() {
+            return false;
+        }
+        self.temporary.write().unwrap().blocked.insert(value.trim().to_string(), FrameworkValue::Boolean(true));
+        self.refresh_blocked();
+        true
+    }
+
+    /// Removes an entry added by `block()`
+    pub fn unblock(&self, value: &str) -> bool {
+        let removed = self.temporary.write().unwrap().blocked.remove(value.trim()).is_some();
+        if removed {
+            self.refresh_blocked();
+        }
+        removed
+    }
+
+    /// Parses `Temporary.blocked` into `Blacklist.blocked`
+    fn refresh_blocked(&self) {
+        let blocked = self.temporary.read().unwrap().blocked.keys().filter_map(|entry| IpRange::parse(entry)).collect();
+        self.blacklist.write().unwrap().blocked = blocked;
+    }
+
+    /// Checks the IP against `CONF._blacklist` and `Temporary.blocked`,
+    /// `CONF._blacklist` is parsed again only when it has been changed
+    pub fn is_blocked(&self, ip: &str) -> bool {
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return false,
+        };
+
+        let matches = |blacklist: &Blacklist| blacklist.configured.iter().chain(&blacklist.blocked).any(|range| range.contains(&ip));
+
+        {
+            let conf = CONF.read().unwrap();
+            let blacklist = self.blacklist.read().unwrap();
+            if blacklist.source == conf._blacklist {
+                return matches(&blacklist);
+            }
+        }
+
+        let source = CONF.read().unwrap()._blacklist.clone();
+        let mut blacklist = self.blacklist.write().unwrap();
+        blacklist.configured = parse_blacklist(&source);
+        blacklist.source = source;
+        matches(&blacklist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_addresses_and_ranges() {
+        let ranges = parse_blacklist("192.168.1.10, 10.0.0.0/8; 2001:db8::/32\n::1 invalid 1.2.3.4/33");
+        assert_eq!(ranges.len(), 4);
+
+        let blocked = |value: &str| ranges.iter().any(|range| range.contains(&ip(value)));
+
+        assert!(blocked("192.168.1.10"));
+        assert!(!blocked("192.168.1.11"));
+        assert!(blocked("10.255.0.1"));
+        assert!(!blocked("11.0.0.1"));
+        assert!(blocked("2001:db8:abcd::1"));
+        assert!(!blocked("2001:db9::1"));
+        assert!(blocked("::1"));
+
+        // IPv4-mapped IPv6 addresses
+        assert!(blocked("::ffff:10.1.2.3"));
+    }
+
+    #[test]
+    fn handles_edge_prefixes() {
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(IpRange::parse("::/0").unwrap().contains(&ip("2001:db8::1")));
+        assert!(!IpRange::parse("::/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(IpRange::parse("1.2.3.4/32").unwrap().contains(&ip("1.2.3.4")));
+        assert!(IpRange::parse("1.2.3.4/x").is_none());
+    }
+
+    #[test]
+    fn caches_runtime_entries() {
+        let framework = Framework::default();
+        assert!(framework.block("172.16.0.0/12"));
+        assert_eq!(framework.blacklist.read().unwrap().blocked.len(), 1);
+        assert!(framework.is_blocked("172.20.1.1"));
+
+        assert!(framework.unblock("172.16.0.0/12"));
+        assert!(framework.blacklist.read().unwrap().blocked.is_empty());
+        assert!(!framework.is_blocked("172.20.1.1"));
+    }
+}
@@ -0,0 +1,234 @@
+// Total-rs response compression
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+
+use crate::http::{Response, ResponseBody};
+use crate::{Framework, CONF};
+
+/// Smaller bodies are sent as they are
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Larger bodies are compressed on the blocking thread pool
+const BLOCKING_COMPRESS_SIZE: usize = 64 * 1024;
+
+/// Supported `Content-Encoding` values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub(crate) const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn ext(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Picks the best encoding accepted by the client (`br` > `gzip` > `deflate`), `q=0` refuses an encoding
+    pub(crate) fn negotiate(accept: &str) -> Option<Encoding> {
+        let mut accepted = Vec::new();
+
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_lowercase();
+            let refused = parts.any(|param| {
+                let param = param.trim().replace(' ', "");
+                param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+            });
+
+            if !refused {
+                accepted.push(name);
+            }
+        }
+
+        let wildcard = accepted.iter().any(|name| name == "*");
+        Encoding::ALL
+            .into_iter()
+            .find(|encoding| wildcard || accepted.iter().any(|name| name == encoding.name()))
+    }
+
+    /// Tags a compressed variant of a resource, `"abc"` becomes `"abc-br"`
+    pub(crate) fn etag(&self, etag: &str) -> String {
+        match etag.strip_suffix('"') {
+            Some(tag) => format!("{}-{}\"", tag, self.name()),
+            None => format!("{}-{}", etag, self.name()),
+        }
+    }
+
+    /// Compresses data, `best` is used for cached static files
+    pub(crate) fn encode(&self, data: &[u8], best: bool) -> io::Result<Vec<u8>> {
+        self.encode_to(data, Vec::new(), best)
+    }
+
+    /// Compresses everything from the reader into the writer and returns the writer
+    pub(crate) fn encode_to<R, W>(&self, mut reader: R, writer: W, best: bool) -> io::Result<W>
+    where
+        R: Read,
+        W: Write,
+    {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(writer, 4096, if best { 11 } else { 5 }, 22);
+                io::copy(&mut reader, &mut writer)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(writer, if best { Compression::best() } else { Compression::default() });
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(writer, if best { Compression::best() } else { Compression::default() });
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Textual content types worth compressing, images, archives and media are already compressed
+pub(crate) fn compressible(content_type: &str) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    content_type.starts_with("text/")
+        || content_type.ends_with("/json")
+        || content_type.ends_with("/javascript")
+        || content_type.ends_with("/xml")
+        || content_type.ends_with("+xml")
+        || content_type.ends_with("+json")
+        || content_type == "application/wasm"
+}
+
+impl Framework {
+    /// Compresses the response according to `Accept-Encoding` when `CONF._httpcompress` is enabled
+    pub(crate) async fn compress(&self, accept: Option<&str>, mut response: Response) -> Response {
+        if !CONF.read().unwrap()._httpcompress || response.status != 200 || response.header("content-encoding").is_some() {
+            return response;
+        }
+
+        if !compressible(response.header("content-type").unwrap_or_default()) {
+            return response;
+        }
+
+        let size = match &response.body {
+            ResponseBody::Bytes(bytes) => bytes.len() as u64,
+            ResponseBody::File { offset: 0, length, .. } => *length,
+            _ => return response,
+        };
+
+        if size < MIN_COMPRESS_SIZE as u64 {
+            return response;
+        }
+
+        response.vary("Accept-Encoding");
+
+        let encoding = match accept.and_then(Encoding::negotiate) {
+            Some(encoding) => encoding,
+            None => return response,
+        };
+
+        match &mut response.body {
+            ResponseBody::Bytes(bytes) => {
+                let data = std::mem::take(bytes);
+                let (result, data) = if data.len() < BLOCKING_COMPRESS_SIZE {
+                    (encoding.encode(&data, false), data)
+                } else {
+                    match tokio::task::spawn_blocking(move || (encoding.encode(&data, false), data)).await {
+                        Ok(done) => done,
+                        Err(_) => return Response::new(500),
+                    }
+                };
+
+                match result {
+                    Ok(compressed) => *bytes = compressed,
+                    Err(_) => {
+                        *bytes = data;
+                        return response;
+                    }
+                }
+            }
+            ResponseBody::File { path, length, .. } => match self.precompressed(path, encoding).await {
+                Ok((cached, size)) => {
+                    *path = cached;
+                    *length = size;
+                }
+                Err(_) => return response,
+            },
+            _ => return response,
+        }
+
+        // The compressed variant must not share the ETag of the identity one
+        if let Some(etag) = response.header("etag").map(|etag| encoding.etag(etag)) {
+            response.set_header("ETag", &etag);
+        }
+
+        response.set_header("Content-Encoding", encoding.name());
+        response
+    }
+
+    /// Returns the compressed copy of a static file from `TPath::tmp`, the file is compressed only once per version
+    async fn precompressed(&self, path: &Path, encoding: Encoding) -> io::Result<(PathBuf, u64)> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+
+        let directory = self.path.tmp(Some("compressed"));
+        let cached = directory.join(format!("{:x}-{:x}-{:x}.{}", hasher.finish(), modified.as_millis(), metadata.len(), encoding.ext()));
+
+        if let Ok(metadata) = tokio::fs::metadata(&cached).await {
+            return Ok((cached, metadata.len()));
+        }
+
+        // Concurrent requests write their own copy, the rename is atomic
+        self.path.verify(&directory);
+        let nanos = std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let partial = cached.with_extension(format!("{}.{:x}", encoding.ext(), nanos));
+
+        // The file is streamed through the encoder, it's never read into memory as a whole
+        let (source, target) = (path.to_path_buf(), partial.clone());
+        let written = tokio::task::spawn_blocking(move || -> io::Result<u64> {
+            let reader = io::BufReader::new(File::open(source)?);
+            let mut writer = encoding.encode_to(reader, io::BufWriter::new(File::create(target)?), true)?;
+            writer.flush()?;
+            writer.get_ref().metadata().map(|metadata| metadata.len())
+        })
+        .await
+        .map_err(io::Error::other)
+        .and_then(|result| result);
+
+        let size = match written {
+            Ok(size) => size,
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(err);
+            }
+        };
+
+        tokio::fs::rename(&partial, &cached).await?;
+        Ok((cached, size))
+    }
+}
@@ -0,0 +1,392 @@
+// Total-rs controller
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::http::{strip_newlines, Request, Response, ResponseBody};
+use crate::types::{AuthHandler, Controller, FrameworkValue, Route, RouteAuth};
+use crate::utils::{content_type, decode_uri, parse_query};
+use crate::session::run_blocking;
+use crate::xml::to_xml;
+use crate::{Framework, CONF, DEF, F};
+
+/// Adds `charset=utf-8` to textual content types
+pub(crate) fn with_charset(content_type: &str) -> String {
+    if content_type.contains("charset") {
+        return content_type.to_string();
+    }
+
+    let textual = content_type.starts_with("text/")
+        || content_type.ends_with("/json")
+        || content_type.ends_with("/xml")
+        || content_type.ends_with("+xml")
+        || content_type.ends_with("/javascript");
+
+    if textual {
+        format!("{}; charset=utf-8", content_type)
+    } else {
+        content_type.to_string()
+    }
+}
+
+/// Verifies the keys of a query string or an urlencoded body against `CONF._httpmaxkeys` and `CONF._httpmaxkey`
+pub(crate) fn verify_keys(text: &str) -> Result<(), u16> {
+    let (maxkeys, maxkey) = {
+        let conf = CONF.read().unwrap();
+        (conf._httpmaxkeys, conf._httpmaxkey)
+    };
+
+    let mut count = 0;
+    for pair in text.split('&').filter(|pair| !pair.is_empty()) {
+        count += 1;
+        let key = pair.split_once('=').map(|(key, _)| key).unwrap_or(pair);
+        if count > maxkeys || decode_uri(&key.replace('+', " ")).chars().count() > maxkey {
+            return Err(400);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses the request body according to its content type with `DEF.parsers`
+pub(crate) fn parse_body(content_type: &str, payload: &[u8]) -> Result<FrameworkValue, u16> {
+    if payload.is_empty() {
+        return Ok(FrameworkValue::Null);
+    }
+
+    let content_type = content_type.to_lowercase();
+
+    if content_type.contains("/json") {
+        let text = std::str::from_utf8(payload).map_err(|_| 400u16)?;
+        let value = (DEF.parsers.json)(text).map_err(|_| 400u16)?;
+        Ok(FrameworkValue::from(value))
+    } else if content_type.contains("x-www-form-urlencoded") {
+        let text = std::str::from_utf8(payload).map_err(|_| 400u16)?;
+        (DEF.parsers.urlencoded)(text).map_err(|_| 400u16)
+    } else if content_type.contains("/xml") {
+        let text = std::str::from_utf8(payload).map_err(|_| 400u16)?;
+        (DEF.parsers.xml)(text).map_err(|_| 400u16)
+    } else {
+        Ok(FrameworkValue::Null)
+    }
+}
+
+/// Parses the `Cookie` header, values are percent-decoded
+pub(crate) fn parse_cookies(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), decode_uri(value.trim().trim_matches('"'))))
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+impl Controller {
+    /// Creates the controller of a request, the session is loaded separately by `load_session()`
+    pub(crate) fn new(framework: &'static Framework, mut request: Request, route: Option<Arc<Route>>, params: HashMap<String, FrameworkValue>, sender: oneshot::Sender<Response>) -> Result<Self, u16> {
+        let body = if request.files.is_empty() && request.fields.is_empty() {
+            parse_body(request.header("content-type").unwrap_or_default(), &request.body)?
+        } else {
+            FrameworkValue::Object(std::mem::take(&mut request.fields).into_iter().map(|(key, value)| (key, FrameworkValue::String(value))).collect())
+        };
+        let cookies = request.header("cookie").map(parse_cookies).unwrap_or_default();
+
+        let language = request
+            .header("accept-language")
+            .and_then(|value| value.split([',', ';', '-']).next())
+            .map(|value| value.trim().to_lowercase())
+            .unwrap_or_default();
+
+        Ok(Self {
+            ip: request.ip.clone(),
+            method: request.method.clone(),
+            url: request.url.clone(),
+            path: request.path.clone(),
+            query: parse_query(&request.querystring),
+            params,
+            body,
+            cookies,
+            files: std::mem::take(&mut request.files),
+            user: None,
+            session: None,
+            language,
+            mobile: request.mobile(),
+            xhr: request.xhr(),
+            route,
+            status: 200,
+            response_headers: Vec::new(),
+            session_id: None,
+            framework,
+            sender: Some(sender),
+            payload: request.body,
+            headers: request.headers,
+        })
+    }
+
+    /// Returns a typed route parameter
+    pub fn param(&self, name: &str) -> Option<&FrameworkValue> {
+        self.params.get(name)
+    }
+
+    /// Returns a request header, the name is case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|value| value.as_str())
+    }
+
+    /// Returns a value of the parsed body (objects only)
+    pub fn field(&self, name: &str) -> Option<&FrameworkValue> {
+        match &self.body {
+            FrameworkValue::Object(map) => map.get(name),
+            _ => None,
+        }
+    }
+
+    /// Sets a header of the response, CR and LF are removed from the name and the value
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let name = strip_newlines(name);
+        self.response_headers.retain(|(key, _)| !key.eq_ignore_ascii_case(&name));
+        self.response_headers.push((name, strip_newlines(value)));
+    }
+
+    /// Sends a prepared response to the client
+    pub fn send(mut self, mut response: Response) {
+        let update = self.save_session();
+
+        for (name, value) in self.response_headers.drain(..) {
+            if name.eq_ignore_ascii_case("set-cookie") {
+                response.append_header(&name, &value);
+            } else {
+                response.set_header(&name, &value);
+            }
+        }
+
+        let sender = self.sender.take();
+        let deliver = move || {
+            if let Some(sender) = sender {
+                let _ = sender.send(response);
+            }
+        };
+
+        // The next request of the client must find the stored session
+        match update {
+            Some(update) => run_blocking(move || {
+                update();
+                deliver();
+            }),
+            None => deliver(),
+        }
+    }
+
+    /// Sets the status code used by `json()`, `html()`, `plain()`, `xml()` and `binary()`
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    fn respond(self, content_type: &str, body: Vec<u8>) {
+        let status = self.status;
+        self.send(Response::with_body(status, &with_charset(content_type), body));
+    }
+
+    /// Responds with JSON
+    pub fn json<T: Into<serde_json::Value>>(self, value: T) {
+        self.framework.stats.write().unwrap().response.json += 1;
+        let body = serde_json::to_vec(&value.into()).unwrap_or_default();
+        self.respond("application/json", body);
+    }
+
+    /// Responds with `{ success: true, value: ... }`
+    pub fn success<T: Into<serde_json::Value>>(self, value: T) {
+        let result = DEF.on_success(value.into());
+        self.json(serde_json::json!({ "success": result.success, "value": result.value }));
+    }
+
+    /// Responds with HTML
+    pub fn html(self, body: impl Into<String>) {
+        self.framework.stats.write().unwrap().response.html += 1;
+        self.respond("text/html", body.into().into_bytes());
+    }
+
+    /// Responds with plain text
+    pub fn plain(self, body: impl Into<String>) {
+        self.framework.stats.write().unwrap().response.text += 1;
+        self.respond("text/plain", body.into().into_bytes());
+    }
+
+    /// Responds with XML
+    pub fn xml(self, body: impl Into<String>) {
+        self.framework.stats.write().unwrap().response.xml += 1;
+        self.respond("text/xml", body.into().into_bytes());
+    }
+
+    /// Responds with a value serialized as XML by `to_xml()`
+    pub fn xml_value(self, root: &str, value: impl Into<FrameworkValue>) {
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>{}", to_xml(root, &value.into()));
+        self.xml(body);
+    }
+
+    /// Responds with binary data
+    pub fn binary(self, data: Vec<u8>, content_type: &str) {
+        self.framework.stats.write().unwrap().response.binary += 1;
+        self.respond(content_type, data);
+    }
+
+    /// Responds with a file from the disk, `download` sets the name of the attachment
+    pub fn file(mut self, path: impl AsRef<Path>, download: Option<&str>) {
+        let path = path.as_ref();
+
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return self.throw404(),
+        };
+
+        self.framework.stats.write().unwrap().response.file += 1;
+
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        if let Some(name) = download {
+            self.set_header("Content-Disposition", &format!("attachment; filename=\"{}\"", name.replace('"', "")));
+        }
+
+        let mut response = Response::new(200);
+        response.set_header("Content-Type", &with_charset(content_type(ext)));
+        response.set_header("Accept-Ranges", "bytes");
+        response.body = ResponseBody::File {
+            path: path.to_path_buf(),
+            offset: 0,
+            length: metadata.len(),
+        };
+
+        let response = match self.header("range") {
+            Some(range) => self.framework.partial(range, response),
+            None => response,
+        };
+        self.send(response);
+    }
+
+    /// Opens a chunked response, the response ends when the returned sender is dropped
+    pub fn stream(self, content_type: &str) -> mpsc::Sender<Vec<u8>> {
+        self.framework.stats.write().unwrap().response.stream += 1;
+
+        let (sender, receiver) = mpsc::channel(16);
+        let mut response = Response::new(200);
+        response.set_header("Content-Type", &with_charset(content_type));
+        response.body = ResponseBody::Stream(receiver, None);
+        self.send(response);
+        sender
+    }
+
+    /// Redirects the client (302, or 301 when `permanent`)
+    pub fn redirect(mut self, url: &str, permanent: bool) {
+        self.framework.stats.write().unwrap().response.redirect += 1;
+        self.set_header("Location", url);
+        self.send(Response::new(if permanent { 301 } else { 302 }));
+    }
+
+    /// Responds with `204 No Content`
+    pub fn empty(self) {
+        self.framework.stats.write().unwrap().response.empty += 1;
+        self.send(Response::new(204));
+    }
+
+    /// Responds with `400 Bad Request` and a Total.js error list `[{ "error": "..." }]`
+    pub fn invalid(self, error: &str) {
+        self.framework.stats.write().unwrap().response.errorbuilder += 1;
+        let body = serde_json::to_vec(&serde_json::json!([{ "error": error }])).unwrap_or_default();
+        self.send(Response::with_body(400, &with_charset("application/json"), body));
+    }
+
+    fn throw(self, status: u16) {
+        self.send(Response::throw(status));
+    }
+
+    pub fn throw401(self) {
+        self.throw(401);
+    }
+
+    pub fn throw403(self) {
+        self.throw(403);
+    }
+
+    pub fn throw404(self) {
+        self.throw(404);
+    }
+
+    pub fn throw409(self) {
+        self.throw(409);
+    }
+
+    pub fn throw500(self) {
+        self.throw(500);
+    }
+}
+
+impl Framework {
+    /// Registers the delegate resolving `Controller.user`, routes declared with `+` require a user
+    pub fn on_auth<H>(&self, handler: H)
+    where
+        H: Fn(&Controller) -> Option<FrameworkValue> + Send + Sync + 'static,
+    {
+        *self.auth.write().unwrap() = Some(Arc::new(handler) as AuthHandler);
+    }
+
+    /// Resolves `Controller.user` with the `on_auth()` delegate and checks the route requirement
+    pub(crate) fn authorize(&self, controller: &mut Controller, auth: &RouteAuth) -> Result<(), u16> {
+        let handler = self.auth.read().unwrap().clone();
+        if let Some(handler) = handler {
+            controller.user = std::panic::catch_unwind(AssertUnwindSafe(|| handler(controller))).map_err(|_| 500u16)?;
+        }
+
+        let allowed = match auth {
+            RouteAuth::Any => true,
+            RouteAuth::Authorized => controller.user.is_some(),
+            RouteAuth::Unauthorized => controller.user.is_none(),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(401)
+        }
+    }
+}
+
+/// Registers the authorization delegate on the global framework instance
+#[allow(non_snake_case)]
+pub fn AUTH<H>(handler: H)
+where
+    H: Fn(&Controller) -> Option<FrameworkValue> + Send + Sync + 'static,
+{
+    F.on_auth(handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_keys() {
+        assert_eq!(verify_keys(""), Ok(()));
+        assert_eq!(verify_keys("page=2&&sort=name&flag"), Ok(()));
+        assert_eq!(verify_keys(&"a=1&".repeat(33)), Ok(()));
+        assert_eq!(verify_keys(&"a=1&".repeat(34)), Err(400));
+
+        // Key lengths are counted after decoding
+        assert_eq!(verify_keys(&format!("{}=1", "%C3%A1".repeat(25))), Ok(()));
+        assert_eq!(verify_keys(&format!("{}=1", "k".repeat(26))), Err(400));
+        assert_eq!(verify_keys(&format!("{}=1", "+".repeat(26))), Err(400));
+    }
+
+    #[test]
+    fn parses_cookies() {
+        let cookies = parse_cookies(" lang=sk; name=\"Peter%20Sirka\";invalid; =empty; theme= ");
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["lang"], "sk");
+        assert_eq!(cookies["name"], "Peter Sirka");
+        assert_eq!(cookies["theme"], "");
+        assert!(parse_cookies("").is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+// Total-rs cookies
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+
+use crate::http::HTTP_DATE;
+use crate::types::{Controller, CookieOptions};
+use crate::utils::parse_expire;
+use crate::CONF;
+
+/// Length of the AES-GCM nonce prepended to encrypted cookies
+const NONCE_SIZE: usize = 12;
+
+/// Used when `CONF.secret` is empty, signed and encrypted cookies don't survive restarts then
+static FALLBACK_SECRET: Lazy<String> = Lazy::new(|| URL_SAFE_NO_PAD.encode(Aes256Gcm::generate_key(OsRng)));
+
+fn secret() -> String {
+    let secret = CONF.read().unwrap().secret.clone();
+    if secret.is_empty() {
+        FALLBACK_SECRET.clone()
+    } else {
+        secret
+    }
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length")
+}
+
+/// Derives a key for one purpose so signing and encryption never share a key
+fn derive(secret: &str, purpose: &str) -> [u8; 32] {
+    let mut mac = hmac(secret.as_bytes());
+    mac.update(purpose.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// The signature covers the name as well, so a value can't be moved to another cookie
+fn signature(name: &str, value: &str, secret: &str) -> Hmac<Sha256> {
+    let mut mac = hmac(&derive(secret, "cookie-signature"));
+    mac.update(name.as_bytes());
+    mac.update(b"=");
+    mac.update(value.as_bytes());
+    mac
+}
+
+/// Appends an HMAC-SHA256 signature: `value.signature`
+pub(crate) fn sign(name: &str, value: &str, secret: &str) -> String {
+    format!("{}.{}", value, URL_SAFE_NO_PAD.encode(signature(name, value, secret).finalize().into_bytes()))
+}
+
+/// Returns the value of a signed cookie, `None` if the value or the signature has been tampered with
+pub(crate) fn unsign(name: &str, value: &str, secret: &str) -> Option<String> {
+    let (value, sign) = value.rsplit_once('.')?;
+    let sign = URL_SAFE_NO_PAD.decode(sign).ok()?;
+    signature(name, value, secret).verify_slice(&sign).ok()?;
+    Some(value.to_string())
+}
+
+fn cipher(secret: &str) -> Aes256Gcm {
+    Aes256Gcm::new(&derive(secret, "cookie-encryption").into())
+}
+
+/// Encrypts a value with AES-256-GCM: `base64url(nonce + ciphertext + tag)`, `name` is authenticated too
+pub(crate) fn seal(name: &str, value: &str, secret: &str) -> String {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let encrypted = cipher(secret)
+        .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: name.as_bytes() })
+        .expect("encryption of in-memory data can't fail");
+
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&encrypted);
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// Decrypts a value created by `seal()`, `None` if it has been tampered with
+pub(crate) fn open(name: &str, value: &str, secret: &str) -> Option<String> {
+    let data = URL_SAFE_NO_PAD.decode(value).ok()?;
+    if data.len() < NONCE_SIZE {
+        return None;
+    }
+
+    let (nonce, encrypted) = data.split_at(NONCE_SIZE);
+    let decrypted = cipher(secret).decrypt(Nonce::from_slice(nonce), Payload { msg: encrypted, aad: name.as_bytes() }).ok()?;
+    String::from_utf8(decrypted).ok()
+}
+
+/// Percent-encodes characters which aren't allowed in cookie values
+fn encode_value(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&'()*+-./:<=>?@[]^_`{|}~".contains(&byte) {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    output
+}
+
+/// Creates the `Set-Cookie` header value
+fn serialize(name: &str, value: &str, expires: Option<DateTime<Utc>>, options: &CookieOptions) -> String {
+    let (samesite, secure) = {
+        let conf = CONF.read().unwrap();
+        (options.samesite.clone().unwrap_or_else(|| conf._cookiesamesite.clone()), options.secure.unwrap_or(conf._cookiesecure))
+    };
+
+    let mut cookie = format!("{}={}; Path={}", name, encode_value(value), options.path.as_deref().unwrap_or("/"));
+
+    if let Some(expires) = expires {
+        cookie.push_str(&format!("; Expires={}", expires.format(HTTP_DATE)));
+    }
+
+    if let Some(domain) = &options.domain {
+        cookie.push_str(&format!("; Domain={}", domain));
+    }
+
+    if !samesite.is_empty() {
+        cookie.push_str(&format!("; SameSite={}", samesite));
+    }
+
+    // Browsers reject `SameSite=None` without `Secure`
+    if secure || samesite.eq_ignore_ascii_case("none") {
+        cookie.push_str("; Secure");
+    }
+
+    if options.httponly.unwrap_or(true) {
+        cookie.push_str("; HttpOnly");
+    }
+
+    cookie
+}
+
+impl Controller {
+    /// Returns a cookie of the request
+    pub fn get_cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(|value| value.as_str())
+    }
+
+    /// Returns a cookie set by `cookie_signed()`, `None` if it has been tampered with
+    pub fn get_cookie_signed(&self, name: &str) -> Option<String> {
+        unsign(name, self.cookies.get(name)?, &secret())
+    }
+
+    /// Returns a cookie set by `cookie_encrypted()`, `None` if it has been tampered with
+    pub fn get_cookie_encrypted(&self, name: &str) -> Option<String> {
+        open(name, self.cookies.get(name)?, &secret())
+    }
+
+    /// Sets a cookie, `expires` is e.g. `"1 day"` or empty for a session cookie
+    pub fn cookie(&mut self, name: &str, value: &str, expires: &str, options: CookieOptions) {
+        let expires = parse_expire(expires).and_then(|expires| chrono::Duration::from_std(expires).ok()).map(|expires| Utc::now() + expires);
+        let cookie = serialize(name, value, expires, &options);
+        self.response_headers.push(("Set-Cookie".to_string(), cookie));
+    }
+
+    /// Sets a cookie signed with `CONF.secret`, the value stays readable by the client
+    pub fn cookie_signed(&mut self, name: &str, value: &str, expires: &str, options: CookieOptions) {
+        let value = sign(name, value, &secret());
+        self.cookie(name, &value, expires, options);
+    }
+
+    /// Sets a cookie encrypted with `CONF.secret`
+    pub fn cookie_encrypted(&mut self, name: &str, value: &str, expires: &str, options: CookieOptions) {
+        let value = seal(name, value, &secret());
+        self.cookie(name, &value, expires, options);
+    }
+
+    /// Removes a cookie in the client, `options` must match the path and the domain of the cookie
+    pub fn remove_cookie(&mut self, name: &str, options: CookieOptions) {
+        let cookie = serialize(name, "", Some(DateTime::UNIX_EPOCH), &options);
+        self.response_headers.push(("Set-Cookie".to_string(), cookie));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_tampered_signed_cookies() {
+        let signed = sign("user", "15", "secret");
+        assert!(signed.starts_with("15."));
+        assert_eq!(unsign("user", &signed, "secret").as_deref(), Some("15"));
+
+        assert_eq!(unsign("user", &signed.replacen("15", "16", 1), "secret"), None);
+        assert_eq!(unsign("user", &format!("{}x", signed), "secret"), None);
+        assert_eq!(unsign("admin", &signed, "secret"), None);
+        assert_eq!(unsign("user", &signed, "other"), None);
+        assert_eq!(unsign("user", "15", "secret"), None);
+    }
+
+    #[test]
+    fn detects_tampered_encrypted_cookies() {
+        let sealed = seal("session", "id=5; role=admin", "secret");
+        assert!(!sealed.contains("admin"));
+        assert_ne!(sealed, seal("session", "id=5; role=admin", "secret"));
+        assert_eq!(open("session", &sealed, "secret").as_deref(), Some("id=5; role=admin"));
+
+        let mut data = URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+        data[NONCE_SIZE] ^= 1;
+        assert_eq!(open("session", &URL_SAFE_NO_PAD.encode(data), "secret"), None);
+        assert_eq!(open("other", &sealed, "secret"), None);
+        assert_eq!(open("session", &sealed, "other"), None);
+        assert_eq!(open("session", "short", "secret"), None);
+    }
+
+    #[test]
+    fn serializes_attributes() {
+        let options = CookieOptions { domain: Some("totaljs.com".to_string()), samesite: Some("None".to_string()), httponly: Some(false), ..Default::default() };
+        assert_eq!(serialize("lang", "en sk;", None, &options), "lang=en%20sk%3B; Path=/; Domain=totaljs.com; SameSite=None; Secure");
+
+        let options = CookieOptions { path: Some("/admin/".to_string()), samesite: Some("Strict".to_string()), secure: Some(true), ..Default::default() };
+        assert_eq!(
+            serialize("id", "1", Some(DateTime::UNIX_EPOCH), &options),
+            "id=1; Path=/admin/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; SameSite=Strict; Secure; HttpOnly"
+        );
+    }
+}
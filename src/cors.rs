@@ -0,0 +1,207 @@
+// Total-rs CORS
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use crate::http::{Request, Response};
+use crate::routing::normalize;
+use crate::types::Cors;
+use crate::{Framework, CONF, F};
+
+/// Lifetime of preflight results in browsers (seconds)
+const CORS_MAXAGE: u32 = 600;
+
+/// Splits an origin into the scheme, the host and the port
+fn split_origin(origin: &str) -> (Option<&str>, &str, Option<&str>) {
+    let (scheme, rest) = match origin.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, origin),
+    };
+
+    let rest = rest.trim_end_matches('/');
+    match rest.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => (scheme, host, Some(port)),
+        _ => (scheme, rest, None),
+    }
+}
+
+/// Checks the `Origin` header against allowed origins, `*.domain.com` allows all subdomains
+/// and entries without a scheme or a port allow any scheme or port
+pub(crate) fn allowed_origin(origins: &[String], origin: &str) -> bool {
+    let (scheme, host, port) = split_origin(origin);
+
+    origins.iter().any(|allowed| {
+        if allowed == "*" {
+            return true;
+        }
+
+        let (allowed_scheme, allowed_host, allowed_port) = split_origin(allowed);
+
+        if allowed_scheme.is_some_and(|allowed| Some(allowed) != scheme) || allowed_port.is_some_and(|allowed| Some(allowed) != port) {
+            return false;
+        }
+
+        match allowed_host.strip_prefix("*.") {
+            Some(domain) => host.len() > domain.len() + 1 && host.to_lowercase().ends_with(&format!(".{}", domain.to_lowercase())),
+            None => host.eq_ignore_ascii_case(allowed_host),
+        }
+    })
+}
+
+/// Parses a comma-separated list of origins
+fn parse_origins(value: &str) -> Vec<String> {
+    value.split(',').map(|origin| origin.trim().to_string()).filter(|origin| !origin.is_empty()).collect()
+}
+
+impl Cors {
+    fn matches(&self, path: &str) -> bool {
+        match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        }
+    }
+
+    fn wildcard(&self) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*")
+    }
+
+    /// Adds `Access-Control-Allow-Origin` and related headers
+    fn apply(&self, origin: &str, response: &mut Response) {
+        // Credentials are never allowed for `*`, see `Framework::cors()`
+        if self.wildcard() {
+            response.set_header("Access-Control-Allow-Origin", "*");
+        } else {
+            response.set_header("Access-Control-Allow-Origin", origin);
+        }
+
+        if self.credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl Framework {
+    /// Registers a CORS policy for a path, e.g. `F.cors("/api/*", &["https://*.totaljs.com"], true)`,
+    /// the most specific policy overrides `CONF._cors`. Credentials can't be allowed for the `*` origin.
+    pub fn cors(&self, path: &str, origins: &[&str], credentials: bool) {
+        if credentials && origins.iter().any(|origin| origin.trim() == "*") {
+            panic!("CORS(\"{}\"): credentials can't be allowed for all origins (\"*\")", path);
+        }
+
+        let wildcard = path.trim_end().ends_with('*');
+        let mut path = normalize(path.trim_end().trim_end_matches('*'));
+        if wildcard {
+            path.push('*');
+        }
+
+        let mut routes = self.routes.write().unwrap();
+        routes.cors.retain(|cors| cors.path != path);
+        routes.cors.push(Cors {
+            path,
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            credentials,
+        });
+
+        // Exact paths first, then longer prefixes
+        routes.cors.sort_by_key(|cors| (cors.path.ends_with('*'), std::cmp::Reverse(cors.path.len())));
+    }
+
+    /// Returns the CORS policy of the request path and the `Origin` of the request when it's allowed
+    pub(crate) fn cors_policy(&self, request: &Request) -> Option<(Cors, Option<String>)> {
+        let path = normalize(&request.path);
+
+        let policy = self.routes.read().unwrap().cors.iter().find(|cors| cors.matches(&path)).cloned();
+        let policy = match policy {
+            Some(policy) => policy,
+            None => {
+                let origins = parse_origins(&CONF.read().unwrap()._cors);
+                if origins.is_empty() {
+                    return None;
+                }
+                Cors { path, origins, credentials: false }
+            }
+        };
+
+        let origin = request.header("origin").filter(|origin| allowed_origin(&policy.origins, origin)).map(String::from);
+        Some((policy, origin))
+    }
+
+    /// Answers `OPTIONS` preflight requests of origins allowed by `CONF._cors` or `CORS()`
+    pub(crate) fn preflight(&self, request: &Request) -> Option<Response> {
+        if request.method != "OPTIONS" || request.header("origin").is_none() {
+            return None;
+        }
+
+        let method = request.header("access-control-request-method")?;
+
+        let (policy, origin) = match self.cors_policy(request) {
+            Some((policy, Some(origin))) => (policy, origin),
+            _ => return Some(Response::throw(403)),
+        };
+
+        let mut response = Response::new(204);
+        policy.apply(&origin, &mut response);
+        response.set_header("Access-Control-Allow-Methods", &method.to_uppercase());
+
+        if let Some(headers) = request.header("access-control-request-headers") {
+            response.set_header("Access-Control-Allow-Headers", headers);
+        }
+
+        response.set_header("Access-Control-Max-Age", &CORS_MAXAGE.to_string());
+        Some(response)
+    }
+
+    /// Adds CORS headers to the response of an allowed origin, all responses of a policy
+    /// without `*` vary by `Origin` so shared caches keep the variants apart
+    pub(crate) fn cors_headers(&self, policy: Option<(Cors, Option<String>)>, response: &mut Response) {
+        if let Some((policy, origin)) = policy {
+            if !policy.wildcard() {
+                response.vary("Origin");
+            }
+            if let Some(origin) = origin {
+                if response.header("access-control-allow-origin").is_none() {
+                    policy.apply(&origin, response);
+                }
+            }
+        }
+    }
+}
+
+/// Registers a CORS policy on the global framework instance
+#[allow(non_snake_case)]
+pub fn CORS(path: &str, origins: &[&str], credentials: bool) {
+    F.cors(path, origins, credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_origins() {
+        let allowed = origins(&["https://totaljs.com", "*.example.com", "http://localhost:8000"]);
+
+        assert!(allowed_origin(&allowed, "https://totaljs.com"));
+        assert!(!allowed_origin(&allowed, "http://totaljs.com"));
+        assert!(!allowed_origin(&allowed, "https://eviltotaljs.com"));
+
+        assert!(allowed_origin(&allowed, "https://app.example.com"));
+        assert!(allowed_origin(&allowed, "http://a.b.example.com:3000"));
+        assert!(!allowed_origin(&allowed, "https://example.com"));
+        assert!(!allowed_origin(&allowed, "https://example.com.evil.io"));
+
+        assert!(allowed_origin(&allowed, "http://localhost:8000"));
+        assert!(!allowed_origin(&allowed, "http://localhost:9000"));
+
+        assert!(allowed_origin(&origins(&["*"]), "https://anything.io"));
+    }
+
+    #[test]
+    #[should_panic(expected = "credentials can't be allowed")]
+    fn rejects_credentials_for_all_origins() {
+        Framework::default().cors("/api/*", &["https://totaljs.com", "*"], true);
+    }
+}
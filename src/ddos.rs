@@ -0,0 +1,97 @@
+// Total-rs request limiter
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::time::Duration;
+use chrono::Utc;
+use tokio::sync::watch;
+
+use crate::http::Response;
+use crate::types::DDOSEntry;
+use crate::{Framework, CONF};
+
+/// Window of the per-IP request counter
+const DDOS_WINDOW: Duration = Duration::from_secs(1);
+
+/// Interval of removing expired idle entries
+const DDOS_CLEANUP: Duration = Duration::from_secs(10);
+
+/// Releases a request slot of the IP when the request ends
+pub(crate) struct DdosGuard {
+    framework: &'static Framework,
+    ip: String,
+}
+
+impl Drop for DdosGuard {
+    fn drop(&mut self) {
+        if let Some(entry) = self.framework.temporary.write().unwrap().ddos.get_mut(&self.ip) {
+            entry.pending = entry.pending.saturating_sub(1);
+        }
+    }
+}
+
+impl Framework {
+    /// Limits requests per IP to `CONF._httpreqlimit` in progress and per second (`0` disables the limiter).
+    /// Returns `503 Service Unavailable` with `Retry-After` when the limit is exceeded.
+    pub(crate) fn ddos(&'static self, ip: &str) -> Result<Option<DdosGuard>, Response> {
+        let limit = CONF.read().unwrap()._httpreqlimit as u32;
+        if limit == 0 {
+            return Ok(None);
+        }
+
+        let now = Utc::now();
+        let window = chrono::Duration::from_std(DDOS_WINDOW).unwrap_or_default();
+
+        let retry = {
+            let mut temporary = self.temporary.write().unwrap();
+            let entry = temporary.ddos.entry(ip.to_string()).or_insert_with(|| DDOSEntry {
+                count: 0,
+                expires: now + window,
+                pending: 0,
+            });
+
+            if entry.expires <= now {
+                entry.count = 0;
+                entry.expires = now + window;
+            }
+
+            if entry.count >= limit || entry.pending >= limit {
+                Some((entry.expires - now).num_milliseconds().max(0) as u64)
+            } else {
+                entry.count += 1;
+                entry.pending += 1;
+                None
+            }
+        };
+
+        match retry {
+            Some(milliseconds) => {
+                self.stats.write().unwrap().response.ddos += 1;
+                let mut response = Response::throw(503);
+                response.set_header("Retry-After", &milliseconds.div_ceil(1000).max(1).to_string());
+                Err(response)
+            }
+            None => Ok(Some(DdosGuard { framework: self, ip: ip.to_string() })),
+        }
+    }
+
+    /// Removes expired entries without requests in progress from `Temporary.ddos`
+    pub fn ddos_cleanup(&self) {
+        let now = Utc::now();
+        self.temporary.write().unwrap().ddos.retain(|_, entry| entry.pending > 0 || entry.expires > now);
+    }
+
+    /// Runs `ddos_cleanup()` and `bans_cleanup()` periodically until the server is closed
+    pub(crate) async fn ddos_cleaner(&'static self, mut signal: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(DDOS_CLEANUP);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.ddos_cleanup();
+                    self.bans_cleanup().await;
+                }
+                _ = signal.changed() => break,
+            }
+        }
+    }
+}
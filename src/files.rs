@@ -0,0 +1,275 @@
+// Total-rs static files
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::compress::Encoding;
+use crate::controller::with_charset;
+use crate::http::{Request, Response, ResponseBody, HTTP_DATE};
+use crate::utils::{content_type, decode_uri};
+use crate::{Framework, CONF};
+
+/// Returns the lowercase extension of the last segment of the path
+pub(crate) fn extension(path: &str) -> Option<String> {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    match name.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() => Some(ext.to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Maximum count of ranges of one request after overlapping ranges are merged
+const MAX_RANGES: usize = 16;
+
+/// Size of the pieces of `multipart/byteranges` parts read from the disk
+const RANGE_CHUNK: usize = 16 * 1024;
+
+/// Parses `Range: bytes=...` into sorted inclusive ranges, overlapping and adjacent ranges are merged
+/// and open-ended ranges are limited by `buffer`. Returns `None` when the header should be ignored
+/// (also for more than `MAX_RANGES` ranges) and `Some(empty)` when nothing is satisfiable.
+pub(crate) fn parse_range(header: &str, size: u64, buffer: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',') {
+        let (start, end) = spec.trim().split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+
+        let range = if start.is_empty() {
+            // The last N bytes
+            let length: u64 = end.parse().ok()?;
+            if length == 0 || size == 0 {
+                continue;
+            }
+            (size.saturating_sub(length), size - 1)
+        } else {
+            let start: u64 = start.parse().ok()?;
+            let end = if end.is_empty() {
+                start.saturating_add(buffer.max(1) - 1)
+            } else {
+                end.parse().ok()?
+            };
+
+            if end < start {
+                return None;
+            }
+
+            if start >= size {
+                continue;
+            }
+
+            (start, end.min(size - 1))
+        };
+
+        ranges.push(range);
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    (merged.len() <= MAX_RANGES).then_some(merged)
+}
+
+impl Framework {
+    /// Maps the URL path to a file in `TPath::public`, refuses traversal out of the directory
+    fn public_file(&self, path: &str) -> Option<PathBuf> {
+        let path = decode_uri(path);
+        let mut file = self.path.public(None);
+
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                segment if segment.contains('\\') || segment.contains('\0') => return None,
+                segment => file.push(segment),
+            }
+        }
+
+        Some(file)
+    }
+
+    /// Serves a static file from `TPath::public`, allowed extensions are configured in `CONF._httpfiles`
+    /// and `CONF._httpchecktypes` limits them to known MIME types
+    pub(crate) async fn static_file(&self, request: &Request) -> Response {
+        let ext = match extension(&request.path) {
+            Some(ext) => ext,
+            None => return Response::throw(404),
+        };
+
+        let (allowed, checktypes, maxage, etag) = {
+            let conf = CONF.read().unwrap();
+            (conf._httpfiles.get(&ext).copied().unwrap_or(false), conf._httpchecktypes, conf._httpmaxage, conf._httpetag.clone())
+        };
+
+        if !allowed {
+            return Response::throw(404);
+        }
+
+        let mimetype = content_type(&ext);
+
+        // `CONF._httpchecktypes` refuses allowed extensions without a known MIME type
+        if checktypes && mimetype == "application/octet-stream" {
+            return Response::throw(404);
+        }
+
+        let path = match self.public_file(&request.path) {
+            Some(path) => path,
+            None => return Response::throw(404),
+        };
+
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Response::throw(404),
+        };
+
+        let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        let etag = format!("\"{:x}-{:x}{}\"", modified.as_millis(), metadata.len(), etag);
+        let lastmodified = DateTime::<Utc>::from_timestamp(modified.as_secs() as i64, 0).unwrap_or_default();
+
+        // Compressed variants have their own ETags, a matching one is sent back with `304`
+        let mut matched = None;
+        let notmodified = match request.header("if-none-match") {
+            Some(value) => value.split(',').any(|tag| {
+                let tag = tag.trim().trim_start_matches("W/");
+                if tag == "*" || tag == etag {
+                    return true;
+                }
+                if Encoding::ALL.iter().any(|encoding| encoding.etag(&etag) == tag) {
+                    matched = Some(tag.to_string());
+                    return true;
+                }
+                false
+            }),
+            None => request
+                .header("if-modified-since")
+                .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+                .map(|since| lastmodified.timestamp() <= since.timestamp())
+                .unwrap_or(false),
+        };
+
+        let mut response = Response::new(if notmodified { 304 } else { 200 });
+        response.set_header("ETag", matched.as_deref().unwrap_or(&etag));
+        response.set_header("Last-Modified", &lastmodified.format(HTTP_DATE).to_string());
+        response.set_header("Cache-Control", &format!("public, max-age={}", maxage));
+
+        if notmodified {
+            self.stats.write().unwrap().response.notmodified += 1;
+            return response;
+        }
+
+        self.stats.write().unwrap().response.file += 1;
+        response.set_header("Content-Type", &with_charset(mimetype));
+        response.set_header("Accept-Ranges", "bytes");
+        response.body = ResponseBody::File {
+            path,
+            offset: 0,
+            length: metadata.len(),
+        };
+
+        // `If-Range` serves the whole file when it has been changed
+        let unchanged = request.header("if-range").map(|value| value == etag).unwrap_or(true);
+
+        match request.header("range") {
+            Some(range) if unchanged => self.partial(range, response),
+            _ => response,
+        }
+    }
+
+    /// Turns a file response into `206 Partial Content` (or `416`) according to the `Range` header,
+    /// multiple ranges are streamed as `multipart/byteranges`
+    pub(crate) fn partial(&self, range: &str, mut response: Response) -> Response {
+        let (path, size) = match &response.body {
+            ResponseBody::File { path, length, .. } => (path.clone(), *length),
+            _ => return response,
+        };
+
+        let buffer = CONF.read().unwrap()._httprangebuffer as u64 * 1024;
+
+        let ranges = match parse_range(range, size, buffer) {
+            Some(ranges) => ranges,
+            None => return response,
+        };
+
+        if ranges.is_empty() {
+            let mut response = Response::new(416);
+            response.set_header("Content-Range", &format!("bytes */{}", size));
+            return response;
+        }
+
+        self.stats.write().unwrap().response.streaming += 1;
+        response.status = 206;
+
+        if let [(start, end)] = ranges[..] {
+            response.set_header("Content-Range", &format!("bytes {}-{}/{}", start, end, size));
+            response.body = ResponseBody::File {
+                path,
+                offset: start,
+                length: end - start + 1,
+            };
+            return response;
+        }
+
+        let content_type = response.header("content-type").unwrap_or("application/octet-stream").to_string();
+        let boundary = format!("{:x}", Utc::now().timestamp_nanos_opt().unwrap_or_default());
+        response.set_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary));
+
+        let (sender, receiver) = mpsc::channel(4);
+        let (failed, failure) = oneshot::channel();
+        response.body = ResponseBody::Stream(receiver, Some(failure));
+
+        tokio::spawn(async move {
+            let parts = async {
+                let mut file = tokio::fs::File::open(&path).await?;
+
+                for (start, end) in ranges {
+                    let head = format!("--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", boundary, content_type, start, end, size);
+                    if sender.send(head.into_bytes()).await.is_err() {
+                        return Ok(());
+                    }
+
+                    file.seek(SeekFrom::Start(start)).await?;
+                    let mut data = (&mut file).take(end - start + 1);
+                    while data.limit() > 0 {
+                        let mut buffer = vec![0; RANGE_CHUNK];
+                        let read = data.read(&mut buffer).await?;
+                        if read == 0 {
+                            // The file has been changed in the meantime
+                            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                        }
+
+                        buffer.truncate(read);
+                        if sender.send(buffer).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+
+                    if sender.send(b"\r\n".to_vec()).await.is_err() {
+                        return Ok(());
+                    }
+                }
+
+                let _ = sender.send(format!("--{}--\r\n", boundary).into_bytes()).await;
+                Ok(())
+            };
+
+            // The error is sent before the sender is dropped
+            if let Err(err) = parts.await {
+                let _ = failed.send(err);
+            }
+        });
+
+        response
+    }
+}
@@ -34,4 +34,8 @@ pub static IGNORE_AUDIT: Lazy<HashMap<&'static str, i32>> = Lazy::new(|| {
 });
 
 // Static mutable variable for CONCAT - this would better be handled through a mutex or other thread-safe approach
-pub static mut CONCAT: [Option<String>; 2] = [None, None];
\ No newline at end of file
+#[allow(dead_code)]
+pub static mut CONCAT: [Option<String>; 2] = [None, None];
+pub static REG_MOBILE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)Mobile|iPhone|iPad|iPod|Android|BlackBerry|IEMobile|Kindle|Opera Mini").unwrap()
+});
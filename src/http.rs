@@ -0,0 +1,1093 @@
+// Total-rs HTTP/1.1 server
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use chrono::Utc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::controller::verify_keys;
+use crate::globals::REG_MOBILE;
+use crate::multipart::{boundary, read_multipart, Uploads};
+use crate::types::{Controller, HttpFile, RouteHandler};
+use crate::{Framework, CONF, VERSION};
+
+pub(crate) const MAX_HEADER_SIZE: usize = 1024 * 16;
+pub(crate) const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Parsed incoming HTTP request
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub path: String,
+    pub querystring: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    /// Fields of a `multipart/form-data` body
+    pub fields: HashMap<String, String>,
+    /// Files of a `multipart/form-data` body stored in `TPath::tmp`
+    pub files: Vec<HttpFile>,
+    pub ip: String,
+}
+
+impl Request {
+    /// Returns a header value, the name is case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|value| value.as_str())
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+
+    /// Checks the `Upgrade: websocket` header
+    pub fn websocket(&self) -> bool {
+        self.header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+
+    pub fn xhr(&self) -> bool {
+        self.header("x-requested-with").map(|value| value.eq_ignore_ascii_case("XMLHttpRequest")).unwrap_or(false)
+    }
+
+    pub fn mobile(&self) -> bool {
+        self.header("user-agent").map(|ua| REG_MOBILE.is_match(ua)).unwrap_or(false)
+    }
+
+    /// Prefers a JSON answer (XHR or `Accept: application/json` without HTML)
+    pub fn accepts_json(&self) -> bool {
+        let accept = self.header("accept").unwrap_or_default();
+        self.xhr() || (accept.contains("json") && !accept.contains("html"))
+    }
+
+    /// Copy of the request without the body
+    pub(crate) fn head(&self) -> Request {
+        Request {
+            method: self.method.clone(),
+            url: self.url.clone(),
+            path: self.path.clone(),
+            querystring: self.querystring.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            body: Vec::new(),
+            fields: HashMap::new(),
+            files: Vec::new(),
+            ip: self.ip.clone(),
+        }
+    }
+}
+
+/// Body of an outgoing response
+#[derive(Debug, Default)]
+pub enum ResponseBody {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    /// Part of a file read from the disk when the response is written
+    File { path: PathBuf, offset: u64, length: u64 },
+    /// Chunks sent with `Transfer-Encoding: chunked` until the sender is dropped,
+    /// an error received from the second channel aborts the connection instead of ending the body
+    Stream(mpsc::Receiver<Vec<u8>>, Option<oneshot::Receiver<io::Error>>),
+}
+
+impl ResponseBody {
+    /// Length of the body, streams are unknown (0)
+    pub fn len(&self) -> usize {
+        match self {
+            ResponseBody::Empty | ResponseBody::Stream(..) => 0,
+            ResponseBody::Bytes(bytes) => bytes.len(),
+            ResponseBody::File { length, .. } => *length as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Outgoing HTTP response
+#[derive(Debug, Default)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ResponseBody,
+    // The response is rendered by `Routes.fallback` or the default error page
+    pub(crate) throw: bool,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: ResponseBody::Empty,
+            throw: false,
+        }
+    }
+
+    /// Creates an error response rendered later by the fallback handlers
+    pub(crate) fn throw(status: u16) -> Self {
+        let mut response = Self::new(status);
+        response.throw = true;
+        response
+    }
+
+    /// Creates a response with a body and its content type
+    pub fn with_body(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        let mut response = Self::new(status);
+        response.set_header("Content-Type", content_type);
+        response.body = ResponseBody::Bytes(body.into());
+        response
+    }
+
+    /// Sets (or replaces) a header, the name is case-insensitive and CR/LF are removed
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let name = strip_newlines(name);
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(&name));
+        self.headers.push((name, strip_newlines(value)));
+    }
+
+    /// Adds a header without replacing others with the same name, e.g. `Set-Cookie`
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers.push((strip_newlines(name), strip_newlines(value)));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Adds a token to the `Vary` header unless it's already there
+    pub fn vary(&mut self, token: &str) {
+        let value = match self.header("vary") {
+            Some(value) if value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token) || item.trim() == "*") => return,
+            Some(value) if !value.trim().is_empty() => format!("{}, {}", value, token),
+            _ => token.to_string(),
+        };
+        self.set_header("Vary", &value);
+    }
+}
+
+/// Errors produced while reading a request from the socket
+#[derive(Debug)]
+pub(crate) enum HttpError {
+    Io,
+    BadRequest,
+    /// The body exceeds `CONF._httpmaxsize` or the route limit
+    PayloadTooLarge,
+    /// The request line and headers exceed `MAX_HEADER_SIZE`
+    HeadersTooLarge,
+}
+
+impl From<io::Error> for HttpError {
+    fn from(_: io::Error) -> Self {
+        HttpError::Io
+    }
+}
+
+impl HttpError {
+    fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::Io => None,
+            HttpError::BadRequest => Some(400),
+            HttpError::PayloadTooLarge => Some(413),
+            HttpError::HeadersTooLarge => Some(431),
+        }
+    }
+}
+
+/// Removes CR and LF so a header value can't inject other headers or a body
+pub(crate) fn strip_newlines(text: &str) -> String {
+    text.replace(['\r', '\n'], "")
+}
+
+pub fn status_text(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+/// Running HTTP server started by `Framework::http()`
+pub struct HttpServer {
+    pub mode: String,
+    address: Option<SocketAddr>,
+    unixsocket: Option<PathBuf>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl HttpServer {
+    /// Address of the TCP listener
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.address.map(|address| address.port()).unwrap_or(0)
+    }
+
+    /// Path of the Unix socket (when `CONF._unixsocket` is set)
+    pub fn unixsocket(&self) -> Option<&Path> {
+        self.unixsocket.as_deref()
+    }
+
+    /// Stops accepting new connections
+    pub fn close(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Waits until the server is closed
+    pub async fn wait(self) {
+        let _ = self.task.await;
+    }
+}
+
+impl Framework {
+    /// Starts the HTTP server according to `CONF._ip`, `CONF._port` and `CONF._httptimeout`,
+    /// a non-empty `CONF._unixsocket` replaces the TCP listener with a Unix socket
+    pub async fn http(&'static self, mode: &str) -> io::Result<HttpServer> {
+        let (ip, port, unixsocket) = {
+            let config = CONF.read().unwrap();
+            (config._ip.clone(), config._port.clone(), config._unixsocket.clone())
+        };
+
+        if !unixsocket.is_empty() {
+            return self.http_unixsocket(mode, PathBuf::from(unixsocket)).await;
+        }
+
+        let port = if port == "auto" {
+            std::env::var("PORT").unwrap_or_else(|_| "0".to_string())
+        } else {
+            port
+        };
+
+        let port: u16 = port.trim().parse().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port \"{}\"", port))
+        })?;
+
+        let listener = TcpListener::bind((ip.as_str(), port)).await?;
+        let address = listener.local_addr()?;
+        let (shutdown, mut signal) = watch::channel(false);
+
+        self.banner(mode, &format!("http://{}/", address));
+        self.load_bans();
+        tokio::spawn(self.ddos_cleaner(shutdown.subscribe()));
+        tokio::spawn(self.session_cleaner(shutdown.subscribe()));
+        tokio::spawn(self.websocket_pinger(shutdown.subscribe()));
+
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        if let Ok((stream, remote)) = accepted {
+                            let _ = stream.set_nodelay(true);
+                            tokio::spawn(self.serve(stream, remote.ip().to_string()));
+                        }
+                    }
+                    _ = signal.changed() => break,
+                }
+            }
+        });
+
+        Ok(HttpServer {
+            mode: mode.to_string(),
+            address: Some(address),
+            unixsocket: None,
+            shutdown,
+            task,
+        })
+    }
+
+    #[cfg(unix)]
+    async fn http_unixsocket(&'static self, mode: &str, path: PathBuf) -> io::Result<HttpServer> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        use tokio::net::UnixListener;
+
+        if let Some(parent) = path.parent() {
+            self.path.verify(parent);
+        }
+
+        // Removes a stale socket left by a previous process, other files are kept and `bind()` fails
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        let listener = UnixListener::bind(&path)?;
+
+        // Reverse proxies (nginx) usually run under a different user, e.g. in the same group
+        let permissions = CONF.read().unwrap()._unixsocketmode;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(permissions))?;
+
+        let (shutdown, mut signal) = watch::channel(false);
+
+        self.banner(mode, &format!("unix:{}", path.display()));
+        self.load_bans();
+        tokio::spawn(self.ddos_cleaner(shutdown.subscribe()));
+        tokio::spawn(self.session_cleaner(shutdown.subscribe()));
+        tokio::spawn(self.websocket_pinger(shutdown.subscribe()));
+
+        let socket = path.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        if let Ok((stream, _)) = accepted {
+                            tokio::spawn(self.serve(stream, String::new()));
+                        }
+                    }
+                    _ = signal.changed() => break,
+                }
+            }
+            let _ = std::fs::remove_file(&socket);
+        });
+
+        Ok(HttpServer {
+            mode: mode.to_string(),
+            address: None,
+            unixsocket: Some(path),
+            shutdown,
+            task,
+        })
+    }
+
+    #[cfg(not(unix))]
+    async fn http_unixsocket(&'static self, _mode: &str, _path: PathBuf) -> io::Result<HttpServer> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform"))
+    }
+
+    fn banner(&self, mode: &str, listen: &str) {
+        let config = CONF.read().unwrap();
+        println!("====================================================");
+        println!("PID          : {}", std::process::id());
+        println!("Total.js     : v{}", VERSION);
+        println!("OS           : {}", std::env::consts::OS);
+        println!("Mode         : {}", mode);
+        println!("Date (UTC)   : {}", Utc::now().format("%Y-%m-%d %H:%M:%S"));
+        println!("====================================================");
+        println!("Name         : {}", config.name);
+        println!("Version      : {}", config.version);
+        println!("Webserver    : {}", listen);
+        println!("====================================================");
+    }
+
+    /// Handles all requests of a single connection (keep-alive aware),
+    /// an empty `ip` means the client is behind a reverse proxy (Unix socket)
+    pub(crate) async fn serve<S>(&'static self, stream: S, ip: String)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let directory = self.path.tmp(None);
+
+        loop {
+            let timeout = Duration::from_secs(CONF.read().unwrap()._httptimeout.max(1));
+
+            // Removes uploaded files which haven't been moved when the request ends in any way
+            let mut uploads = Uploads::new(directory.clone());
+            let mut request = match tokio::time::timeout(timeout, read_request(&mut reader, |request| self.body_limit(request), &mut uploads)).await {
+                Ok(Ok(Some(request))) => request,
+                Ok(Err(err)) => {
+                    if let Some(status) = err.status() {
+                        let request = Request { ip: ip.clone(), ..Default::default() };
+                        let response = self.render_error(Response::throw(status), request).await;
+                        let _ = write_response(&mut writer, response, false, false).await;
+                        let _ = writer.shutdown().await;
+
+                        // Discards the unread body, closing with pending data resets the connection
+                        // before the client reads the response
+                        let mut unread = (&mut reader).take(1024 * 1024);
+                        let mut sink = tokio::io::sink();
+                        let _ = tokio::time::timeout(Duration::from_secs(1), tokio::io::copy(&mut unread, &mut sink)).await;
+                    }
+                    break;
+                }
+                // Connection closed or idle keep-alive connection timed out
+                Ok(Ok(None)) | Err(_) => break,
+            };
+
+            request.ip = if ip.is_empty() {
+                request.header("x-forwarded-for")
+                    .or_else(|| request.header("x-real-ip"))
+                    .and_then(|value| value.split(',').next())
+                    .map(|value| value.trim().to_string())
+                    .unwrap_or_else(|| "127.0.0.1".to_string())
+            } else {
+                ip.clone()
+            };
+
+            if request.websocket() {
+                let found = self.routes.read().unwrap().find_websocket(&request.path);
+                if let Some((route, params)) = found {
+                    return self.upgrade(reader, writer, request, route, params).await;
+                }
+            }
+
+            let proxy = self.routes.read().unwrap().find_proxy(&request.path);
+            if let Some(proxy) = proxy.clone().filter(|_| request.websocket()) {
+                return self.tunnel(reader, writer, request, proxy).await;
+            }
+
+            let mut keep_alive = request.keep_alive();
+            let head = request.method == "HEAD";
+
+            let response = match proxy {
+                Some(proxy) => {
+                    let (response, reusable) = self.forward(&mut reader, request, proxy).await;
+                    keep_alive &= reusable;
+                    response
+                }
+                None => self.handle(request).await,
+            };
+            let written = write_response(&mut writer, response, head, keep_alive).await;
+            drop(uploads);
+
+            if written.is_err() || !keep_alive {
+                break;
+            }
+        }
+
+        let _ = writer.shutdown().await;
+    }
+
+    /// Maximum body size of the request, the route limit (`<5MB`) overrides `CONF._httpmaxsize` (kB),
+    /// bodies of proxied requests are streamed to the upstream with the `CONF._httpmaxsize` limit
+    fn body_limit(&self, request: &Request) -> Option<usize> {
+        let routes = self.routes.read().unwrap();
+        if routes.find_proxy(&request.path).is_some() {
+            return None;
+        }
+
+        let route = routes.find(&request.method, &request.path);
+        match route.and_then(|(route, _)| route.size) {
+            Some(size) => Some(size),
+            None => Some(CONF.read().unwrap()._httpmaxsize * 1024),
+        }
+    }
+
+    /// Processes a parsed request and produces the response
+    pub(crate) async fn handle(&'static self, request: Request) -> Response {
+        {
+            let mut stats = self.stats.write().unwrap();
+            stats.request.request += 1;
+            stats.request.pending += 1;
+            stats.request.size += request.body.len() as i64;
+
+            if !request.files.is_empty() {
+                stats.request.upload += 1;
+                stats.performance.upload += request.files.len() as i64;
+                stats.request.size += request.files.iter().map(|file| file.size as i64).sum::<i64>();
+            }
+
+            match request.method.as_str() {
+                "GET" => stats.request.get += 1,
+                "POST" => stats.request.post += 1,
+                "PUT" => stats.request.put += 1,
+                "PATCH" => stats.request.patch += 1,
+                "DELETE" => stats.request.delete += 1,
+                "HEAD" => stats.request.head += 1,
+                "OPTIONS" => stats.request.options += 1,
+                _ => {}
+            }
+
+            if request.xhr() {
+                stats.request.xhr += 1;
+            } else {
+                stats.request.web += 1;
+            }
+
+            if request.mobile() {
+                stats.request.mobile += 1;
+            } else {
+                stats.request.desktop += 1;
+            }
+        }
+
+        let head = request.head();
+        let accept = request.header("accept-encoding").map(String::from);
+        let cors = self.cors_policy(&request);
+
+        // Holds the request slot of the IP until the response is ready
+        let mut guard = None;
+
+        let ip = request.ip.clone();
+        let blocked = self.is_blocked(&ip) || self.is_banned(&ip);
+
+        let mut response = if blocked {
+            self.stats.write().unwrap().request.blocked += 1;
+            Response::throw(403)
+        } else {
+            match self.ddos(&request.ip) {
+                Ok(slot) => {
+                    guard = slot;
+                    match self.preflight(&request) {
+                        Some(response) => response,
+                        None => self.execute(request).await,
+                    }
+                }
+                Err(response) => response,
+            }
+        };
+
+        if !blocked {
+            self.count_violation(&ip, response.status).await;
+        }
+
+        if response.throw {
+            response = self.render_error(response, head).await;
+        }
+
+        self.cors_headers(cors, &mut response);
+
+        let response = self.compress(accept.as_deref(), response).await;
+        drop(guard);
+
+        let mut stats = self.stats.write().unwrap();
+        stats.request.pending -= 1;
+        stats.response.size += response.body.len() as i64;
+        response
+    }
+
+    /// Finds the route and waits for the handler's response (bounded by the route timeout or `CONF._httptimeout`)
+    async fn execute(&'static self, request: Request) -> Response {
+        if let Err(status) = verify_keys(&request.querystring) {
+            return Response::throw(status);
+        }
+
+        // API endpoints (`CONF._api`) multiplex operations by the `schema` of the body
+        let api = request.method == "POST" && self.routes.read().unwrap().is_api(&request.path);
+
+        let (route, params) = if api {
+            (None, HashMap::new())
+        } else {
+            let found = self.routes.read().unwrap().find(&request.method, &request.path);
+            match found {
+                Some((route, params)) => (Some(route), params),
+                // Unmatched GET/HEAD requests fall back to static files
+                None if request.method == "GET" || request.method == "HEAD" => return self.static_file(&request).await,
+                None => return Response::throw(404),
+            }
+        };
+
+        let (sender, receiver) = oneshot::channel();
+
+        let mut controller = match Controller::new(self, request, route, params, sender) {
+            Ok(controller) => controller,
+            Err(status) => return Response::throw(status),
+        };
+        controller.load_session().await;
+
+        if api {
+            if let Err(status) = self.prepare_api(&mut controller) {
+                return Response::throw(status);
+            }
+        }
+
+        let route = match controller.route.clone() {
+            Some(route) => route,
+            None => return Response::throw(404),
+        };
+
+        if let Err(status) = self.authorize(&mut controller, &route.auth) {
+            return Response::throw(status);
+        }
+
+        let timeout = route.timeout.unwrap_or_else(|| Duration::from_secs(CONF.read().unwrap()._httptimeout.max(1)));
+
+        let work = async move {
+            match route.handler.clone() {
+                Some(handler) => {
+                    if std::panic::catch_unwind(AssertUnwindSafe(|| handler(controller))).is_err() {
+                        return Response::throw(500);
+                    }
+                }
+                None => self.run_actions(controller).await,
+            }
+
+            // The controller has been dropped without a response
+            receiver.await.unwrap_or_else(|_| Response::throw(500))
+        };
+
+        match tokio::time::timeout(timeout, work).await {
+            Ok(response) => response,
+            Err(_) => {
+                self.stats.write().unwrap().response.timeout += 1;
+                Response::throw(408)
+            }
+        }
+    }
+
+    /// Renders an error with its `Routes.fallback` handler, or with the default error page
+    pub(crate) async fn render_error(&'static self, thrown: Response, request: Request) -> Response {
+        let status = thrown.status;
+        self.count_error(status);
+
+        let json = request.accepts_json();
+        let handler = self.routes.read().unwrap().fallback.get(&status.to_string()).cloned();
+
+        let mut response = match handler {
+            Some(handler) => self.execute_fallback(status, handler, request).await.unwrap_or_else(|| error_page(status, json)),
+            None => error_page(status, json),
+        };
+
+        // Headers set by the handler before throwing (e.g. WWW-Authenticate)
+        for (name, value) in thrown.headers {
+            if response.header(&name).is_none() {
+                response.set_header(&name, &value);
+            }
+        }
+
+        response
+    }
+
+    async fn execute_fallback(&'static self, status: u16, handler: RouteHandler, request: Request) -> Option<Response> {
+        let timeout = Duration::from_secs(CONF.read().unwrap()._httptimeout.max(1));
+        let (sender, receiver) = oneshot::channel();
+
+        let mut controller = Controller::new(self, request, None, HashMap::new(), sender).ok()?;
+        controller.load_session().await;
+        controller.set_status(status);
+
+        std::panic::catch_unwind(AssertUnwindSafe(|| handler(controller))).ok()?;
+
+        match tokio::time::timeout(timeout, receiver).await {
+            // Throwing inside a fallback handler ends with the default error page
+            Ok(Ok(response)) if !response.throw => {
+                self.stats.write().unwrap().response.custom += 1;
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    /// Updates the matching `error*` counter
+    pub(crate) fn count_error(&self, status: u16) {
+        let mut stats = self.stats.write().unwrap();
+        match status {
+            400 => stats.response.error400 += 1,
+            401 => stats.response.error401 += 1,
+            403 => stats.response.error403 += 1,
+            404 => stats.response.error404 += 1,
+            409 => stats.response.error409 += 1,
+            431 => stats.response.error431 += 1,
+            500 => stats.response.error500 += 1,
+            501 => stats.response.error501 += 1,
+            503 => stats.response.error503 += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Built-in error page, JSON for XHR/API clients and HTML for browsers
+pub(crate) fn error_page(status: u16, json: bool) -> Response {
+    let text = status_text(status);
+
+    if json {
+        let body = serde_json::json!([{ "error": text, "status": status }]);
+        return Response::with_body(status, "application/json; charset=utf-8", body.to_string());
+    }
+
+    let html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\" /><title>{status}: {text}</title>\
+<style>body{{font-family:Arial,sans-serif;text-align:center;padding:80px 20px;color:#505050}}h1{{font-size:60px;margin:0}}</style></head>\
+<body><h1>{status}</h1><p>{text}</p></body></html>"
+    );
+    Response::with_body(status, "text/html; charset=utf-8", html)
+}
+
+/// Reads a single request, returns `None` when the connection has been closed.
+/// `limit` returns the maximum body size for the parsed head, `None` leaves the body in the reader
+/// (proxied requests). Uploaded files are stored by `uploads`.
+pub(crate) async fn read_request<R, L>(reader: &mut R, limit: L, uploads: &mut Uploads) -> Result<Option<Request>, HttpError>
+where
+    R: AsyncBufRead + Unpin,
+    L: Fn(&Request) -> Option<usize>,
+{
+    let mut head = Vec::new();
+
+    loop {
+        // A line without `\n` can't grow beyond the limit
+        let mut line = Vec::new();
+        let read = (&mut *reader).take((MAX_HEADER_SIZE - head.len() + 1) as u64).read_until(b'\n', &mut line).await?;
+
+        if read == 0 {
+            return if head.is_empty() { Ok(None) } else { Err(HttpError::BadRequest) };
+        }
+
+        // Skips empty lines between pipelined requests
+        if head.is_empty() && (line == b"\r\n" || line == b"\n") {
+            continue;
+        }
+
+        head.extend_from_slice(&line);
+
+        if head.len() > MAX_HEADER_SIZE {
+            return Err(HttpError::HeadersTooLarge);
+        }
+
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+    }
+
+    let head = String::from_utf8(head).map_err(|_| HttpError::BadRequest)?;
+    let mut lines = head.lines();
+
+    let mut first = lines.next().unwrap_or_default().split(' ');
+    let method = first.next().unwrap_or_default().to_uppercase();
+    let url = first.next().unwrap_or_default().to_string();
+    let version = first.next().unwrap_or_default().to_string();
+
+    if method.is_empty() || !url.starts_with('/') || !version.starts_with("HTTP/") {
+        return Err(HttpError::BadRequest);
+    }
+
+    let mut headers = HashMap::new();
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let (name, value) = line.split_once(':').ok_or(HttpError::BadRequest)?;
+        let (name, value) = (name.trim().to_lowercase(), value.trim().to_string());
+
+        // Disagreeing lengths would let the body be framed differently by another server
+        if name == "content-length" && headers.get(&name).is_some_and(|length| *length != value) {
+            return Err(HttpError::BadRequest);
+        }
+        headers.insert(name, value);
+    }
+
+    let (path, querystring) = match url.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (url.clone(), String::new()),
+    };
+
+    let mut request = Request {
+        method,
+        url,
+        path,
+        querystring,
+        version,
+        headers,
+        body: Vec::new(),
+        fields: HashMap::new(),
+        files: Vec::new(),
+        ip: String::new(),
+    };
+
+    let chunked = request.header("transfer-encoding").map(|value| value.to_lowercase().contains("chunked")).unwrap_or(false);
+
+    let limit = match limit(&request) {
+        Some(limit) => limit,
+        None => return Ok(Some(request)),
+    };
+    let boundary = request.header("content-type").and_then(boundary);
+
+    if let Some(boundary) = boundary {
+        let (fields, files) = if chunked {
+            let body = read_chunked(reader, limit).await?;
+            read_multipart(&body[..], &boundary, uploads).await?
+        } else if let Some(length) = request.header("content-length") {
+            let length: u64 = length.parse().map_err(|_| HttpError::BadRequest)?;
+            if length > limit as u64 {
+                return Err(HttpError::PayloadTooLarge);
+            }
+
+            let mut body = (&mut *reader).take(length);
+            let parsed = read_multipart(&mut body, &boundary, uploads).await?;
+
+            // The epilogue after the closing delimiter
+            tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+            parsed
+        } else {
+            (HashMap::new(), Vec::new())
+        };
+
+        request.fields = fields;
+        request.files = files;
+    } else if chunked {
+        request.body = read_chunked(reader, limit).await?;
+    } else if let Some(length) = request.header("content-length") {
+        let length: usize = length.parse().map_err(|_| HttpError::BadRequest)?;
+        if length > limit {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await?;
+        request.body = body;
+    }
+
+    Ok(Some(request))
+}
+
+/// Appends a line of at most `MAX_HEADER_SIZE` bytes, longer lines fail with `io::ErrorKind::InvalidData`
+pub(crate) async fn read_line<R>(reader: &mut R, line: &mut String) -> io::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let read = (&mut *reader).take(MAX_HEADER_SIZE as u64).read_line(line).await?;
+    if read == MAX_HEADER_SIZE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(read)
+}
+
+/// Maps a too long line to `400 Bad Request`, other errors close the connection
+fn line_error(err: io::Error) -> HttpError {
+    if err.kind() == io::ErrorKind::InvalidData {
+        HttpError::BadRequest
+    } else {
+        HttpError::Io
+    }
+}
+
+/// Reads a chunked body of at most `limit` bytes, trailers are limited by `MAX_HEADER_SIZE`
+async fn read_chunked<R>(reader: &mut R, limit: usize) -> Result<Vec<u8>, HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    loop {
+        let mut line = String::new();
+        read_line(reader, &mut line).await.map_err(line_error)?;
+        let size = line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16).map_err(|_| HttpError::BadRequest)?;
+
+        if size == 0 {
+            // Trailer headers
+            let mut trailers = 0;
+            loop {
+                line.clear();
+                let read = read_line(reader, &mut line).await.map_err(|err| match line_error(err) {
+                    HttpError::BadRequest => HttpError::HeadersTooLarge,
+                    err => err,
+                })?;
+                trailers += read;
+                if trailers > MAX_HEADER_SIZE {
+                    return Err(HttpError::HeadersTooLarge);
+                }
+                if read == 0 || line.trim().is_empty() {
+                    break;
+                }
+            }
+            return Ok(body);
+        }
+
+        let offset = body.len();
+        let end = offset.checked_add(size).filter(|end| *end <= limit).ok_or(HttpError::PayloadTooLarge)?;
+        body.resize(end, 0);
+        reader.read_exact(&mut body[offset..]).await?;
+
+        // The data must be followed by CRLF
+        let mut crlf = [0u8; 2];
+        reader.read_exact(&mut crlf).await?;
+        if &crlf != b"\r\n" {
+            return Err(HttpError::BadRequest);
+        }
+    }
+}
+
+/// Serializes and writes a response
+pub(crate) async fn write_response<W>(writer: &mut W, mut response: Response, head: bool, keep_alive: bool) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let poweredby = CONF.read().unwrap()._xpoweredby.clone();
+    if !poweredby.is_empty() && response.header("x-powered-by").is_none() {
+        response.set_header("X-Powered-By", &poweredby);
+    }
+
+    response.set_header("Date", &Utc::now().format(HTTP_DATE).to_string());
+    // `101 Switching Protocols` keeps `Connection: Upgrade` and has no body
+    if response.status != 101 {
+        response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+    }
+
+    if let ResponseBody::Stream(..) = response.body {
+        response.set_header("Transfer-Encoding", "chunked");
+    } else if response.status >= 200 && response.status != 304 && response.status != 204 {
+        response.set_header("Content-Length", &response.body.len().to_string());
+    }
+
+    let mut output = format!("HTTP/1.1 {} {}\r\n", response.status, status_text(response.status));
+    for (name, value) in response.headers.iter() {
+        output.push_str(name);
+        output.push_str(": ");
+        output.push_str(value);
+        output.push_str("\r\n");
+    }
+    output.push_str("\r\n");
+
+    writer.write_all(output.as_bytes()).await?;
+
+    if !head {
+        match &mut response.body {
+            ResponseBody::Empty => {}
+            ResponseBody::Bytes(bytes) => writer.write_all(bytes).await?,
+            ResponseBody::File { path, offset, length } => {
+                let mut file = tokio::fs::File::open(&path).await?;
+                file.seek(io::SeekFrom::Start(*offset)).await?;
+                let copied = tokio::io::copy(&mut file.take(*length), writer).await?;
+                if copied != *length {
+                    // The file has been changed in the meantime, the connection can't be reused
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated"));
+                }
+            }
+            ResponseBody::Stream(receiver, failure) => {
+                while let Some(chunk) = receiver.recv().await {
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+                    writer.write_all(&chunk).await?;
+                    writer.write_all(b"\r\n").await?;
+                    writer.flush().await?;
+                }
+                // Without the last chunk the client knows the body is incomplete
+                if let Some(Ok(err)) = failure.as_mut().map(|failure| failure.try_recv()) {
+                    return Err(err);
+                }
+                writer.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+    }
+
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(raw: &[u8], limit: usize) -> Result<Option<Request>, HttpError> {
+        let mut reader = BufReader::new(raw);
+        read_request(&mut reader, |_| Some(limit), &mut Uploads::new(std::env::temp_dir())).await
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_within_limit() {
+        let request = read(b"POST /upload/ HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", 5).await.unwrap().unwrap();
+        assert_eq!(request.body, b"hello");
+
+        let raw = b"POST /upload/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n";
+        let request = read(raw, 5).await.unwrap().unwrap();
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_bodies() {
+        // The declared length is refused before reading the body
+        let result = read(b"POST /upload/ HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n", 1024).await;
+        assert!(matches!(result, Err(HttpError::PayloadTooLarge)));
+
+        // Chunked bodies are aborted once the limit is exceeded
+        let raw = b"POST /upload/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n3\r\nlo!\r\n0\r\n\r\n";
+        let result = read(raw, 5).await;
+        assert!(matches!(result, Err(HttpError::PayloadTooLarge)));
+        assert_eq!(result.unwrap_err().status(), Some(413));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_headers() {
+        let raw = format!("GET / HTTP/1.1\r\nX-Large: {}\r\n\r\n", "a".repeat(MAX_HEADER_SIZE));
+        let result = read(raw.as_bytes(), 1024).await;
+        assert!(matches!(result, Err(HttpError::HeadersTooLarge)));
+        assert_eq!(result.unwrap_err().status(), Some(431));
+
+        // A header line without an end is refused once the limit is buffered
+        let mut reader = BufReader::new(tokio::io::repeat(b'a'));
+        let result = read_request(&mut reader, |_| Some(1024), &mut Uploads::new(std::env::temp_dir())).await;
+        assert!(matches!(result, Err(HttpError::HeadersTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_chunked_bodies() {
+        let chunked = |body: &[u8]| [&b"POST /upload/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"[..], body].concat();
+
+        // Endless size lines and trailers
+        let head = chunked(b"");
+        let mut reader = BufReader::new((&head[..]).chain(tokio::io::repeat(b'1')));
+        let result = read_request(&mut reader, |_| Some(1024), &mut Uploads::new(std::env::temp_dir())).await;
+        assert!(matches!(result, Err(HttpError::BadRequest)));
+
+        let trailers = chunked(&[&b"0\r\n"[..], &b"X-Trailer: 1\r\n".repeat(MAX_HEADER_SIZE / 10)].concat());
+        assert!(matches!(read(&trailers, 1024).await, Err(HttpError::HeadersTooLarge)));
+
+        // Data not followed by CRLF and sizes overflowing the limit
+        assert!(matches!(read(&chunked(b"3\r\nhello\r\n0\r\n\r\n"), 1024).await, Err(HttpError::BadRequest)));
+        assert!(matches!(read(&chunked(b"3\r\nhel\n0\r\n\r\n"), 1024).await, Err(HttpError::BadRequest)));
+        assert!(matches!(read(&chunked(b"1\r\na\r\nffffffffffffffff\r\n"), 1024).await, Err(HttpError::PayloadTooLarge)));
+    }
+
+    #[tokio::test]
+    async fn rejects_conflicting_lengths() {
+        let raw = b"POST /upload/ HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!";
+        assert!(matches!(read(raw, 1024).await, Err(HttpError::BadRequest)));
+
+        let raw = b"POST /upload/ HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\nhello";
+        assert_eq!(read(raw, 1024).await.unwrap().unwrap().body, b"hello");
+    }
+
+    #[test]
+    fn merges_vary_tokens() {
+        let mut response = Response::new(200);
+        response.vary("Origin");
+        response.vary("Accept-Encoding");
+        response.vary("origin");
+        assert_eq!(response.header("vary"), Some("Origin, Accept-Encoding"));
+    }
+
+    #[test]
+    fn strips_newlines_from_headers() {
+        let mut response = Response::new(200);
+        response.set_header("Location", "/home/\r\nSet-Cookie: admin=1");
+        response.append_header("X-Name\n", "a\rb");
+        assert_eq!(response.header("location"), Some("/home/Set-Cookie: admin=1"));
+        assert_eq!(response.header("x-name"), Some("ab"));
+    }
+}
@@ -4,9 +4,7 @@
 use once_cell::sync::Lazy;
 use std::path::PathBuf;
 use std::collections::HashMap;
-use lazy_static::lazy_static;
-use std::sync::RwLock;
-use serde_json::json;
+use std::sync::{Arc, RwLock};
 use chrono::Utc;
 use std::fs;
 use std::time::Instant;
@@ -15,16 +13,44 @@ use std::time::Instant;
 mod types;
 mod utils;
 mod globals;
+mod http;
+mod routing;
+mod controller;
+mod actions;
+mod files;
+mod compress;
+mod multipart;
+mod xml;
+mod cors;
+mod blacklist;
+mod ddos;
+mod bans;
+mod cookies;
+mod session;
+mod websocket;
+mod sse;
+mod proxy;
 
 // Re-export the main components for library users
-pub use types::{FrameworkValue, InternalStats, Routes, Temporary, Stats, Config, DEF, AuditData, Message, SuccessResult, ErrorInfo, Controller, Parsers, Validators};
-pub use utils::TPath;
-pub use globals::{EMPTY_ARRAY, EMPTY_OBJECT, REG_HTTPHTTPS, REG_SKIPERRORS, SOCKETWINDOWS, IGNORE_AUDIT};
+pub use types::{JsonParser, UrlencodedParser, XmlParser};
+pub use types::{FrameworkValue, InternalStats, Routes, Temporary, Blacklist, Stats, Config, DEF, AuditData, Message, SuccessResult, ErrorInfo, Controller, Parsers, Validators, SMTPConfig};
+pub use types::{ClusterStats, PerformanceStats, OtherStats, RequestStats, ResponseStats, ServiceStats, Currency, TMail, CronJob, Internal, Performance};
+pub use types::{RouteHandler, RouteSegment, RouteAuth, RouteParamType, AuthHandler, HttpFile, ActionOptions, ActionHandler, Cors, CookieOptions, SseEvent, ServerSentEvents};
+pub use types::{Route, WebSocketRoute, WebSocketConnection, WebSocketMessage, WebSocketHandler, WebSocketMessageHandler, WebSocketCloseHandler, FileRoute, MiddlewareHandler, ImageMiddlewareHandler, Proxy, ProxyBeforeHandler, ProxyAfterHandler, CryptoKey, DDOSEntry, Service, PendingItem, Ban, Session, DateTimeFormatter};
+pub use session::{SessionStorage, MemorySessions, FileSessions};
+pub use utils::{TPath, encrypt, decrypt, hash_user_agent, parse_expire, encode_uri, decode_uri, content_type, parse_urlencoded, parse_query};
+pub use routing::ROUTE;
+pub use controller::AUTH;
+pub use actions::NEWACTION;
+pub use cors::CORS;
+pub use proxy::PROXY;
+pub use xml::{parse_xml, to_xml, XML_TEXT};
+pub use http::{HttpServer, Request, Response, ResponseBody, status_text};
+pub use globals::{EMPTY_ARRAY, EMPTY_OBJECT, REG_HTTPHTTPS, REG_SKIPERRORS, REG_MOBILE, SOCKETWINDOWS, IGNORE_AUDIT};
 
 
 
 /// Main Framework structure
-
 pub struct Framework {
     pub id: String,
     pub clusterid: String,
@@ -41,7 +67,7 @@ pub struct Framework {
     pub schedules: HashMap<String, FrameworkValue>,
     pub modules: HashMap<String, FrameworkValue>,
     pub plugins: HashMap<String, FrameworkValue>,
-    pub actions: HashMap<String, FrameworkValue>,
+    pub actions: RwLock<HashMap<String, ActionHandler>>,
     pub apiservices: HashMap<String, FrameworkValue>,
     pub processing: HashMap<String, FrameworkValue>,
     pub transformations: HashMap<String, FrameworkValue>,
@@ -56,15 +82,18 @@ pub struct Framework {
     
     // Arrays
     pub timeouts: Vec<FrameworkValue>,
-    pub errors: Vec<FrameworkValue>,
     pub paused: Vec<FrameworkValue>,
     pub crons: Vec<FrameworkValue>,
+    pub errors: RwLock<Vec<ErrorInfo>>,
     
     // Complex objects
     pub internal: InternalStats,
-    pub routes: Routes,
-    pub temporary: Temporary,
-    pub stats: Stats,
+    pub routes: RwLock<Routes>,
+    pub temporary: RwLock<Temporary>,
+    pub blacklist: RwLock<Blacklist>,
+    pub stats: RwLock<Stats>,
+    pub auth: RwLock<Option<AuthHandler>>,
+    pub sessions: RwLock<Arc<dyn SessionStorage>>,
     pub path: Lazy<TPath>
 }
 
@@ -85,7 +114,7 @@ impl Default for Framework {
             schedules: HashMap::new(),
             modules: HashMap::new(),
             plugins: HashMap::new(),
-            actions: HashMap::new(),
+            actions: RwLock::new(HashMap::new()),
             apiservices: HashMap::new(),
             processing: HashMap::new(),
             transformations: HashMap::new(),
@@ -99,14 +128,17 @@ impl Default for Framework {
             workers: HashMap::new(),
             
             timeouts: Vec::new(),
-            errors: Vec::new(),
             paused: Vec::new(),
             crons: Vec::new(),
+            errors: RwLock::new(Vec::new()),
             
             internal: InternalStats::default(),
-            routes: Routes::default(),
-            temporary: Temporary::default(),
-            stats: Stats::default(),
+            routes: RwLock::new(Routes::default()),
+            temporary: RwLock::new(Temporary::default()),
+            blacklist: RwLock::new(Blacklist::default()),
+            stats: RwLock::new(Stats::default()),
+            auth: RwLock::new(None),
+            sessions: RwLock::new(Arc::new(MemorySessions::default())),
             path: Lazy::new(|| TPath::new(PathBuf::from("src")))
         }
     }
@@ -153,6 +185,8 @@ pub static CONF: Lazy<RwLock<Config>> = Lazy::new(|| {
         _httpmaxkeys: 33,
         _httpmaxkey: 25,
         _blacklist: String::new(),
+        _banlimit: 0,
+        _banexpire: String::from("15 minutes"),
         _xpoweredby: String::from("Total.js"),
         _maxopenfiles: 100,
         _minifyjs: true,
@@ -162,6 +196,7 @@ pub static CONF: Lazy<RwLock<Config>> = Lazy::new(|| {
         _port: String::from("auto"),
         _ip: String::from("0.0.0.0"),
         _unixsocket: String::new(),
+        _unixsocketmode: 0o660,
         _timezone: String::from("utc"),
         _insecure: false,
         _performance: false,
@@ -182,6 +217,8 @@ pub static CONF: Lazy<RwLock<Config>> = Lazy::new(|| {
         _proxytimeout: 5,
         _cookiesamesite: String::from("Lax"),
         _cookiesecure: false,
+        _sessioncookie: String::from("__session"),
+        _sessionexpire: String::from("30 minutes"),
         _csrfexpiration: String::from("30 minutes"),
         _tapi: true,
         _tapiurl: String::from("eu"),
@@ -192,10 +229,22 @@ pub static CONF: Lazy<RwLock<Config>> = Lazy::new(|| {
         _tmsmaxsize: 256,
         _tmsurl: String::from("/$tms/"),
         _tmsclearblocked: 60,
+        mail_from: None,
+        mail_from_name: None,
+        mail_reply: None,
+        mail_cc: None,
+        mail_bcc: None,
+        smtp: SMTPConfig::default(),
     })
 });
 
 
+impl Default for DEF {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DEF {
     pub fn new() -> Self {
         let email_regex = regex::Regex::new(r"^[a-zA-Z0-9-_.+]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}$").unwrap();
@@ -212,21 +261,10 @@ impl DEF {
             parsers: Parsers {
                 json: Box::new(|value| serde_json::from_str(value)),
                 urlencoded: Box::new(|value| {
-                    // Simple implementation for URL encoded parsing
-                    let mut map = HashMap::new();
-                    for pair in value.split('&') {
-                        if let Some(idx) = pair.find('=') {
-                            let key = &pair[0..idx];
-                            let val = &pair[(idx + 1)..];
-                            map.insert(key.to_string(), val.to_string());
-                        }
-                    }
-                    map
-                }),
-                xml: Box::new(|value| {
-                    // Simple placeholder for XML parsing
-                    Ok(value.to_string())
+                    let conf = CONF.read().unwrap();
+                    parse_urlencoded(value, conf._httpmaxkeys, conf._httpmaxkey)
                 }),
+                xml: Box::new(parse_xml),
             },
             validators: Validators {
                 email: email_regex,
@@ -247,21 +285,21 @@ impl DEF {
         }
     }
 
-    pub fn on_csrf_create(&self, ctrl: &Controller, f: &F) -> String {
-        let config = f.config.lock().unwrap();
+    pub fn on_csrf_create(&self, ctrl: &Controller) -> String {
+        let config = CONF.read().unwrap();
         
-        if let Some(secret) = &config._csrfexpiration {
+        if let Some(expire) = parse_expire(&config._csrfexpiration) {
             let now = Utc::now();
-            let expiration = now + chrono::Duration::milliseconds(secret.as_millis() as i64);
+            let expiration = now + chrono::Duration::milliseconds(expire.as_millis() as i64);
             
             let data = vec![
                 ctrl.ip.clone(),
-                hash_user_agent(&ctrl.headers.get("user-agent").unwrap_or(&String::new())),
-                expiration.timestamp_millis(),
+                hash_user_agent(ctrl.headers.get("user-agent").map(|ua| ua.as_str()).unwrap_or("")),
+                expiration.timestamp_millis().to_string(),
             ];
             
-            if let Some(secret_csrf) = &config.secret_csrf {
-                encrypt(&serde_json::to_string(&data).unwrap(), secret_csrf)
+            if !config.secret_csrf.is_empty() {
+                encrypt(&serde_json::to_string(&data).unwrap(), &config.secret_csrf)
             } else {
                 String::new()
             }
@@ -270,18 +308,18 @@ impl DEF {
         }
     }
 
-    pub fn on_csrf_check(&self, ctrl: &Controller, f: &F) -> bool {
-        let config = f.config.lock().unwrap();
+    pub fn on_csrf_check(&self, ctrl: &Controller) -> bool {
+        let config = CONF.read().unwrap();
         
-        if let Some(secret_csrf) = &config.secret_csrf {
+        if !config.secret_csrf.is_empty() {
             let token = ctrl.headers.get("x-csrf-token").or_else(|| ctrl.query.get("csrf"));
             
             if let Some(token) = token {
                 if token.len() > 10 {
-                    if let Some(decrypted) = decrypt(token, secret_csrf) {
+                    if let Some(decrypted) = decrypt(token, &config.secret_csrf) {
                         if let Ok(data) = serde_json::from_str::<Vec<String>>(&decrypted) {
                             let now = Utc::now().timestamp_millis();
-                            let user_agent_hash = hash_user_agent(&ctrl.headers.get("user-agent").unwrap_or(&String::new()));
+                            let user_agent_hash = hash_user_agent(ctrl.headers.get("user-agent").map(|ua| ua.as_str()).unwrap_or(""));
                             
                             return data.len() >= 3 && 
                                    data[0] == ctrl.ip && 
@@ -297,15 +335,15 @@ impl DEF {
         }
     }
 
-    pub fn on_audit(&self, name: Option<&str>, data: &mut AuditData, f: &F) {
-        f.stats.performance.open += 1;
+    pub fn on_audit(&self, name: Option<&str>, data: &mut AuditData, f: &Framework) {
+        f.stats.write().unwrap().performance.open += 1;
         
         data.dtcreated = Utc::now();
         
         let audit_name = name.unwrap_or("audit");
-        let log_path = f.path.logs(Some(&format!("{}.log", audit_name)), f);
+        let log_path = f.path.logs(Some(&format!("{}.log", audit_name)));
         
-        let serialized = serde_json::to_string(data).unwrap() + "\n";
+        let serialized = serde_json::to_string(data).unwrap_or_default() + "\n";
         let _ = fs::OpenOptions::new()
             .create(true)
             .append(true)
@@ -314,7 +352,7 @@ impl DEF {
     }
 
     pub fn on_mail(&self, email: &str, subject: &str, body: &str, 
-                   callback: Option<&str>, reply: Option<&str>, f: &F) -> Message {
+                   _callback: Option<&str>, reply: Option<&str>) -> Message {
         let mut msg = Message {
             subject: subject.to_string(),
             body: body.to_string(),
@@ -339,7 +377,7 @@ impl DEF {
         }
 
         // Set from address
-        let config = f.config.lock().unwrap();
+        let config = CONF.read().unwrap();
         let from_email = config.mail_from.clone()
             .or_else(|| config.smtp.from.clone())
             .or_else(|| config.smtp.user.clone())
@@ -381,11 +419,11 @@ impl DEF {
         msg
     }
 
-    pub fn on_view_compile(&self, name: &str, html: &str) -> String {
+    pub fn on_view_compile(&self, _name: &str, html: &str) -> String {
         html.to_string()
     }
 
-    pub fn on_error(&self, err: &dyn std::error::Error, name: Option<&str>, url: Option<&str>, f: &F) {
+    pub fn on_error(&self, err: &dyn std::error::Error, name: Option<&str>, url: Option<&str>, f: &Framework) {
         let now = Utc::now();
         
         let error_name = if let Some(n) = name {
@@ -410,11 +448,12 @@ impl DEF {
                 error_str,
                 url_info);
                 
-        if let Some(stack) = std::backtrace::Backtrace::capture().to_string() {
+        let stack = std::backtrace::Backtrace::capture();
+        if stack.status() == std::backtrace::BacktraceStatus::Captured {
             println!("{}", stack);
         }
         
-        let mut errors = &mut f.errors;
+        let mut errors = f.errors.write().unwrap();
         let error_info = ErrorInfo {
             error: error_str,
             name: if error_name.is_empty() { None } else { Some(error_name) },
@@ -431,7 +470,7 @@ impl DEF {
         // f.$events.error && f.emit('error', obj);
         
         // Update error stats
-        f.stats.error += 1;
+        f.stats.write().unwrap().error += 1;
     }
 }
 
@@ -443,5 +482,6 @@ pub fn initialize_framework() -> Framework {
 
 /// Create a new PathUtils instance with the given base directory
 pub static VERSION: &str = "5.0.0";
-pub static F: Lazy<Framework> = Lazy::new(|| Framework::default());
+pub static F: Lazy<Framework> = Lazy::new(Framework::default);
+pub static DEF: Lazy<DEF> = Lazy::new(DEF::new);
 pub static PATH: Lazy<TPath> = Lazy::new(|| TPath::new(PathBuf::from("src")));
\ No newline at end of file
@@ -0,0 +1,342 @@
+// Total-rs multipart/form-data parser
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
+
+use crate::http::HttpError;
+use crate::types::HttpFile;
+use crate::CONF;
+
+/// Maximum size of the headers of a single part
+const MAX_PART_HEADER_SIZE: usize = 1024 * 8;
+
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Temporary files created for the uploads of a request,
+/// the files which haven't been moved are removed when it's dropped
+pub(crate) struct Uploads {
+    directory: PathBuf,
+    created: Vec<PathBuf>,
+}
+
+impl Uploads {
+    pub(crate) fn new(directory: PathBuf) -> Self {
+        Self { directory, created: Vec::new() }
+    }
+}
+
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        for path in self.created.drain(..) {
+            // Moved files don't exist anymore
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Returns the boundary of a `multipart/form-data` content type
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|value| !value.is_empty() && value.len() <= 70)
+}
+
+/// Returns a parameter of the `Content-Disposition` header
+fn disposition(header: &str, name: &str) -> Option<String> {
+    header
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/// Destination of a part
+enum Sink {
+    Discard,
+    Field(Vec<u8>),
+    File(tokio::fs::File, usize),
+}
+
+impl Sink {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Discard => Ok(()),
+            Sink::Field(value) => {
+                value.extend_from_slice(data);
+                Ok(())
+            }
+            Sink::File(file, size) => {
+                *size += data.len();
+                file.write_all(data).await
+            }
+        }
+    }
+}
+
+/// Buffered reader searching for the boundary without loading whole parts into memory
+struct Scanner<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> Scanner<R> {
+    /// Reads the next chunk into the buffer, returns `false` at the end of the body
+    async fn fill(&mut self) -> Result<bool, HttpError> {
+        let chunk = self.reader.fill_buf().await?;
+        if chunk.is_empty() {
+            return Ok(false);
+        }
+
+        let length = chunk.len();
+        self.buffer.extend_from_slice(chunk);
+        self.reader.consume(length);
+        Ok(true)
+    }
+
+    /// Reads a line without its line break
+    async fn line(&mut self) -> Result<String, HttpError> {
+        loop {
+            if let Some(index) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=index).collect();
+                let line = String::from_utf8(line).map_err(|_| HttpError::BadRequest)?;
+                return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+            }
+
+            if self.buffer.len() > MAX_PART_HEADER_SIZE || !self.fill().await? {
+                return Err(HttpError::BadRequest);
+            }
+        }
+    }
+
+    /// Writes the data before the delimiter into the sink and skips the delimiter
+    async fn part(&mut self, delimiter: &[u8], sink: &mut Sink) -> Result<(), HttpError> {
+        loop {
+            if let Some(index) = self.buffer.windows(delimiter.len()).position(|window| window == delimiter) {
+                sink.write(&self.buffer[..index]).await?;
+                self.buffer.drain(..index + delimiter.len());
+                return Ok(());
+            }
+
+            // The tail can contain the beginning of the delimiter
+            let keep = delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let length = self.buffer.len() - keep;
+                sink.write(&self.buffer[..length]).await?;
+                self.buffer.drain(..length);
+            }
+
+            if !self.fill().await? {
+                return Err(HttpError::BadRequest);
+            }
+        }
+    }
+
+    /// Returns `true` after the closing delimiter `--boundary--`
+    async fn closing(&mut self) -> Result<bool, HttpError> {
+        while self.buffer.len() < 2 {
+            if !self.fill().await? {
+                return Err(HttpError::BadRequest);
+            }
+        }
+
+        if self.buffer.starts_with(b"--") {
+            return Ok(true);
+        }
+
+        // Transport padding after the delimiter
+        if !self.line().await?.trim().is_empty() {
+            return Err(HttpError::BadRequest);
+        }
+
+        Ok(false)
+    }
+}
+
+/// Parses a `multipart/form-data` body, file parts are streamed into the directory of `uploads`
+/// which removes them when it's dropped
+pub(crate) async fn read_multipart<R>(reader: R, boundary: &str, uploads: &mut Uploads) -> Result<(HashMap<String, String>, Vec<HttpFile>), HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut fields = HashMap::new();
+    let mut files = Vec::new();
+    parse(reader, boundary, uploads, &mut fields, &mut files).await?;
+    Ok((fields, files))
+}
+
+async fn parse<R>(reader: R, boundary: &str, uploads: &mut Uploads, fields: &mut HashMap<String, String>, files: &mut Vec<HttpFile>) -> Result<(), HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+    // Text fields are limited like the keys of an urlencoded body
+    let (maxkeys, maxkey) = {
+        let conf = CONF.read().unwrap();
+        (conf._httpmaxkeys, conf._httpmaxkey)
+    };
+    let mut count = 0;
+
+    // The first delimiter isn't preceded by a line break
+    let mut scanner = Scanner { reader, buffer: b"\r\n".to_vec() };
+    scanner.part(&delimiter, &mut Sink::Discard).await?;
+
+    while !scanner.closing().await? {
+        let mut headers = HashMap::new();
+        loop {
+            let line = scanner.line().await?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+
+        let header = headers.get("content-disposition").ok_or(HttpError::BadRequest)?;
+        let name = disposition(header, "name").unwrap_or_default();
+
+        // Browsers send the whole path in some cases
+        let filename = disposition(header, "filename").map(|filename| filename.rsplit(['/', '\\']).next().unwrap_or_default().to_string());
+
+        match filename {
+            Some(filename) if !filename.is_empty() => {
+                let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+                let path = uploads.directory.join(format!("uploadedfile-{:x}{:x}.bin", nanos, UPLOADS.fetch_add(1, Ordering::Relaxed)));
+
+                tokio::fs::create_dir_all(&uploads.directory).await?;
+                let file = tokio::fs::File::create(&path).await?;
+                uploads.created.push(path.clone());
+
+                files.push(HttpFile {
+                    name,
+                    filename,
+                    content_type: headers.get("content-type").cloned().unwrap_or_else(|| "application/octet-stream".to_string()),
+                    size: 0,
+                    path,
+                });
+
+                let mut sink = Sink::File(file, 0);
+                scanner.part(&delimiter, &mut sink).await?;
+
+                if let Sink::File(mut file, size) = sink {
+                    file.flush().await?;
+                    if let Some(uploaded) = files.last_mut() {
+                        uploaded.size = size;
+                    }
+                }
+            }
+            // An empty file input
+            Some(_) => scanner.part(&delimiter, &mut Sink::Discard).await?,
+            None => {
+                count += 1;
+                if count > maxkeys || name.chars().count() > maxkey {
+                    return Err(HttpError::BadRequest);
+                }
+
+                let mut sink = Sink::Field(Vec::new());
+                scanner.part(&delimiter, &mut sink).await?;
+                if let Sink::Field(value) = sink {
+                    fields.insert(name, String::from_utf8_lossy(&value).to_string());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl HttpFile {
+    /// Reads the whole file
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        std::fs::read(&self.path)
+    }
+
+    /// Moves the file to `target`, moved files are kept after the request
+    pub fn move_to(&mut self, target: impl AsRef<Path>) -> io::Result<()> {
+        let target = target.as_ref();
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Renaming fails across file systems
+        if std::fs::rename(&self.path, target).is_err() {
+            std::fs::copy(&self.path, target)?;
+            std::fs::remove_file(&self.path)?;
+        }
+
+        self.path = target.to_path_buf();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn parses_parts_split_across_reads() {
+        let body = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"C:\\\\docs\\\\a.txt\"\r\nContent-Type: text/plain\r\n\r\n\
+line 1\r\n-XyZ is not a delimiter\r\n\r\n--XyZ--\r\nepilogue";
+
+        let directory = std::env::temp_dir().join(format!("total5-multipart-{}", std::process::id()));
+
+        // A tiny buffer splits the delimiters between reads
+        let reader = BufReader::with_capacity(3, &body[..]);
+        let mut uploads = Uploads::new(directory.clone());
+        let (fields, files) = read_multipart(reader, "XyZ", &mut uploads).await.unwrap();
+
+        assert_eq!(fields.get("title").map(String::as_str), Some("Hello"));
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "file");
+        assert_eq!(files[0].filename, "a.txt");
+        assert_eq!(files[0].content_type, "text/plain");
+        assert_eq!(files[0].read().unwrap(), b"line 1\r\n-XyZ is not a delimiter\r\n");
+        assert_eq!(files[0].size, 33);
+
+        drop(uploads);
+        assert!(!files[0].path.exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_files_of_invalid_bodies() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nunterminated";
+        let directory = std::env::temp_dir().join(format!("total5-multipart-invalid-{}", std::process::id()));
+
+        let mut uploads = Uploads::new(directory.clone());
+        let result = read_multipart(&body[..], "XyZ", &mut uploads).await;
+        assert!(matches!(result, Err(HttpError::BadRequest)));
+
+        drop(uploads);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn parses_boundaries() {
+        assert_eq!(boundary("multipart/form-data; boundary=----abc").as_deref(), Some("----abc"));
+        assert_eq!(boundary("multipart/form-data; charset=utf-8; boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(boundary("application/json; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+}
@@ -0,0 +1,617 @@
+// Total-rs reverse proxy
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::ddos::DdosGuard;
+use crate::http::{read_line, write_response, Request, Response, ResponseBody, MAX_HEADER_SIZE};
+use crate::routing::normalize;
+use crate::types::Proxy;
+use crate::utils::decode_uri;
+use crate::{Framework, CONF, F};
+
+/// Headers of a single connection, they aren't forwarded
+const HOP_HEADERS: [&str; 10] = ["connection", "keep-alive", "proxy-connection", "proxy-authenticate", "proxy-authorization", "te", "trailer", "transfer-encoding", "upgrade", "expect"];
+
+/// Size of the pieces of streamed response bodies
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// How the end of a body is recognized
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    Chunked,
+    Length(u64),
+    /// The body ends when the upstream closes the connection
+    Close,
+}
+
+fn framing(chunked: bool, length: Option<&str>) -> io::Result<Framing> {
+    if chunked {
+        return Ok(Framing::Chunked);
+    }
+
+    match length {
+        Some(length) => length.trim().parse().map(Framing::Length).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content-length")),
+        None => Ok(Framing::Close),
+    }
+}
+
+/// Reads the size of the next chunk
+async fn chunk_size<R>(reader: &mut R, line: &mut String) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    if read_line(reader, line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let size = line.trim().split(';').next().unwrap_or_default();
+    u64::from_str_radix(size, 16).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))
+}
+
+/// Copies exactly `length` bytes
+async fn copy_exact<R, W>(reader: &mut R, writer: &mut W, length: u64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if tokio::io::copy(&mut reader.take(length), writer).await? != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::FileTooLarge, "body too large")
+}
+
+/// Copies the request body as it's read, chunked bodies keep their encoding.
+/// Bodies over `limit` bytes fail with `io::ErrorKind::FileTooLarge`.
+async fn copy_body<R, W>(reader: &mut R, request: &Request, writer: &mut W, limit: u64) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match framing(chunked(request), request.header("content-length"))? {
+        Framing::Chunked => {
+            let mut line = String::new();
+            let mut total: u64 = 0;
+            loop {
+                let size = chunk_size(reader, &mut line).await?;
+                total = total.saturating_add(size);
+                if total > limit {
+                    return Err(too_large());
+                }
+
+                writer.write_all(line.as_bytes()).await?;
+
+                if size == 0 {
+                    // Trailer headers
+                    let mut trailers = 0;
+                    loop {
+                        line.clear();
+                        trailers += read_line(reader, &mut line).await?;
+                        if trailers > MAX_HEADER_SIZE {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "trailers too large"));
+                        }
+                        writer.write_all(line.as_bytes()).await?;
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+
+                // The data and its CRLF
+                copy_exact(reader, writer, size + 2).await?;
+            }
+        }
+        Framing::Length(length) if length > limit => return Err(too_large()),
+        Framing::Length(length) => copy_exact(reader, writer, length).await?,
+        Framing::Close => {}
+    }
+
+    writer.flush().await
+}
+
+/// Sends `length` bytes in pieces, returns `false` when the client has gone away
+async fn pipe<R>(reader: &mut R, sender: &mpsc::Sender<Vec<u8>>, length: u64) -> io::Result<bool>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = reader.take(length);
+    loop {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return if length == u64::MAX || reader.limit() == 0 { Ok(true) } else { Err(io::ErrorKind::UnexpectedEof.into()) };
+        }
+
+        buffer.truncate(read);
+        if sender.send(buffer).await.is_err() {
+            return Ok(false);
+        }
+    }
+}
+
+/// Streams the decoded response body of the upstream
+async fn stream_body<R>(mut reader: R, framing: Framing, sender: &mpsc::Sender<Vec<u8>>) -> io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    match framing {
+        Framing::Chunked => {
+            let mut line = String::new();
+            loop {
+                let size = chunk_size(&mut reader, &mut line).await?;
+                if size == 0 || !pipe(&mut reader, sender, size).await? {
+                    return Ok(());
+                }
+                line.clear();
+                read_line(&mut reader, &mut line).await?;
+            }
+        }
+        Framing::Length(length) => pipe(&mut reader, sender, length).await.map(|_| ()),
+        Framing::Close => pipe(&mut reader, sender, u64::MAX).await.map(|_| ()),
+    }
+}
+
+fn chunked(request: &Request) -> bool {
+    request.header("transfer-encoding").is_some_and(|value| value.to_lowercase().contains("chunked"))
+}
+
+/// Reads the status and the headers of the upstream response, `1xx` responses except `101` are skipped
+async fn read_head<R>(reader: &mut R) -> io::Result<(u16, Vec<(String, String)>)>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let mut head = String::new();
+        loop {
+            let read = read_line(reader, &mut head).await?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if head.len() > MAX_HEADER_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "headers too large"));
+            }
+            if head.ends_with("\n\n") || head.ends_with("\r\n\r\n") {
+                break;
+            }
+        }
+
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status line"))?;
+
+        if (100..200).contains(&status) && status != 101 {
+            continue;
+        }
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        return Ok((status, headers));
+    }
+}
+
+impl Proxy {
+    /// Replaces `source` in the path with the path of the target, returns `None` for dot segments
+    /// (also percent-encoded) which would leave the path of the target
+    fn rewrite(&self, path: &str, querystring: &str) -> Option<String> {
+        let skip = self.source.split('/').filter(|segment| !segment.is_empty()).count();
+        let rest: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).skip(skip).collect();
+
+        if rest.iter().any(|segment| matches!(decode_uri(segment).as_str(), "." | "..")) {
+            return None;
+        }
+
+        let mut url = if rest.is_empty() {
+            self.path.clone()
+        } else {
+            let mut url = format!("{}/{}", self.path.trim_end_matches('/'), rest.join("/"));
+            if path.ends_with('/') {
+                url.push('/');
+            }
+            url
+        };
+
+        if !querystring.is_empty() {
+            url.push('?');
+            url.push_str(querystring);
+        }
+        Some(url)
+    }
+
+    /// Creates the request head sent to the upstream with `X-Forwarded-*` headers
+    fn request_head(&self, request: &Request, upgrade: bool) -> String {
+        let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, request.url, self.host);
+
+        for (name, value) in request.headers.iter() {
+            if name == "host" || name.starts_with("x-forwarded-") || HOP_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        let forwarded = match request.header("x-forwarded-for") {
+            Some(value) => format!("{}, {}", value, request.ip),
+            None => request.ip.clone(),
+        };
+
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded));
+        head.push_str(&format!("X-Forwarded-Proto: {}\r\n", request.header("x-forwarded-proto").unwrap_or("http")));
+        if let Some(host) = request.header("x-forwarded-host").or_else(|| request.header("host")) {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+
+        if upgrade {
+            head.push_str(&format!("Connection: Upgrade\r\nUpgrade: {}\r\n", request.header("upgrade").unwrap_or("websocket")));
+        } else {
+            head.push_str("Connection: close\r\n");
+        }
+
+        if chunked(request) {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        }
+
+        head.push_str("\r\n");
+        head
+    }
+
+    pub fn before<H>(&self, handler: H)
+    where
+        H: Fn(&mut Request) -> Option<Response> + Send + Sync + 'static,
+    {
+        *self.before.write().unwrap() = Some(Arc::new(handler));
+    }
+
+    pub fn after<H>(&self, handler: H)
+    where
+        H: Fn(&Request, &mut Response) + Send + Sync + 'static,
+    {
+        *self.after.write().unwrap() = Some(Arc::new(handler));
+    }
+}
+
+impl Framework {
+    /// Registers a reverse proxy, e.g. `F.proxy("/old-api/", "http://127.0.0.1:9000/")`,
+    /// the rest of the path is appended to the path of the target
+    pub fn proxy(&self, source: &str, target: &str) -> Arc<Proxy> {
+        let rest = target
+            .strip_prefix("http://")
+            .unwrap_or_else(|| panic!("PROXY(\"{}\", \"{}\"): only http:// targets are supported", source, target));
+
+        let (host, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        if host.is_empty() {
+            panic!("PROXY(\"{}\", \"{}\"): the target doesn't contain a host", source, target);
+        }
+
+        let address = if host.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+
+        let proxy = Arc::new(Proxy {
+            source: normalize(source),
+            target: target.to_string(),
+            host: host.to_string(),
+            address,
+            path: path.to_string(),
+            before: RwLock::new(None),
+            after: RwLock::new(None),
+        });
+
+        let mut routes = self.routes.write().unwrap();
+        routes.proxies.retain(|other| other.source != proxy.source);
+        routes.proxies.push(proxy.clone());
+        routes.proxies.sort_by_key(|proxy| std::cmp::Reverse(proxy.source.len()));
+        proxy
+    }
+
+    fn proxy_timeout(&self) -> Duration {
+        Duration::from_secs(CONF.read().unwrap()._proxytimeout.max(1))
+    }
+
+    /// Rewrites the path, runs the `before` hook and sends the request head to the upstream
+    async fn connect_upstream(&self, request: &mut Request, proxy: &Proxy, upgrade: bool) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf), Response> {
+        request.url = proxy.rewrite(&request.path, &request.querystring).ok_or_else(|| Response::throw(400))?;
+
+        let handler = proxy.before.read().unwrap().clone();
+        if let Some(handler) = handler {
+            match std::panic::catch_unwind(AssertUnwindSafe(|| handler(request))) {
+                Ok(Some(response)) => return Err(response),
+                Ok(None) => {}
+                Err(_) => return Err(Response::throw(500)),
+            }
+        }
+
+        let stream = match tokio::time::timeout(self.proxy_timeout(), TcpStream::connect(&proxy.address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(_)) => return Err(Response::throw(502)),
+            Err(_) => return Err(Response::throw(504)),
+        };
+
+        let (reader, mut writer) = stream.into_split();
+        if writer.write_all(proxy.request_head(request, upgrade).as_bytes()).await.is_err() {
+            return Err(Response::throw(502));
+        }
+
+        Ok((BufReader::new(reader), writer))
+    }
+
+    /// Waits for the response head of the upstream, the body is attached by `stream()`
+    async fn upstream_head(&self, reader: &mut BufReader<OwnedReadHalf>, request: &Request) -> Result<(Response, Framing), Response> {
+        let (status, headers) = match tokio::time::timeout(self.proxy_timeout(), read_head(reader)).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(_)) => return Err(Response::throw(502)),
+            Err(_) => return Err(Response::throw(504)),
+        };
+
+        let header = |name: &str| headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+
+        let framing = if request.method == "HEAD" || status < 200 || status == 204 || status == 304 {
+            Framing::Length(0)
+        } else {
+            let chunked = header("transfer-encoding").is_some_and(|value| value.to_lowercase().contains("chunked"));
+            framing(chunked, header("content-length")).map_err(|_| Response::throw(502))?
+        };
+
+        let mut response = Response::new(status);
+        for (name, value) in headers.iter() {
+            let name = name.to_lowercase();
+            // `101 Switching Protocols` needs its `Connection` and `Upgrade`
+            let hop = HOP_HEADERS.contains(&name.as_str()) && !(status == 101 && (name == "connection" || name == "upgrade"));
+            if !hop && name != "content-length" {
+                response.append_header(&name, value);
+            }
+        }
+
+        self.stats.write().unwrap().response.proxy += 1;
+        Ok((response, framing))
+    }
+
+    /// Streams the body of the upstream response and runs the `after` hook,
+    /// a truncated body aborts the connection of the client.
+    /// The request slot of the client (`guard`) is released when the body has been read.
+    fn stream(&self, mut response: Response, reader: BufReader<OwnedReadHalf>, framing: Framing, request: &Request, proxy: &Proxy, guard: Option<DdosGuard>) -> Response {
+        if framing != Framing::Length(0) {
+            let (sender, receiver) = mpsc::channel(16);
+            let (failed, failure) = oneshot::channel();
+            response.body = ResponseBody::Stream(receiver, Some(failure));
+            tokio::spawn(async move {
+                // The error is sent before the sender is dropped, so the writer sees it after the last chunk
+                if let Err(err) = stream_body(reader, framing, &sender).await {
+                    let _ = failed.send(err);
+                }
+                drop(guard);
+            });
+        }
+        self.after(response, request, proxy)
+    }
+
+    fn after(&self, mut response: Response, request: &Request, proxy: &Proxy) -> Response {
+        let handler = proxy.after.read().unwrap().clone();
+        if let Some(handler) = handler {
+            if std::panic::catch_unwind(AssertUnwindSafe(|| handler(request, &mut response))).is_err() {
+                return Response::throw(500);
+            }
+        }
+        response
+    }
+
+    /// Checks the client like `handle()` and connects to the upstream,
+    /// the returned guard holds the request slot of the client until the upstream is done
+    async fn open_upstream(&'static self, request: &mut Request, proxy: &Proxy, upgrade: bool) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf, Option<DdosGuard>), Response> {
+        if self.is_blocked(&request.ip) || self.is_banned(&request.ip) {
+            self.stats.write().unwrap().request.blocked += 1;
+            return Err(Response::throw(403));
+        }
+
+        let guard = self.ddos(&request.ip)?;
+        let (reader, writer) = self.connect_upstream(request, proxy, upgrade).await?;
+        Ok((reader, writer, guard))
+    }
+
+    /// Forwards a request to the upstream and streams its response back,
+    /// returns `false` when the connection of the client can't be reused
+    pub(crate) async fn forward<R>(&'static self, reader: &mut R, mut request: Request, proxy: Arc<Proxy>) -> (Response, bool)
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        self.stats.write().unwrap().request.request += 1;
+
+        let head = request.head();
+        let limit = CONF.read().unwrap()._httpmaxsize as u64 * 1024;
+        let (response, reusable) = match self.open_upstream(&mut request, &proxy, false).await {
+            Ok((mut upstream, mut writer, guard)) => match copy_body(reader, &head, &mut writer, limit).await {
+                Ok(()) => match self.upstream_head(&mut upstream, &request).await {
+                    Ok((response, framing)) => (self.stream(response, upstream, framing, &request, &proxy, guard), true),
+                    Err(response) => (response, true),
+                },
+                Err(err) if err.kind() == io::ErrorKind::FileTooLarge => (Response::throw(413), false),
+                Err(_) => (Response::throw(502), false),
+            },
+            // The unread body of a rejected request
+            Err(response) => (response, copy_body(reader, &head, &mut tokio::io::sink(), limit).await.is_ok()),
+        };
+
+        let response = if response.throw { self.render_error(response, head).await } else { response };
+        (response, reusable)
+    }
+
+    /// Forwards a WebSocket upgrade and relays the frames in both directions until one side closes
+    pub(crate) async fn tunnel<R, W>(&'static self, reader: R, mut writer: W, mut request: Request, proxy: Arc<Proxy>)
+    where
+        R: AsyncBufRead + Unpin + Send,
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.stats.write().unwrap().request.websocket += 1;
+
+        let head = request.head();
+        let response = match self.open_upstream(&mut request, &proxy, true).await {
+            Ok((mut upstream, upstream_writer, guard)) => match self.upstream_head(&mut upstream, &request).await {
+                Ok((response, _)) if response.status == 101 => match self.after(response, &request, &proxy) {
+                    response if response.status == 101 => {
+                        relay(reader, writer, upstream, upstream_writer, response).await;
+                        drop(guard);
+                        return;
+                    }
+                    response => response,
+                },
+                Ok((response, framing)) => self.stream(response, upstream, framing, &request, &proxy, guard),
+                Err(response) => response,
+            },
+            Err(response) => response,
+        };
+
+        let response = if response.throw { self.render_error(response, head).await } else { response };
+        let _ = write_response(&mut writer, response, false, false).await;
+        let _ = writer.shutdown().await;
+    }
+}
+
+/// Writes the `101` response of the upstream and copies data in both directions until one side closes
+async fn relay<R, W>(mut reader: R, mut writer: W, mut upstream: BufReader<OwnedReadHalf>, mut upstream_writer: OwnedWriteHalf, response: Response)
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if write_response(&mut writer, response, false, true).await.is_ok() {
+        // Both readers may have buffered data already, `copy()` relays it first
+        tokio::select! {
+            _ = tokio::io::copy(&mut reader, &mut upstream_writer) => {}
+            _ = tokio::io::copy(&mut upstream, &mut writer) => {}
+        }
+    }
+
+    let _ = upstream_writer.shutdown().await;
+    let _ = writer.shutdown().await;
+}
+
+/// Registers a reverse proxy on the global framework instance
+#[allow(non_snake_case)]
+pub fn PROXY(source: &str, target: &str) -> Arc<Proxy> {
+    F.proxy(source, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(source: &str, path: &str) -> Proxy {
+        Proxy {
+            source: normalize(source),
+            target: format!("http://127.0.0.1:9000{}", path),
+            host: "127.0.0.1:9000".to_string(),
+            address: "127.0.0.1:9000".to_string(),
+            path: path.to_string(),
+            before: RwLock::new(None),
+            after: RwLock::new(None),
+        }
+    }
+
+    #[test]
+    fn rewrites_paths() {
+        let api = proxy("/old-api/", "/");
+        assert_eq!(api.rewrite("/old-api/users/5/", "page=2").as_deref(), Some("/users/5/?page=2"));
+        assert_eq!(api.rewrite("/Old-Api//users", "").as_deref(), Some("/users"));
+        assert_eq!(api.rewrite("/old-api", "").as_deref(), Some("/"));
+
+        let versioned = proxy("/old-api/", "/v2/");
+        assert_eq!(versioned.rewrite("/old-api/users/", "").as_deref(), Some("/v2/users/"));
+        assert_eq!(versioned.rewrite("/old-api/", "x=1").as_deref(), Some("/v2/?x=1"));
+
+        // Dot segments would leave the path of the target
+        for path in ["/old-api/../admin/", "/old-api/users/./", "/old-api/%2e%2E/admin/", "/old-api/.%2e/"] {
+            assert_eq!(versioned.rewrite(path, ""), None, "{}", path);
+        }
+        assert_eq!(versioned.rewrite("/old-api/..hidden/", "").as_deref(), Some("/v2/..hidden/"));
+    }
+
+    #[test]
+    fn adds_forwarded_headers() {
+        let mut request = Request { method: "POST".to_string(), url: "/users/".to_string(), ip: "10.0.0.2".to_string(), ..Default::default() };
+        for (name, value) in [("host", "www.totaljs.com"), ("x-forwarded-for", "10.0.0.1"), ("connection", "keep-alive"), ("transfer-encoding", "chunked"), ("x-token", "123")] {
+            request.headers.insert(name.to_string(), value.to_string());
+        }
+
+        let head = proxy("/old-api/", "/").request_head(&request, false);
+        assert!(head.starts_with("POST /users/ HTTP/1.1\r\nHost: 127.0.0.1:9000\r\n"));
+        assert!(head.contains("x-token: 123\r\n"));
+        assert!(head.contains("X-Forwarded-For: 10.0.0.1, 10.0.0.2\r\nX-Forwarded-Proto: http\r\nX-Forwarded-Host: www.totaljs.com\r\n"));
+        assert!(head.ends_with("Connection: close\r\nTransfer-Encoding: chunked\r\n\r\n"));
+        assert!(!head.contains("keep-alive"));
+    }
+
+    #[tokio::test]
+    async fn copies_chunked_bodies() {
+        let mut request = Request::default();
+        request.headers.insert("transfer-encoding".to_string(), "chunked".to_string());
+
+        let raw = b"3\r\nhel\r\n2;ext\r\nlo\r\n0\r\nX-Trailer: 1\r\n\r\nGET /next/ HTTP/1.1\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let mut output = Vec::new();
+        copy_body(&mut reader, &request, &mut output, 5).await.unwrap();
+
+        // The encoding is kept and the next request stays in the reader
+        assert_eq!(output, &raw[..raw.len() - 21]);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET /next/ HTTP/1.1\r\n");
+
+        // Bodies over the limit and endless lines fail
+        let error = copy_body(&mut BufReader::new(&raw[..]), &request, &mut tokio::io::sink(), 4).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+
+        let mut endless = BufReader::new(tokio::io::repeat(b'1'));
+        let error = copy_body(&mut endless, &request, &mut tokio::io::sink(), u64::MAX).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        request.headers.insert("content-length".to_string(), "6".to_string());
+        request.headers.remove("transfer-encoding");
+        let error = copy_body(&mut BufReader::new(&b"hello!"[..]), &request, &mut tokio::io::sink(), 5).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+    }
+
+    #[tokio::test]
+    async fn decodes_upstream_responses() {
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut reader = BufReader::new(&raw[..]);
+        let (status, headers) = read_head(&mut reader).await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(headers, [("Transfer-Encoding".to_string(), "chunked".to_string())]);
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        stream_body(reader, Framing::Chunked, &sender).await.unwrap();
+        drop(sender);
+        let mut body = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            body.extend(chunk);
+        }
+        assert_eq!(body, b"hello world");
+
+        // Truncated bodies fail
+        let (sender, _receiver) = mpsc::channel(16);
+        assert!(stream_body(&b"hel"[..], Framing::Length(5), &sender).await.is_err());
+    }
+}
@@ -0,0 +1,575 @@
+// Total-rs routing
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::types::{Controller, FrameworkValue, Proxy, Route, RouteAuth, RouteHandler, RouteParamType, RouteSegment, Routes, WebSocketRoute};
+use crate::utils::decode_uri;
+use crate::{Framework, CONF, DEF, F};
+
+const METHODS: [&str; 7] = ["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+
+impl Route {
+    /// Parses a Total.js route declaration, e.g. `+GET /api/users/{id}/ <10s <1MB @json #auth --> Users/read (response) Users/log`
+    pub fn parse(declaration: &str) -> Result<Route, String> {
+        let (definition, chain) = match declaration.split_once("-->") {
+            Some((definition, chain)) => (definition, Some(chain)),
+            None => (declaration, None),
+        };
+
+        let mut tokens = definition.split_whitespace();
+        let mut method = tokens.next().ok_or("missing method")?.to_uppercase();
+
+        let mut auth = if let Some(name) = method.strip_prefix('+') {
+            method = name.to_string();
+            RouteAuth::Authorized
+        } else if let Some(name) = method.strip_prefix('-') {
+            method = name.to_string();
+            RouteAuth::Unauthorized
+        } else {
+            RouteAuth::Any
+        };
+
+        if method != "API" && !METHODS.contains(&method.as_str()) {
+            return Err(format!("unsupported method \"{}\"", method));
+        }
+
+        let mut url = tokens.next().ok_or("missing URL")?.to_string();
+        let mut endpoint = None;
+
+        // API [endpoint] [+|-]operation/{param}, the endpoint defaults to `CONF._api`
+        if method == "API" {
+            let operation = if url.starts_with('/') {
+                endpoint = Some(url.clone());
+                tokens.next().ok_or("missing API operation")?.to_string()
+            } else {
+                endpoint = Some(CONF.read().unwrap()._api.clone());
+                url.clone()
+            };
+
+            url = if let Some(name) = operation.strip_prefix('+') {
+                auth = RouteAuth::Authorized;
+                format!("/{}", name)
+            } else if let Some(name) = operation.strip_prefix('-') {
+                auth = RouteAuth::Unauthorized;
+                format!("/{}", name)
+            } else {
+                format!("/{}", operation)
+            };
+        }
+
+        if !url.starts_with('/') {
+            return Err(format!("invalid URL \"{}\"", url));
+        }
+
+        let mut segments = Vec::new();
+        let mut params = Vec::new();
+
+        for (index, segment) in url.split('/').filter(|segment| !segment.is_empty()).enumerate() {
+            if segment == "*" {
+                if index + 1 != url.split('/').filter(|segment| !segment.is_empty()).count() {
+                    return Err("the wildcard must be the last segment".to_string());
+                }
+                segments.push(RouteSegment::Wildcard);
+            } else if segment.starts_with('{') && segment.ends_with('}') {
+                let (name, kind) = match segment[1..segment.len() - 1].split_once(':') {
+                    Some((name, kind)) => (name.trim().to_string(), RouteParamType::parse(kind.trim())?),
+                    None => (segment[1..segment.len() - 1].trim().to_string(), RouteParamType::String),
+                };
+                if name.is_empty() || params.contains(&name) {
+                    return Err(format!("invalid parameter \"{}\"", segment));
+                }
+                params.push(name.clone());
+                segments.push(RouteSegment::Param(name, kind));
+            } else {
+                segments.push(RouteSegment::Static(segment.to_lowercase()));
+            }
+        }
+
+        let mut timeout = None;
+        let mut size = None;
+        let mut flags = Vec::new();
+
+        for token in tokens {
+            if let Some(limit) = token.strip_prefix('<') {
+                match parse_limit(limit)? {
+                    Limit::Timeout(value) => timeout = Some(value),
+                    Limit::Size(value) => size = Some(value),
+                }
+            } else if token.starts_with('@') || token.starts_with('#') {
+                if token.len() < 2 {
+                    return Err(format!("invalid flag \"{}\"", token));
+                }
+                flags.push(token.to_string());
+            } else {
+                return Err(format!("unexpected \"{}\"", token));
+            }
+        }
+
+        let mut actions = Vec::new();
+        let mut response = None;
+
+        if let Some(chain) = chain {
+            for name in chain.split(|c: char| c.is_whitespace() || c == ',').filter(|name| !name.is_empty()) {
+                if name == "(response)" {
+                    if actions.is_empty() {
+                        return Err("\"(response)\" must follow an action".to_string());
+                    }
+                    response = Some(actions.len() - 1);
+                } else {
+                    actions.push(name.to_string());
+                }
+            }
+
+            if actions.is_empty() {
+                return Err("missing actions after \"-->\"".to_string());
+            }
+        }
+
+        let operation = match (&endpoint, segments.first()) {
+            (Some(_), Some(RouteSegment::Static(name))) => Some(name.clone()),
+            (Some(_), _) => return Err("the API operation must start with its name".to_string()),
+            _ => None,
+        };
+
+        let path = if let Some(endpoint) = endpoint {
+            normalize(&endpoint)
+        } else if segments.is_empty() {
+            "/".to_string()
+        } else {
+            let mut path = String::new();
+            for segment in segments.iter() {
+                path.push('/');
+                match segment {
+                    RouteSegment::Static(name) => path.push_str(name),
+                    RouteSegment::Param(name, RouteParamType::String) => path.push_str(&format!("{{{}}}", name)),
+                    RouteSegment::Param(name, kind) => path.push_str(&format!("{{{}:{}}}", name, kind.name())),
+                    RouteSegment::Wildcard => path.push('*'),
+                }
+            }
+            path.push('/');
+            path
+        };
+
+        // Routes with more segments and fewer untyped parameters first, wildcards last
+        let wildcard = segments.contains(&RouteSegment::Wildcard);
+        let untyped = segments.iter().filter(|segment| matches!(segment, RouteSegment::Param(_, RouteParamType::String))).count();
+        let priority = if wildcard { -1000 } else { 0 } + (segments.len() as i64) * 10 - untyped as i64;
+
+        Ok(Route {
+            declaration: declaration.trim().to_string(),
+            method,
+            path,
+            segments,
+            params,
+            auth,
+            timeout,
+            size,
+            flags,
+            actions,
+            response,
+            operation,
+            priority,
+            handler: None,
+        })
+    }
+
+    /// Static routes do not contain any parameters or wildcards
+    pub fn is_static(&self) -> bool {
+        self.segments.iter().all(|segment| matches!(segment, RouteSegment::Static(_)))
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|item| item == flag)
+    }
+
+    /// Names of middleware declared with `#name`
+    pub fn middleware(&self) -> impl Iterator<Item = &str> {
+        self.flags.iter().filter_map(|flag| flag.strip_prefix('#'))
+    }
+
+    /// Matches URL segments and returns the decoded and typed dynamic parameters,
+    /// a parameter failing its constraint means the route does not match
+    pub fn matches(&self, segments: &[&str]) -> Option<HashMap<String, FrameworkValue>> {
+        let wildcard = self.segments.last() == Some(&RouteSegment::Wildcard);
+
+        if wildcard {
+            if segments.len() < self.segments.len() - 1 {
+                return None;
+            }
+        } else if segments.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+
+        for (segment, value) in self.segments.iter().zip(segments.iter()) {
+            match segment {
+                RouteSegment::Static(name) => {
+                    if !name.eq_ignore_ascii_case(value) {
+                        return None;
+                    }
+                }
+                RouteSegment::Param(name, kind) => {
+                    params.insert(name.clone(), kind.convert(&decode_uri(value))?);
+                }
+                RouteSegment::Wildcard => break,
+            }
+        }
+
+        Some(params)
+    }
+}
+
+impl RouteParamType {
+    pub fn parse(name: &str) -> Result<RouteParamType, String> {
+        match name.to_lowercase().as_str() {
+            "" | "string" => Ok(RouteParamType::String),
+            "number" => Ok(RouteParamType::Number),
+            "boolean" => Ok(RouteParamType::Boolean),
+            "uid" => Ok(RouteParamType::Uid),
+            "email" => Ok(RouteParamType::Email),
+            "phone" => Ok(RouteParamType::Phone),
+            "zip" => Ok(RouteParamType::Zip),
+            "url" => Ok(RouteParamType::Url),
+            _ => Err(format!("unsupported parameter type \"{}\"", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RouteParamType::String => "string",
+            RouteParamType::Number => "number",
+            RouteParamType::Boolean => "boolean",
+            RouteParamType::Uid => "uid",
+            RouteParamType::Email => "email",
+            RouteParamType::Phone => "phone",
+            RouteParamType::Zip => "zip",
+            RouteParamType::Url => "url",
+        }
+    }
+
+    /// Validates a decoded value with `DEF.validators` and converts it to its typed form
+    pub fn convert(&self, value: &str) -> Option<FrameworkValue> {
+        let validators = &DEF.validators;
+        let valid = match self {
+            RouteParamType::String => !value.is_empty(),
+            RouteParamType::Number => {
+                return match value.parse::<i64>() {
+                    Ok(number) => Some(FrameworkValue::Number(number)),
+                    Err(_) => value.parse::<f64>().ok().filter(|number| number.is_finite()).map(FrameworkValue::Float),
+                };
+            }
+            RouteParamType::Boolean => {
+                return match value.to_lowercase().as_str() {
+                    "true" | "1" | "on" => Some(FrameworkValue::Boolean(true)),
+                    "false" | "0" | "off" => Some(FrameworkValue::Boolean(false)),
+                    _ => None,
+                };
+            }
+            RouteParamType::Uid => validators.uid.is_match(value),
+            RouteParamType::Email => validators.email.is_match(value),
+            RouteParamType::Phone => validators.phone.is_match(value),
+            RouteParamType::Zip => validators.zip.is_match(value),
+            RouteParamType::Url => validators.url.is_match(value),
+        };
+
+        if valid {
+            Some(FrameworkValue::String(value.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+enum Limit {
+    Timeout(Duration),
+    Size(usize),
+}
+
+fn parse_limit(value: &str) -> Result<Limit, String> {
+    let lower = value.to_lowercase();
+    let index = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let invalid = || format!("invalid limit \"<{}\"", value);
+    let number: u64 = lower[..index].parse().map_err(|_| invalid())?;
+
+    let (timeout, multiplier) = match &lower[index..] {
+        "" | "s" => (true, 1000),
+        "ms" => (true, 1),
+        "m" | "min" => (true, 60 * 1000),
+        "b" => (false, 1),
+        "kb" => (false, 1024),
+        "mb" => (false, 1024 * 1024),
+        "gb" => (false, 1024 * 1024 * 1024),
+        _ => return Err(invalid()),
+    };
+
+    let number = number.checked_mul(multiplier).ok_or_else(invalid)?;
+    if timeout {
+        Ok(Limit::Timeout(Duration::from_millis(number)))
+    } else {
+        usize::try_from(number).map(Limit::Size).map_err(|_| invalid())
+    }
+}
+
+/// Lowercases a path and wraps it with slashes (`/api/`)
+pub(crate) fn normalize(path: &str) -> String {
+    let mut output = String::from("/");
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        output.push_str(&segment.to_lowercase());
+        output.push('/');
+    }
+    output
+}
+
+/// Builds the `routescache` key
+fn cachekey(method: &str, segments: &[&str]) -> String {
+    let mut key = method.to_string();
+    key.push(' ');
+    key.push('/');
+    for segment in segments {
+        key.push_str(&segment.to_lowercase());
+        key.push('/');
+    }
+    key
+}
+
+impl Routes {
+    /// Registers a route, static routes are cached for O(1) lookups
+    pub fn add(&mut self, route: Route) -> Arc<Route> {
+        let route = Arc::new(route);
+
+        if route.operation.is_some() {
+            let operations = self.api.entry(route.path.clone()).or_default();
+            operations.push(route.clone());
+            operations.sort_by_key(|route| std::cmp::Reverse(route.priority));
+            return route;
+        }
+
+        if route.is_static() {
+            let segments: Vec<&str> = route.segments.iter().filter_map(|segment| match segment {
+                RouteSegment::Static(name) => Some(name.as_str()),
+                _ => None,
+            }).collect();
+            self.routescache.insert(cachekey(&route.method, &segments), route.clone());
+        }
+
+        self.routes.push(route.clone());
+        self.routes.sort_by_key(|route| std::cmp::Reverse(route.priority));
+        route
+    }
+
+    /// Finds a route for the method and path, `HEAD` requests are served by `GET` routes
+    pub fn find(&self, method: &str, path: &str) -> Option<(Arc<Route>, HashMap<String, FrameworkValue>)> {
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        let methods: &[&str] = if method == "HEAD" { &["HEAD", "GET"] } else { &[method] };
+
+        for method in methods {
+            if let Some(route) = self.routescache.get(&cachekey(method, &segments)) {
+                return Some((route.clone(), HashMap::new()));
+            }
+
+            let found = self.routes
+                .iter()
+                .filter(|route| route.method == *method && !route.is_static())
+                .find_map(|route| route.matches(&segments).map(|params| (route.clone(), params)));
+
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        None
+    }
+}
+
+impl Routes {
+    /// Checks if the path is an API endpoint
+    pub fn is_api(&self, path: &str) -> bool {
+        !self.api.is_empty() && self.api.contains_key(&normalize(path))
+    }
+
+    /// Finds an API operation by its schema, e.g. `users_read/123`
+    pub fn find_api(&self, path: &str, schema: &str) -> Option<(Arc<Route>, HashMap<String, FrameworkValue>)> {
+        let operations = self.api.get(&normalize(path))?;
+        let segments: Vec<&str> = schema.split('/').filter(|segment| !segment.is_empty()).collect();
+        operations.iter().find_map(|route| route.matches(&segments).map(|params| (route.clone(), params)))
+    }
+}
+
+impl Routes {
+    /// Finds a WebSocket route for the path
+    pub fn find_websocket(&self, path: &str) -> Option<(Arc<WebSocketRoute>, HashMap<String, FrameworkValue>)> {
+        if let Some(route) = self.websocketscache.get(&normalize(path)) {
+            return Some((route.clone(), HashMap::new()));
+        }
+
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        self.websockets
+            .iter()
+            .filter(|websocket| !websocket.route.is_static())
+            .find_map(|websocket| websocket.route.matches(&segments).map(|params| (websocket.clone(), params)))
+    }
+
+    /// Finds the most specific proxy whose source contains the path
+    pub fn find_proxy(&self, path: &str) -> Option<Arc<Proxy>> {
+        let path = normalize(path);
+        self.proxies.iter().find(|proxy| path.starts_with(&proxy.source)).cloned()
+    }
+}
+
+impl Framework {
+    /// Registers a route handled by a closure, e.g. `F.route("GET /api/users/{id}/ <10s", |$| ...)`
+    pub fn route<H>(&self, declaration: &str, handler: H) -> Arc<Route>
+    where
+        H: Fn(Controller) + Send + Sync + 'static,
+    {
+        let mut route = Route::parse(declaration).unwrap_or_else(|err| panic!("ROUTE(\"{}\"): {}", declaration, err));
+        route.handler = Some(Arc::new(handler) as RouteHandler);
+        self.routes.write().unwrap().add(route)
+    }
+
+    /// Registers a custom error handler (`Routes.fallback`) for a 4xx/5xx status code,
+    /// the controller responds with the status code by default
+    pub fn fallback<H>(&self, status: u16, handler: H)
+    where
+        H: Fn(Controller) + Send + Sync + 'static,
+    {
+        if !(400..600).contains(&status) {
+            panic!("FALLBACK({}): only 4xx and 5xx status codes are supported", status);
+        }
+        self.routes.write().unwrap().fallback.insert(status.to_string(), Arc::new(handler) as RouteHandler);
+    }
+
+    /// Registers a route handled only by its action chain, e.g. `F.route_actions("GET /users/ --> Users/list")`
+    /// or `F.route_actions("API /api/ +users_list --> Users/list")`
+    pub fn route_actions(&self, declaration: &str) -> Arc<Route> {
+        let route = Route::parse(declaration).unwrap_or_else(|err| panic!("ROUTE(\"{}\"): {}", declaration, err));
+        if route.actions.is_empty() {
+            panic!("ROUTE(\"{}\"): missing actions after \"-->\"", declaration);
+        }
+        self.routes.write().unwrap().add(route)
+    }
+}
+
+/// Registers a route on the global framework instance
+#[allow(non_snake_case)]
+pub fn ROUTE<H>(declaration: &str, handler: H) -> Arc<Route>
+where
+    H: Fn(Controller) + Send + Sync + 'static,
+{
+    F.route(declaration, handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_declarations() {
+        let route = Route::parse("+get /api/Users/{id}/ <10s <1MB @json #auth --> Users/read (response) Users/log").unwrap();
+        assert_eq!(route.method, "GET");
+        assert_eq!(route.auth, RouteAuth::Authorized);
+        assert_eq!(route.path, "/api/users/{id}/");
+        assert_eq!(route.params, ["id"]);
+        assert_eq!(route.timeout, Some(Duration::from_secs(10)));
+        assert_eq!(route.size, Some(1024 * 1024));
+        assert!(route.has_flag("@json"));
+        assert_eq!(route.middleware().collect::<Vec<_>>(), ["auth"]);
+        assert_eq!(route.actions, ["Users/read", "Users/log"]);
+        assert_eq!(route.response, Some(0));
+        assert!(!route.is_static());
+
+        let route = Route::parse("-POST /login/ <500ms <64kb").unwrap();
+        assert_eq!(route.auth, RouteAuth::Unauthorized);
+        assert_eq!(route.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(route.size, Some(64 * 1024));
+        assert!(route.is_static());
+
+        assert_eq!(Route::parse("GET /").unwrap().path, "/");
+        assert_eq!(Route::parse("DELETE /files/*").unwrap().segments.last(), Some(&RouteSegment::Wildcard));
+    }
+
+    #[test]
+    fn rejects_invalid_declarations() {
+        for declaration in [
+            "",
+            "FETCH /users/",
+            "GET",
+            "GET users/",
+            "GET /files/*/list/",
+            "GET /users/{}/",
+            "GET /users/{id}/{id}/",
+            "GET /users/ <10x",
+            "GET /users/ <18446744073709551615",
+            "GET /users/ <307445734561825861m",
+            "GET /users/ <17179869184gb",
+            "GET /users/ @",
+            "GET /users/ json",
+            "GET /users/ -->",
+            "GET /users/ --> (response)",
+        ] {
+            assert!(Route::parse(declaration).is_err(), "{}", declaration);
+        }
+    }
+
+    #[test]
+    fn orders_routes_by_priority() {
+        let priority = |declaration: &str| Route::parse(declaration).unwrap().priority;
+
+        assert!(priority("GET /users/new/") > priority("GET /users/{id}/"));
+        assert!(priority("GET /users/{id}/") > priority("GET /users/"));
+        assert!(priority("GET /users/") > priority("GET /users/*"));
+        assert!(priority("GET /*") < priority("GET /"));
+    }
+
+    #[test]
+    fn converts_typed_params() {
+        assert_eq!(RouteParamType::parse("Number"), Ok(RouteParamType::Number));
+        assert_eq!(RouteParamType::parse(""), Ok(RouteParamType::String));
+        assert!(RouteParamType::parse("date").is_err());
+
+        assert_eq!(RouteParamType::Number.convert("42"), Some(FrameworkValue::Number(42)));
+        assert_eq!(RouteParamType::Number.convert("-1.5"), Some(FrameworkValue::Float(-1.5)));
+        assert_eq!(RouteParamType::Boolean.convert("ON"), Some(FrameworkValue::Boolean(true)));
+        assert_eq!(RouteParamType::Boolean.convert("0"), Some(FrameworkValue::Boolean(false)));
+        assert_eq!(RouteParamType::Email.convert("info@totaljs.com"), Some(FrameworkValue::String("info@totaljs.com".to_string())));
+
+        for (kind, value) in [
+            (RouteParamType::Uid, "1234561f"),
+            (RouteParamType::Phone, "+421 123 456789"),
+            (RouteParamType::Zip, "811 01"),
+            (RouteParamType::Url, "https://www.totaljs.com/"),
+        ] {
+            assert!(kind.convert(value).is_some(), "{} {}", kind.name(), value);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_typed_params() {
+        for (kind, value) in [
+            (RouteParamType::String, ""),
+            (RouteParamType::Number, "abc"),
+            (RouteParamType::Number, "inf"),
+            (RouteParamType::Boolean, "yes"),
+            (RouteParamType::Uid, "123"),
+            (RouteParamType::Email, "info@totaljs"),
+            (RouteParamType::Phone, "12-34"),
+            (RouteParamType::Zip, "81"),
+            (RouteParamType::Url, "ftp://totaljs.com"),
+        ] {
+            assert_eq!(kind.convert(value), None, "{} {}", kind.name(), value);
+        }
+
+        // A parameter failing its constraint means the route does not match
+        let route = Route::parse("GET /users/{id:number}/{active:boolean}/").unwrap();
+        let params = route.matches(&["users", "5", "true"]).unwrap();
+        assert_eq!(params.get("id"), Some(&FrameworkValue::Number(5)));
+        assert_eq!(params.get("active"), Some(&FrameworkValue::Boolean(true)));
+        assert!(route.matches(&["users", "abc", "true"]).is_none());
+        assert!(route.matches(&["users", "5", "maybe"]).is_none());
+    }
+}
@@ -0,0 +1,232 @@
+// Total-rs sessions
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+
+use crate::types::{Controller, CookieOptions, FrameworkValue, Session};
+use crate::utils::{parse_expire, TPath};
+use crate::{Framework, CONF};
+
+/// Interval of removing expired sessions and recounting online users
+const SESSION_CLEANUP: Duration = Duration::from_secs(60);
+
+/// Backend of sessions, `F.set_session_storage()` replaces the default `MemorySessions`.
+/// The calls run on the blocking thread pool, so implementations may use blocking I/O.
+pub trait SessionStorage: Send + Sync {
+    /// Returns an unexpired session
+    fn get(&self, id: &str) -> Option<Session>;
+    /// Creates or updates a session
+    fn set(&self, session: Session);
+    fn remove(&self, id: &str);
+    /// Removes expired sessions and returns the number of active ones
+    fn clean(&self) -> usize;
+}
+
+/// Sessions kept in memory, they are lost on restart
+#[derive(Default)]
+pub struct MemorySessions {
+    items: RwLock<HashMap<String, Session>>,
+}
+
+impl SessionStorage for MemorySessions {
+    fn get(&self, id: &str) -> Option<Session> {
+        self.items.read().unwrap().get(id).filter(|session| session.expires > Utc::now()).cloned()
+    }
+
+    fn set(&self, session: Session) {
+        self.items.write().unwrap().insert(session.id.clone(), session);
+    }
+
+    fn remove(&self, id: &str) {
+        self.items.write().unwrap().remove(id);
+    }
+
+    fn clean(&self) -> usize {
+        let now = Utc::now();
+        let mut items = self.items.write().unwrap();
+        items.retain(|_, session| session.expires > now);
+        items.len()
+    }
+}
+
+/// Sessions stored as JSON files in `TPath::databases("sessions")`, they survive restarts
+pub struct FileSessions {
+    directory: PathBuf,
+}
+
+impl FileSessions {
+    pub fn new(path: &TPath) -> Self {
+        let directory = path.databases(Some("sessions"));
+        path.verify(&directory);
+        Self { directory }
+    }
+
+    /// Ids are generated by `create_id()`, anything else can't be a file name
+    fn file(&self, id: &str) -> Option<PathBuf> {
+        let valid = !id.is_empty() && id.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+        valid.then(|| self.directory.join(format!("{}.json", id)))
+    }
+
+    fn read(&self, path: &PathBuf) -> Option<(FrameworkValue, DateTime<Utc>)> {
+        let value: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+        let expires = value["expires"].as_str()?.parse::<DateTime<Utc>>().ok()?;
+        Some((value["data"].clone().into(), expires))
+    }
+}
+
+impl SessionStorage for FileSessions {
+    fn get(&self, id: &str) -> Option<Session> {
+        let (data, expires) = self.read(&self.file(id)?)?;
+        (expires > Utc::now()).then(|| Session { id: id.to_string(), data, expires })
+    }
+
+    fn set(&self, session: Session) {
+        let path = match self.file(&session.id) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let value = serde_json::json!({ "data": serde_json::Value::from(session.data), "expires": session.expires.to_rfc3339() });
+        if let Err(err) = std::fs::write(&path, value.to_string()) {
+            eprintln!("Failed to save session to {}: {}", path.display(), err);
+        }
+    }
+
+    fn remove(&self, id: &str) {
+        if let Some(path) = self.file(id) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    fn clean(&self) -> usize {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+
+        let now = Utc::now();
+        let mut count = 0;
+
+        for path in entries.flatten().map(|entry| entry.path()).filter(|path| path.extension().is_some_and(|extension| extension == "json")) {
+            match self.read(&path) {
+                Some((_, expires)) if expires > now => count += 1,
+                _ => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+
+        count
+    }
+}
+
+/// Creates a random session id
+fn create_id() -> String {
+    let mut bytes = [0u8; 24];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn expiration() -> DateTime<Utc> {
+    let expire = parse_expire(&CONF.read().unwrap()._sessionexpire).unwrap_or(Duration::from_secs(30 * 60));
+    chrono::Duration::from_std(expire)
+        .ok()
+        .and_then(|expire| Utc::now().checked_add_signed(expire))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+/// Update of the session storage made by `Controller::save_session()`
+pub(crate) type SessionUpdate = Box<dyn FnOnce() + Send>;
+
+/// Runs a task on the blocking thread pool, or in place outside of the runtime
+pub(crate) fn run_blocking(task: impl FnOnce() + Send + 'static) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => drop(handle.spawn_blocking(task)),
+        Err(_) => task(),
+    }
+}
+
+impl Controller {
+    /// Loads the session of the encrypted session cookie
+    pub(crate) async fn load_session(&mut self) {
+        let cookie = CONF.read().unwrap()._sessioncookie.clone();
+        let id = match self.get_cookie_encrypted(&cookie) {
+            Some(id) => id,
+            None => return,
+        };
+
+        let storage = self.framework.session_storage();
+        if let Ok(Some(session)) = tokio::task::spawn_blocking(move || storage.get(&id)).await {
+            self.session = Some(session.data);
+            self.session_id = Some(session.id);
+        }
+    }
+
+    /// Extends the expiration of the session, a new session sets the session cookie and a removed
+    /// session (`None`) is destroyed. Returns the update of the storage, it runs before the response is sent.
+    pub(crate) fn save_session(&mut self) -> Option<SessionUpdate> {
+        let storage = self.framework.session_storage();
+        let cookie = CONF.read().unwrap()._sessioncookie.clone();
+
+        match (self.session.clone(), self.session_id.clone()) {
+            (Some(data), Some(id)) => Some(Box::new(move || storage.set(Session { id, data, expires: expiration() }))),
+            (Some(data), None) => {
+                let id = create_id();
+                self.cookie_encrypted(&cookie, &id, "", CookieOptions::default());
+                self.session_id = Some(id.clone());
+                self.framework.stats.write().unwrap().performance.online += 1;
+                Some(Box::new(move || storage.set(Session { id, data, expires: expiration() })))
+            }
+            (None, Some(id)) => {
+                self.remove_cookie(&cookie, CookieOptions::default());
+                self.session_id = None;
+
+                let mut stats = self.framework.stats.write().unwrap();
+                stats.performance.online = (stats.performance.online - 1).max(0);
+                Some(Box::new(move || storage.remove(&id)))
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+impl Framework {
+    /// Replaces the session backend, e.g. `F.set_session_storage(FileSessions::new(&F.path))`
+    pub fn set_session_storage(&self, storage: impl SessionStorage + 'static) {
+        *self.sessions.write().unwrap() = Arc::new(storage);
+    }
+
+    /// Returns the current session backend
+    pub fn session_storage(&self) -> Arc<dyn SessionStorage> {
+        self.sessions.read().unwrap().clone()
+    }
+
+    /// Removes expired sessions and updates `PerformanceStats.online`
+    pub fn session_cleanup(&self) {
+        let online = self.session_storage().clean();
+        self.stats.write().unwrap().performance.online = online as i64;
+    }
+
+    /// Runs `session_cleanup()` periodically until the server is closed
+    pub(crate) async fn session_cleaner(&'static self, mut signal: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(SESSION_CLEANUP);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let _ = tokio::task::spawn_blocking(move || self.session_cleanup()).await;
+                }
+                _ = signal.changed() => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Total-rs server-sent events
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::controller::with_charset;
+use crate::http::{Response, ResponseBody};
+use crate::types::{Controller, ServerSentEvents, SseEvent};
+
+/// Interval of comments keeping the connection open, they also reveal disconnected clients
+const SSE_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// Field values can't contain line breaks
+fn line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// Serializes an event in the `text/event-stream` format
+pub(crate) fn format_event(event: &SseEvent) -> String {
+    let mut output = String::new();
+
+    if let Some(name) = &event.event {
+        output.push_str(&format!("event: {}\n", line(name)));
+    }
+
+    if let Some(id) = &event.id {
+        output.push_str(&format!("id: {}\n", line(id)));
+    }
+
+    if let Some(retry) = event.retry {
+        output.push_str(&format!("retry: {}\n", retry));
+    }
+
+    if !event.data.is_empty() {
+        for data in event.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            output.push_str(&format!("data: {}\n", data));
+        }
+    }
+
+    output.push('\n');
+    output
+}
+
+/// Writes comments until the response is closed
+async fn heartbeat(sender: mpsc::WeakSender<Vec<u8>>, period: Duration) {
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        match sender.upgrade() {
+            Some(sender) if sender.send(b": heartbeat\n\n".to_vec()).await.is_ok() => {}
+            _ => break,
+        }
+    }
+}
+
+impl ServerSentEvents {
+    /// Sends an event, returns `false` if the client has disconnected
+    pub async fn send(&self, event: SseEvent) -> bool {
+        self.sender.send(format_event(&event).into_bytes()).await.is_ok()
+    }
+
+    /// Sends a `message` event
+    pub async fn data(&self, data: impl Into<String>) -> bool {
+        self.send(SseEvent { data: data.into(), ..Default::default() }).await
+    }
+
+    /// A disconnected client is detected by the next event or heartbeat
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Waits until the client disconnects, e.g. in `select!` with the producer
+    pub async fn closed(&self) {
+        self.sender.closed().await
+    }
+}
+
+impl Controller {
+    /// Opens a `text/event-stream` response, the client resumes with `ServerSentEvents.last_event_id`
+    pub fn sse(self) -> ServerSentEvents {
+        self.framework.stats.write().unwrap().response.sse += 1;
+
+        let last_event_id = self.header("last-event-id").map(String::from);
+        let (sender, receiver) = mpsc::channel(16);
+
+        let mut response = Response::new(200);
+        response.set_header("Content-Type", &with_charset("text/event-stream"));
+        response.set_header("Cache-Control", "no-cache");
+        // Disables buffering of nginx
+        response.set_header("X-Accel-Buffering", "no");
+        response.body = ResponseBody::Stream(receiver, None);
+
+        tokio::spawn(heartbeat(sender.downgrade(), SSE_HEARTBEAT));
+        self.send(response);
+        ServerSentEvents { last_event_id, sender }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_events() {
+        let event = SseEvent { event: Some("update".to_string()), id: Some("7\n".to_string()), retry: Some(3000), data: "line 1\r\nline 2\n".to_string() };
+        assert_eq!(format_event(&event), "event: update\nid: 7\nretry: 3000\ndata: line 1\ndata: line 2\ndata: \n\n");
+        assert_eq!(format_event(&SseEvent { data: "{}".to_string(), ..Default::default() }), "data: {}\n\n");
+        assert_eq!(format_event(&SseEvent { retry: Some(500), ..Default::default() }), "retry: 500\n\n");
+    }
+
+    #[tokio::test]
+    async fn sends_heartbeats_until_closed() {
+        let (sender, mut receiver) = mpsc::channel(16);
+        tokio::spawn(heartbeat(sender.downgrade(), Duration::from_millis(10)));
+
+        assert_eq!(receiver.recv().await.unwrap(), b": heartbeat\n\n");
+        assert_eq!(receiver.recv().await.unwrap(), b": heartbeat\n\n");
+
+        // The response ends when the events are dropped
+        drop(sender);
+        let ended = tokio::time::timeout(Duration::from_secs(1), async { while receiver.recv().await.is_some() {} }).await;
+        assert!(ended.is_ok());
+    }
+}
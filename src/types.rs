@@ -3,12 +3,20 @@
 // Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
 
 use std::collections::HashMap;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use chrono::{DateTime, Utc};
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use crate::blacklist::IpRange;
+use crate::http::{Request, Response};
+use crate::websocket::{Deflate, Outgoing};
+use crate::Framework;
 
 
 /// Framework value types that can be stored in various collections
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub enum FrameworkValue {
     String(String),
     Number(i64),
@@ -16,12 +24,56 @@ pub enum FrameworkValue {
     Boolean(bool),
     Object(HashMap<String, FrameworkValue>),
     Array(Vec<FrameworkValue>),
+    #[default]
     Null,
 }
 
-impl Default for FrameworkValue {
-    fn default() -> Self {
-        FrameworkValue::Null
+impl FrameworkValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            FrameworkValue::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            FrameworkValue::Number(value) => Some(*value),
+            FrameworkValue::Float(value) if value.fract() == 0.0 => Some(*value as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FrameworkValue::Number(value) => Some(*value as f64),
+            FrameworkValue::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            FrameworkValue::Boolean(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, FrameworkValue::Null)
+    }
+}
+
+impl std::fmt::Display for FrameworkValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameworkValue::String(value) => write!(f, "{}", value),
+            FrameworkValue::Number(value) => write!(f, "{}", value),
+            FrameworkValue::Float(value) => write!(f, "{}", value),
+            FrameworkValue::Boolean(value) => write!(f, "{}", value),
+            FrameworkValue::Null => Ok(()),
+            FrameworkValue::Object(_) | FrameworkValue::Array(_) => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -67,6 +119,36 @@ impl From<Vec<FrameworkValue>> for FrameworkValue {
     }
 }
 
+impl From<serde_json::Value> for FrameworkValue {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => FrameworkValue::Null,
+            serde_json::Value::Bool(b) => FrameworkValue::Boolean(b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(n) => FrameworkValue::Number(n),
+                None => FrameworkValue::Float(n.as_f64().unwrap_or_default()),
+            },
+            serde_json::Value::String(s) => FrameworkValue::String(s),
+            serde_json::Value::Array(items) => FrameworkValue::Array(items.into_iter().map(FrameworkValue::from).collect()),
+            serde_json::Value::Object(map) => FrameworkValue::Object(map.into_iter().map(|(key, value)| (key, FrameworkValue::from(value))).collect()),
+        }
+    }
+}
+
+impl From<FrameworkValue> for serde_json::Value {
+    fn from(value: FrameworkValue) -> Self {
+        match value {
+            FrameworkValue::Null => serde_json::Value::Null,
+            FrameworkValue::Boolean(b) => serde_json::Value::Bool(b),
+            FrameworkValue::Number(n) => serde_json::Value::from(n),
+            FrameworkValue::Float(f) => serde_json::Value::from(f),
+            FrameworkValue::String(s) => serde_json::Value::String(s),
+            FrameworkValue::Array(items) => serde_json::Value::Array(items.into_iter().map(serde_json::Value::from).collect()),
+            FrameworkValue::Object(map) => serde_json::Value::Object(map.into_iter().map(|(key, value)| (key, serde_json::Value::from(value))).collect()),
+        }
+    }
+}
+
 /// Stats about cluster operations
 #[derive(Debug, Clone)]
 pub struct ClusterStats {
@@ -113,7 +195,9 @@ pub struct PerformanceStats {
 /// Other statistics
 #[derive(Debug, Clone, Default)]
 pub struct OtherStats {
+    /// Pings sent to WebSocket clients
     pub websocketping: i64,
+    /// WebSocket clients closed for not answering pings in time
     pub websocketcleaner: i64,
     pub obsolete: i64,
     pub mail: i64,
@@ -207,11 +291,16 @@ pub struct Config {
     pub _httprangebuffer: usize,
     pub _httptimeout: u64,
     pub _httpfiles: HashMap<String, bool>,
+    /// Static files are served only when their extension has a known MIME type
     pub _httpchecktypes: bool,
     pub _httpmaxage: u64,
     pub _httpmaxkeys: usize,
     pub _httpmaxkey: usize,
     pub _blacklist: String,
+    /// 401/403/404 responses per minute which ban the IP, `0` (default) disables automatic bans
+    pub _banlimit: usize,
+    /// Duration of automatic bans, e.g. `15 minutes`
+    pub _banexpire: String,
     pub _xpoweredby: String,
     pub _maxopenfiles: usize,
     pub _minifyjs: bool,
@@ -221,6 +310,8 @@ pub struct Config {
     pub _port: String,
     pub _ip: String,
     pub _unixsocket: String,
+    /// Permissions of the Unix socket, e.g. `0o666` when the reverse proxy runs under another user and group
+    pub _unixsocketmode: u32,
     pub _timezone: String,
     pub _insecure: bool,
     pub _performance: bool,
@@ -234,13 +325,22 @@ pub struct Config {
     pub _stats: bool,
     pub _npmcache: String,
     pub _python: String,
+    /// Maximum size of WebSocket messages (kB), the route limit (`<64kB`) overrides it
     pub _wsmaxsize: usize,
+    /// Accepts the permessage-deflate extension offered by clients
     pub _wscompress: bool,
+    /// URI-encoded text messages like Total.js clients with `encodedecode`
     pub _wsencodedecode: bool,
+    /// Clients whose ping latency exceeds this (ms) are closed, `0` disables it
     pub _wsmaxlatency: usize,
+    /// Time (seconds) for connecting to the upstream of `PROXY()` and receiving its response head
     pub _proxytimeout: u64,
     pub _cookiesamesite: String,
     pub _cookiesecure: bool,
+    /// Name of the encrypted cookie with the session id
+    pub _sessioncookie: String,
+    /// Idle time after which sessions expire, e.g. `30 minutes`
+    pub _sessionexpire: String,
     pub _csrfexpiration: String,
     pub _tapi: bool,
     pub _tapiurl: String,
@@ -260,6 +360,7 @@ pub struct Config {
 }
 
 /// Framework statistics
+#[derive(Debug, Clone, Default)]
 pub struct Stats {
     pub compilation: i64,
     pub error: i64,
@@ -279,7 +380,7 @@ pub struct ServiceStats {
 }
 
 /// Temporary storage
-
+#[derive(Default)]
 pub struct Temporary {
     pub path: HashMap<String, FrameworkValue>,
     pub actions: HashMap<String, FrameworkValue>,
@@ -295,7 +396,7 @@ pub struct Temporary {
     pub cryptokeys: HashMap<String, FrameworkValue>,
     pub internal: HashMap<String, FrameworkValue>,
     pub ready: HashMap<String, FrameworkValue>,
-    pub ddos: HashMap<String, FrameworkValue>,
+    pub ddos: HashMap<String, DDOSEntry>,
     pub service: ServiceStats,
     pub pending: Vec<FrameworkValue>,
     pub tmp: HashMap<String, FrameworkValue>,
@@ -304,7 +405,9 @@ pub struct Temporary {
     pub tmsblocked: HashMap<String, FrameworkValue>,
     pub dnscache: HashMap<String, FrameworkValue>,
     pub blocked: HashMap<String, FrameworkValue>,
-    pub bans: HashMap<String, FrameworkValue>,
+    pub bans: HashMap<String, Ban>,
+    /// 401/403/404 responses per IP in the current minute
+    pub violations: HashMap<String, (u32, DateTime<Utc>)>,
     pub calls: HashMap<String, FrameworkValue>,
     pub utils: HashMap<String, FrameworkValue>,
     pub mail: HashMap<String, FrameworkValue>,
@@ -315,20 +418,76 @@ pub struct Temporary {
     pub datetime: HashMap<String, FrameworkValue>,
 }
 
+/// Parsed `CONF._blacklist` and entries of `block()`
+#[derive(Debug, Default)]
+pub struct Blacklist {
+    /// `CONF._blacklist` parsed into `configured`
+    pub(crate) source: String,
+    pub(crate) configured: Vec<IpRange>,
+    pub(crate) blocked: Vec<IpRange>,
+}
+
+#[derive(Default)]
 pub struct Routes {
-    pub fallback: HashMap<String, FrameworkValue>,
+    pub fallback: HashMap<String, RouteHandler>,
     pub virtual_routes: HashMap<String, FrameworkValue>,
-    pub api: HashMap<String, FrameworkValue>,
-    pub routes: Vec<FrameworkValue>,
-    pub routescache: HashMap<String, FrameworkValue>,
-    pub websockets: Vec<FrameworkValue>,
-    pub websocketscache: HashMap<String, FrameworkValue>,
+    pub api: HashMap<String, Vec<Arc<Route>>>,
+    pub routes: Vec<Arc<Route>>,
+    pub routescache: HashMap<String, Arc<Route>>,
+    pub websockets: Vec<Arc<WebSocketRoute>>,
+    pub websocketscache: HashMap<String, Arc<WebSocketRoute>>,
     pub files: Vec<FrameworkValue>,
     pub filescache: HashMap<String, FrameworkValue>,
     pub timeout: Option<i64>,
     pub middleware: HashMap<String, FrameworkValue>,
     pub imagesmiddleware: HashMap<String, FrameworkValue>,
-    pub proxies: Vec<FrameworkValue>,
+    /// Sorted from the longest source
+    pub proxies: Vec<Arc<Proxy>>,
+    pub cors: Vec<Cors>,
+}
+
+/// Attributes of `Controller::cookie()`, unset values fall back to `Config._cookiesamesite`,
+/// `Config._cookiesecure` and `HttpOnly`
+#[derive(Debug, Clone, Default)]
+pub struct CookieOptions {
+    pub domain: Option<String>,
+    /// Defaults to `/`
+    pub path: Option<String>,
+    /// `Strict`, `Lax` or `None`
+    pub samesite: Option<String>,
+    pub secure: Option<bool>,
+    pub httponly: Option<bool>,
+}
+
+/// Event sent by `ServerSentEvents::send()`, events without `data` only update `id` or `retry`
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    /// Name of the event, the client dispatches `message` when unset
+    pub event: Option<String>,
+    /// Sent back by the client in `Last-Event-ID` when it reconnects
+    pub id: Option<String>,
+    /// Reconnection delay of the client (ms)
+    pub retry: Option<u64>,
+    /// Multi-line data is split into several `data:` lines
+    pub data: String,
+}
+
+/// `text/event-stream` response opened by `Controller::sse()`, it ends when all clones are dropped
+#[derive(Clone)]
+pub struct ServerSentEvents {
+    /// `Last-Event-ID` of a reconnected client
+    pub last_event_id: Option<String>,
+    pub(crate) sender: mpsc::Sender<Vec<u8>>,
+}
+
+/// CORS policy registered by `CORS()`, overrides `Config._cors` for matching paths
+#[derive(Debug, Clone, Default)]
+pub struct Cors {
+    /// Normalized path, a trailing `*` matches all nested paths
+    pub path: String,
+    /// Allowed origins, e.g. `https://totaljs.com`, `*.totaljs.com` or `*`
+    pub origins: Vec<String>,
+    pub credentials: bool,
 }
 
 
@@ -345,10 +504,14 @@ pub struct Currency {
     pub symbol: String,
 }
 
+pub type JsonParser = Box<dyn Fn(&str) -> Result<serde_json::Value, serde_json::Error> + Send + Sync>;
+pub type UrlencodedParser = Box<dyn Fn(&str) -> Result<FrameworkValue, String> + Send + Sync>;
+pub type XmlParser = Box<dyn Fn(&str) -> Result<FrameworkValue, String> + Send + Sync>;
+
 pub struct Parsers {
-    pub json: Box<dyn Fn(&str) -> Result<serde_json::Value, serde_json::Error> + Send + Sync>,
-    pub urlencoded: Box<dyn Fn(&str) -> HashMap<String, String> + Send + Sync>,
-    pub xml: Box<dyn Fn(&str) -> Result<String, String> + Send + Sync>,
+    pub json: JsonParser,
+    pub urlencoded: UrlencodedParser,
+    pub xml: XmlParser,
 }
 
 pub struct Validators {
@@ -361,12 +524,59 @@ pub struct Validators {
     pub sqlinjection: regex::Regex,
 }
 
+/// Uploaded file
+#[derive(Debug, Clone)]
+pub struct HttpFile {
+    pub name: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: usize,
+    pub path: PathBuf,
+}
+
 pub struct Controller {
     pub ip: String,
+    pub method: String,
+    pub url: String,
+    pub path: String,
     pub headers: HashMap<String, String>,
     pub query: HashMap<String, String>,
+    pub params: HashMap<String, FrameworkValue>,
+    pub body: FrameworkValue,
+    pub payload: Vec<u8>,
+    pub cookies: HashMap<String, String>,
+    pub files: Vec<HttpFile>,
+    pub user: Option<FrameworkValue>,
+    /// Session data, set it to create a session or to `None` to destroy it
+    pub session: Option<FrameworkValue>,
+    pub language: String,
+    pub mobile: bool,
+    pub xhr: bool,
+    pub route: Option<Arc<Route>>,
+    pub(crate) status: u16,
+    pub(crate) response_headers: Vec<(String, String)>,
+    pub(crate) session_id: Option<String>,
+    pub(crate) framework: &'static Framework,
+    pub(crate) sender: Option<oneshot::Sender<Response>>,
+}
+
+/// Context of an action (`$` in Total.js actions)
+pub struct ActionOptions {
+    pub name: String,
+    pub ip: String,
+    pub language: String,
+    pub params: HashMap<String, FrameworkValue>,
+    pub query: HashMap<String, String>,
+    pub model: FrameworkValue,
+    pub user: Option<FrameworkValue>,
+    pub(crate) sender: Option<oneshot::Sender<Result<FrameworkValue, String>>>,
 }
 
+pub type ActionHandler = Arc<dyn Fn(ActionOptions) + Send + Sync>;
+
+/// Resolves the signed-in user of a request (`AUTH()` delegate)
+pub type AuthHandler = Arc<dyn Fn(&Controller) -> Option<FrameworkValue> + Send + Sync>;
+
 pub struct TMail {
     // Email structure
 }
@@ -382,24 +592,6 @@ pub struct Message {
     pub bcc: Vec<String>,
     pub _sending: Option<std::time::Instant>,
 }
-pub struct Path {
-    pub root: String,
-    pub logs: String,
-    pub scripts: String,
-    pub public: String,
-    pub private: String,
-    pub databases: String,
-    pub plugins: String,
-    pub templates: String,
-    pub flowstreams: String,
-    pub modules: String,
-    pub tmp: String,
-}
-pub struct TPath {
-    pub base_dir: String,
-    pub temporary_dirs: HashMap<String, String>,
-}
-
 
 pub struct CronJob {
     // Cron job properties
@@ -416,6 +608,7 @@ pub struct ErrorInfo {
 }
 
 
+#[derive(Default)]
 pub struct SMTPConfig {
     pub from: Option<String>,
     pub name: Option<String>,
@@ -429,23 +622,107 @@ pub struct Internal {
     pub interval: Option<std::time::Duration>,
 }
 
+pub type RouteHandler = Arc<dyn Fn(Controller) + Send + Sync>;
+
+/// Constraint of a dynamic route parameter (`{id:uid}`)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RouteParamType {
+    #[default]
+    String,
+    Number,
+    Boolean,
+    Uid,
+    Email,
+    Phone,
+    Zip,
+    Url,
+}
+
+/// Part of a route URL
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteSegment {
+    Static(String),
+    Param(String, RouteParamType),
+    Wildcard,
+}
+
+/// Authorization required by a route (`+GET` / `-GET`)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RouteAuth {
+    #[default]
+    Any,
+    Authorized,
+    Unauthorized,
+}
+
 pub struct Route {
     // Route properties
+    pub declaration: String,
+    pub method: String,
     pub path: String,
-    pub handler: Box<dyn Fn() + Send + Sync>,
-}
-
+    pub segments: Vec<RouteSegment>,
+    pub params: Vec<String>,
+    pub auth: RouteAuth,
+    pub timeout: Option<Duration>,
+    pub size: Option<usize>,
+    pub flags: Vec<String>,
+    pub actions: Vec<String>,
+    pub response: Option<usize>,
+    // Name of the operation for `API` routes (the segments describe the operation, `path` the endpoint)
+    pub operation: Option<String>,
+    pub priority: i64,
+    pub handler: Option<RouteHandler>,
+}
+
+/// WebSocket route registered by `F.websocket()`, e.g. `SOCKET /chat/{room}/ @json <64kB`
 pub struct WebSocketRoute {
-    // WebSocket route properties
-    pub path: String,
-    pub handler: Box<dyn Fn() + Send + Sync>,
+    /// Parsed declaration (segments, flags, size limit and authorization)
+    pub route: Route,
+    pub(crate) clients: RwLock<HashMap<String, Arc<WebSocketConnection>>>,
+    pub(crate) on_open: RwLock<Option<WebSocketHandler>>,
+    pub(crate) on_message: RwLock<Option<WebSocketMessageHandler>>,
+    pub(crate) on_close: RwLock<Option<WebSocketCloseHandler>>,
 }
 
+/// Client connected to a `WebSocketRoute`
 pub struct WebSocketConnection {
-    // WebSocket connection properties
     pub id: String,
-    pub socket: Box<dyn std::any::Any + Send + Sync>,
-}
+    pub ip: String,
+    pub url: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub query: HashMap<String, String>,
+    pub params: HashMap<String, FrameworkValue>,
+    pub user: Option<FrameworkValue>,
+    pub session: Option<FrameworkValue>,
+    pub language: String,
+    pub(crate) state: Mutex<HashMap<String, FrameworkValue>>,
+    /// Negotiated permessage-deflate parameters
+    pub(crate) deflate: Option<Deflate>,
+    /// Text messages are URI-encoded (`CONF._wsencodedecode`)
+    pub(crate) encodedecode: bool,
+    /// Time of the ping waiting for a pong
+    pub(crate) ping: Mutex<Option<Instant>>,
+    /// Round trip of the last answered ping
+    pub(crate) latency: Mutex<Option<Duration>>,
+    pub(crate) sender: mpsc::Sender<Outgoing>,
+    pub(crate) closing: Notify,
+    /// The queue is full, the close frame is written before the queued frames
+    pub(crate) overflow: Notify,
+}
+
+/// Message received from or sent to a WebSocket client, `@json` routes receive `Json`
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+    Json(FrameworkValue),
+}
+
+pub type WebSocketHandler = Arc<dyn Fn(Arc<WebSocketConnection>) + Send + Sync>;
+pub type WebSocketMessageHandler = Arc<dyn Fn(Arc<WebSocketConnection>, WebSocketMessage) + Send + Sync>;
+/// Receives the close code, `1006` means the connection has been lost
+pub type WebSocketCloseHandler = Arc<dyn Fn(Arc<WebSocketConnection>, u16) + Send + Sync>;
 
 pub struct FileRoute {
     // File route properties
@@ -463,20 +740,40 @@ pub struct ImageMiddlewareHandler {
     pub handler: Box<dyn Fn() + Send + Sync>,
 }
 
+/// Reverse proxy registered by `PROXY()`
 pub struct Proxy {
-    // Proxy properties
+    /// Normalized path prefix, e.g. `/old-api/`
     pub source: String,
+    /// Upstream URL, e.g. `http://127.0.0.1:9000/`
     pub target: String,
-}
+    /// Authority of the target sent in `Host`
+    pub(crate) host: String,
+    /// `host:port` of the upstream
+    pub(crate) address: String,
+    /// Path of the target which replaces `source`
+    pub(crate) path: String,
+    pub(crate) before: RwLock<Option<ProxyBeforeHandler>>,
+    pub(crate) after: RwLock<Option<ProxyAfterHandler>>,
+}
+
+/// Can modify the request (`url` is the rewritten upstream path) or answer it instead of the upstream
+pub type ProxyBeforeHandler = Arc<dyn Fn(&mut Request) -> Option<Response> + Send + Sync>;
+/// Can modify the status and the headers of the upstream response
+pub type ProxyAfterHandler = Arc<dyn Fn(&Request, &mut Response) + Send + Sync>;
 pub struct CryptoKey {
     // Crypto key properties
     pub key: String,
 }
 
+/// Requests of a single IP tracked by the `CONF._httpreqlimit` limiter
+#[derive(Debug, Clone)]
 pub struct DDOSEntry {
-    // DDOS entry properties
+    /// Requests in the current window
     pub count: u32,
+    /// End of the current window
     pub expires: DateTime<Utc>,
+    /// Requests in progress
+    pub pending: u32,
 }
 
 pub struct Service {
@@ -492,8 +789,17 @@ pub struct PendingItem {
     pub created: DateTime<Utc>,
 }
 
+/// Session loaded from a `SessionStorage`, `expires` moves with every request
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub data: FrameworkValue,
+    pub expires: DateTime<Utc>,
+}
+
+/// Temporary ban of an IP
+#[derive(Debug, Clone)]
 pub struct Ban {
-    // Ban properties
     pub ip: String,
     pub expires: DateTime<Utc>,
 }
@@ -528,6 +834,7 @@ pub struct SuccessResult<T> {
     pub value: T,
 }
 
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct AuditData {
     pub dtcreated: DateTime<Utc>,
     // Other audit data fields would go here
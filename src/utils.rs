@@ -2,7 +2,13 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs;
-use tokio;
+
+use crate::cookies::{open, seal};
+use crate::types::FrameworkValue;
+
+/// Authenticated with the ciphertext, values of `encrypt()` can't be used as encrypted cookies
+const ENCRYPT_CONTEXT: &str = "encrypt";
+
 #[derive(Debug, Clone)]
 pub struct TPath {
     base_dir: PathBuf,
@@ -149,13 +155,12 @@ impl TPath {
     // Route handling 
     pub fn route(&self, path: &str, directory: &str) -> PathBuf {
         // Absolute path
-        if path.starts_with('~') {
-            return PathBuf::from(&path[1..]);
+        if let Some(absolute) = path.strip_prefix('~') {
+            return PathBuf::from(absolute);
         }
         
         // Plugin paths
-        if path.starts_with('_') {
-            let tmp = &path[1..];
+        if let Some(tmp) = path.strip_prefix('_') {
             if let Some(index) = tmp.find('/') {
                 let plugin_name = &tmp[..index];
                 let dir_part = if directory == "root" { "" } else { directory };
@@ -206,3 +211,321 @@ impl TPath {
         path.exists()
     }
 }
+
+/// Parses Total.js style expirations such as `30 minutes`, `1 day` or `5s`
+pub fn parse_expire(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim().to_lowercase();
+    let index = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let count: u64 = value[..index].trim().parse().ok()?;
+    let unit = value[index..].trim();
+
+    let multiplier: u64 = match unit {
+        "ms" | "millisecond" | "milliseconds" => return Some(std::time::Duration::from_millis(count)),
+        "" | "s" | "sec" | "second" | "seconds" => 1,
+        "m" | "min" | "minute" | "minutes" => 60,
+        "h" | "hour" | "hours" => 3600,
+        "d" | "day" | "days" => 86400,
+        "w" | "week" | "weeks" => 604800,
+        "month" | "months" => 2592000,
+        "y" | "year" | "years" => 31536000,
+        _ => return None,
+    };
+
+    count.checked_mul(multiplier).map(std::time::Duration::from_secs)
+}
+
+/// Computes a short hash of the User-Agent header (used by CSRF tokens)
+pub fn hash_user_agent(ua: &str) -> String {
+    let mut hash: i32 = 0;
+    for c in ua.chars() {
+        hash = hash.wrapping_shl(5).wrapping_sub(hash).wrapping_add(c as i32);
+    }
+
+    let mut number = hash.unsigned_abs();
+    let mut output = Vec::new();
+    loop {
+        output.push(std::char::from_digit(number % 36, 36).unwrap());
+        number /= 36;
+        if number == 0 {
+            break;
+        }
+    }
+    output.iter().rev().collect()
+}
+
+/// Encrypts and authenticates a value with AES-256-GCM under a key derived from `key`,
+/// every call uses a random nonce (`base64url(nonce + ciphertext + tag)`)
+pub fn encrypt(value: &str, key: &str) -> String {
+    if key.is_empty() {
+        return String::new();
+    }
+    seal(ENCRYPT_CONTEXT, value, key)
+}
+
+/// Decrypts a value created by `encrypt()`, returns `None` if the value has been tampered with
+pub fn decrypt(value: &str, key: &str) -> Option<String> {
+    if key.is_empty() {
+        return None;
+    }
+    open(ENCRYPT_CONTEXT, value, key)
+}
+
+/// Percent-encodes a value like `encodeURIComponent()` in browsers
+pub fn encode_uri(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.!~*'()".contains(&byte) {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    output
+}
+
+/// Decodes percent-encoded sequences (invalid sequences are kept as they are)
+pub fn decode_uri(value: &str) -> String {
+    if !value.contains('%') {
+        return value.to_string();
+    }
+
+    let bytes = value.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        // `from_str_radix()` alone would accept a sign, e.g. `%+1`
+        if bytes[index] == b'%' && index + 2 < bytes.len() && bytes[index + 1..index + 3].iter().all(u8::is_ascii_hexdigit) {
+            if let Some(byte) = std::str::from_utf8(&bytes[index + 1..index + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                output.push(byte);
+                index += 3;
+                continue;
+            }
+        }
+        output.push(bytes[index]);
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&output).to_string()
+}
+
+/// Decodes a component of an `application/x-www-form-urlencoded` string (`+` is a space)
+fn decode_form(value: &str) -> String {
+    decode_uri(&value.replace('+', " "))
+}
+
+/// Splits `a[b][c]` and `a.b.c` keys into their path, `a[]` ends with an empty segment
+fn key_path(key: &str) -> Vec<String> {
+    let (head, nested) = match key.find('[') {
+        Some(index) if index > 0 && key.ends_with(']') => (&key[..index], Some(&key[index + 1..key.len() - 1])),
+        _ => (key, None),
+    };
+
+    let mut path: Vec<String> = head.split('.').map(String::from).collect();
+    if let Some(nested) = nested {
+        path.extend(nested.split("][").map(String::from));
+    }
+    path
+}
+
+fn insert_form(map: &mut HashMap<String, FrameworkValue>, path: &[String], value: String) {
+    let key = path[0].clone();
+    let array = path.len() == 2 && path[1].is_empty();
+
+    if path.len() > 1 && !array {
+        // A key can't be a value and an object at once, the first declaration wins
+        if let FrameworkValue::Object(child) = map.entry(key).or_insert_with(|| FrameworkValue::Object(HashMap::new())) {
+            insert_form(child, &path[1..], value);
+        }
+        return;
+    }
+
+    match map.get_mut(&key) {
+        Some(FrameworkValue::Array(items)) => items.push(FrameworkValue::String(value)),
+        Some(existing @ FrameworkValue::String(_)) => {
+            let previous = std::mem::take(existing);
+            *existing = FrameworkValue::Array(vec![previous, FrameworkValue::String(value)]);
+        }
+        Some(_) => {}
+        None => {
+            let value = FrameworkValue::String(value);
+            map.insert(key, if array { FrameworkValue::Array(vec![value]) } else { value });
+        }
+    }
+}
+
+/// Parses an `application/x-www-form-urlencoded` string into an object,
+/// nested keys (`a[b]=1`, `a.b=1`) create objects and repeated keys (`a=1&a=2`, `a[]=1`) create arrays
+pub fn parse_urlencoded(value: &str, maxkeys: usize, maxkey: usize) -> Result<FrameworkValue, String> {
+    let mut map = HashMap::new();
+
+    for (count, pair) in value.split('&').filter(|pair| !pair.is_empty()).enumerate() {
+        if count >= maxkeys {
+            return Err(format!("too many keys, the limit is {}", maxkeys));
+        }
+
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = decode_form(key);
+
+        if key.chars().count() > maxkey {
+            return Err(format!("the key is longer than {} characters", maxkey));
+        }
+
+        if !key.is_empty() {
+            insert_form(&mut map, &key_path(&key), decode_form(value));
+        }
+    }
+
+    Ok(FrameworkValue::Object(map))
+}
+
+/// Parses a query string into decoded `key => value` pairs, the last value of a repeated key wins
+pub fn parse_query(value: &str) -> HashMap<String, String> {
+    value
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .map(|(key, value)| (decode_form(key), decode_form(value)))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+/// Returns the MIME type for a file extension (without the dot)
+pub fn content_type(ext: &str) -> &'static str {
+    match ext.to_lowercase().as_str() {
+        "appcache" => "text/cache-manifest",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "eot" => "application/vnd.ms-fontobject",
+        "flac" => "audio/flac",
+        "gif" => "image/gif",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "htm" | "html" => "text/html",
+        "ico" => "image/x-icon",
+        "ics" => "text/calendar",
+        "ipynb" | "ijsnb" | "json" | "map" => "application/json",
+        "jpeg" | "jpg" => "image/jpeg",
+        "js" | "jsx" | "mjs" => "text/javascript",
+        "log" | "txt" => "text/plain",
+        "m3u8" => "application/vnd.apple.mpegurl",
+        "m4v" => "video/x-m4v",
+        "manifest" => "text/cache-manifest",
+        "md" => "text/markdown",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "ogg" => "audio/ogg",
+        "ogv" => "video/ogg",
+        "otf" => "font/otf",
+        "package" | "ui" => "text/plain",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "rar" => "application/x-rar-compressed",
+        "svg" => "image/svg+xml",
+        "swf" => "application/x-shockwave-flash",
+        "ts" => "video/mp2t",
+        "ttf" => "font/ttf",
+        "wasm" => "application/wasm",
+        "wav" => "audio/wav",
+        "webm" => "video/webm",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "xml" | "xsd" | "xsl" | "xslt" => "text/xml",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> FrameworkValue {
+        FrameworkValue::String(value.to_string())
+    }
+
+    #[test]
+    fn parses_urlencoded_values() {
+        let value = parse_urlencoded("name=Pe%C5%A5o+Sirka&empty=&flag&a%2Bb=1%2B1", 33, 25).unwrap();
+        let FrameworkValue::Object(map) = value else { panic!("object expected") };
+
+        assert_eq!(map.get("name"), Some(&string("Peťo Sirka")));
+        assert_eq!(map.get("empty"), Some(&string("")));
+        assert_eq!(map.get("flag"), Some(&string("")));
+        assert_eq!(map.get("a+b"), Some(&string("1+1")));
+    }
+
+    #[test]
+    fn parses_nested_keys_and_arrays() {
+        let value = parse_urlencoded("user[name]=Peter&user.address.city=Bratislava&tags=a&tags=b&ids[]=1&user[name]=Louis", 33, 25).unwrap();
+        let FrameworkValue::Object(map) = value else { panic!("object expected") };
+
+        let FrameworkValue::Object(user) = &map["user"] else { panic!("object expected") };
+        assert_eq!(user["name"], FrameworkValue::Array(vec![string("Peter"), string("Louis")]));
+
+        let FrameworkValue::Object(address) = &user["address"] else { panic!("object expected") };
+        assert_eq!(address["city"], string("Bratislava"));
+
+        assert_eq!(map["tags"], FrameworkValue::Array(vec![string("a"), string("b")]));
+        assert_eq!(map["ids"], FrameworkValue::Array(vec![string("1")]));
+    }
+
+    #[test]
+    fn enforces_key_limits() {
+        assert!(parse_urlencoded("a=1&b=2&c=3", 2, 25).is_err());
+        assert!(parse_urlencoded("a=1&b=2", 2, 25).is_ok());
+        assert!(parse_urlencoded("abcdef=1", 33, 5).is_err());
+        assert!(parse_urlencoded("%61%62%63=1", 33, 3).is_ok());
+    }
+
+    #[test]
+    fn parses_query_strings() {
+        let query = parse_query("q=total+js&page=2&page=3&%C3%A1=%E2%9C%93");
+        assert_eq!(query["q"], "total js");
+        assert_eq!(query["page"], "3");
+        assert_eq!(query["á"], "✓");
+    }
+
+    #[test]
+    fn encodes_uri_components() {
+        let value = r#"{"text":"Hello world & ✓"}"#;
+        assert_eq!(encode_uri(value), "%7B%22text%22%3A%22Hello%20world%20%26%20%E2%9C%93%22%7D");
+        assert_eq!(decode_uri(&encode_uri(value)), value);
+        assert_eq!(encode_uri("a-b_c.d!e~f*g'h(i)"), "a-b_c.d!e~f*g'h(i)");
+    }
+
+    #[test]
+    fn keeps_invalid_escapes() {
+        assert_eq!(decode_uri("%+1%-f%4"), "%+1%-f%4");
+        assert_eq!(decode_uri("100%zz%41"), "100%zzA");
+    }
+
+    #[test]
+    fn parses_expirations() {
+        assert_eq!(parse_expire("30 minutes"), Some(std::time::Duration::from_secs(1800)));
+        assert_eq!(parse_expire("500ms"), Some(std::time::Duration::from_millis(500)));
+        assert_eq!(parse_expire("5"), Some(std::time::Duration::from_secs(5)));
+        assert_eq!(parse_expire("2 fortnights"), None);
+        assert_eq!(parse_expire(&format!("{} years", u64::MAX / 1000)), None);
+    }
+
+    #[test]
+    fn encrypts_with_random_nonces() {
+        let token = encrypt("[\"127.0.0.1\"]", "csrf");
+        assert_ne!(token, encrypt("[\"127.0.0.1\"]", "csrf"));
+        assert_eq!(decrypt(&token, "csrf").as_deref(), Some("[\"127.0.0.1\"]"));
+
+        assert_eq!(decrypt(&token, "other"), None);
+        assert_eq!(decrypt(&format!("{}A", token), "csrf"), None);
+        assert_eq!(decrypt(&token, ""), None);
+        assert_eq!(encrypt("value", ""), "");
+
+        // Encrypted cookies use a different context
+        assert_eq!(decrypt(&seal("token", "value", "csrf"), "csrf"), None);
+    }
+}
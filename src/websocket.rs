@@ -0,0 +1,940 @@
+// Total-rs WebSocket server (RFC 6455)
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+
+use crate::http::{write_response, Request, Response};
+use crate::types::{Controller, FrameworkValue, Route, WebSocketConnection, WebSocketMessage, WebSocketRoute};
+use crate::utils::{decode_uri, encode_uri};
+use crate::{Framework, CONF};
+
+/// Appended to `Sec-WebSocket-Key` to compute `Sec-WebSocket-Accept`
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Time for the client to answer a close frame sent by the server
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of pinging clients and closing the unresponsive ones
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Frames queued for a client, clients which don't read them in time are closed
+const QUEUE_SIZE: usize = 256;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Marks the first frame of a compressed message
+const RSV1: u8 = 0x40;
+
+/// Removed from the end of compressed messages (RFC 7692, section 7.2.1)
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL: u16 = 1002;
+/// Received when the close frame doesn't contain a code, never sent
+const CLOSE_NOSTATUS: u16 = 1005;
+/// The connection has been lost without a close frame, never sent
+const CLOSE_ABNORMAL: u16 = 1006;
+const CLOSE_INVALID: u16 = 1007;
+const CLOSE_POLICY: u16 = 1008;
+const CLOSE_TOOBIG: u16 = 1009;
+const CLOSE_ERROR: u16 = 1011;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Frame queued for the writer of a connection
+pub(crate) enum Outgoing {
+    Message(u8, Vec<u8>),
+    Ping,
+    Pong(Vec<u8>),
+    Close(u16, String),
+}
+
+/// Unmasked frame received from a client
+struct Frame {
+    fin: bool,
+    rsv: u8,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Computes `Sec-WebSocket-Accept` for the client key
+pub(crate) fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Encodes an unmasked frame (servers never mask)
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        length if length < 126 => frame.push(length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads a frame, data frames larger than `limit` fail with `CLOSE_TOOBIG`
+async fn read_frame<R>(reader: &mut R, limit: usize) -> Result<Frame, u16>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await.map_err(|_| CLOSE_ABNORMAL)?;
+
+    let fin = head[0] & 0x80 != 0;
+    let rsv = head[0] & 0x70;
+    let opcode = head[0] & 0x0F;
+
+    // Clients must mask all frames
+    if head[1] & 0x80 == 0 {
+        return Err(CLOSE_PROTOCOL);
+    }
+
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut bytes = [0u8; 2];
+            reader.read_exact(&mut bytes).await.map_err(|_| CLOSE_ABNORMAL)?;
+            u16::from_be_bytes(bytes) as u64
+        }
+        127 => {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes).await.map_err(|_| CLOSE_ABNORMAL)?;
+            u64::from_be_bytes(bytes)
+        }
+        length => length as u64,
+    };
+
+    if opcode >= OPCODE_CLOSE {
+        if !fin || length > 125 {
+            return Err(CLOSE_PROTOCOL);
+        }
+    } else if length > limit as u64 {
+        return Err(CLOSE_TOOBIG);
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await.map_err(|_| CLOSE_ABNORMAL)?;
+
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).await.map_err(|_| CLOSE_ABNORMAL)?;
+
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+
+    Ok(Frame { fin, rsv, opcode, payload })
+}
+
+/// Parameters of the permessage-deflate extension agreed in the handshake (RFC 7692)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Deflate {
+    /// The server resets its compressor after every message
+    server_no_context_takeover: bool,
+    /// The client resets its compressor after every message
+    client_no_context_takeover: bool,
+}
+
+/// Picks the first acceptable permessage-deflate offer of `Sec-WebSocket-Extensions`,
+/// returns the parameters and the response header
+pub(crate) fn negotiate(header: &str) -> Option<(Deflate, String)> {
+    'offers: for offer in header.split(',') {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") {
+            continue;
+        }
+
+        let mut deflate = Deflate::default();
+        let mut response = vec!["permessage-deflate".to_string()];
+        let mut names = Vec::new();
+
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+
+            if names.contains(&name) {
+                continue 'offers;
+            }
+            names.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => {
+                    deflate.server_no_context_takeover = true;
+                    response.push(name.to_string());
+                }
+                ("client_no_context_takeover", None) => {
+                    deflate.client_no_context_takeover = true;
+                    response.push(name.to_string());
+                }
+                // The compressor always uses the maximum window
+                ("server_max_window_bits", Some("15")) => response.push("server_max_window_bits=15".to_string()),
+                // Any window of the client can be decompressed
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) if bits.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits)) => {}
+                _ => continue 'offers,
+            }
+        }
+
+        return Some((deflate, response.join("; ")));
+    }
+
+    None
+}
+
+/// Compresses messages sent to the client
+struct Deflater {
+    compress: Compress,
+    reset: bool,
+}
+
+impl Deflater {
+    fn new(deflate: Deflate) -> Self {
+        Self { compress: Compress::new(Compression::default(), false), reset: deflate.server_no_context_takeover }
+    }
+
+    fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+        let start = self.compress.total_in();
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync).expect("compression of in-memory data can't fail");
+
+            // A full buffer may hide pending output
+            if (self.compress.total_in() - start) as usize == data.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TAIL) {
+            output.truncate(output.len() - DEFLATE_TAIL.len());
+        }
+
+        if self.reset {
+            self.compress.reset();
+        }
+
+        output
+    }
+}
+
+/// Decompresses messages received from the client
+struct Inflater {
+    decompress: Decompress,
+    reset: bool,
+}
+
+impl Inflater {
+    fn new(deflate: Deflate) -> Self {
+        Self { decompress: Decompress::new(false), reset: deflate.client_no_context_takeover }
+    }
+
+    /// Decompressed messages larger than `limit` fail with `CLOSE_TOOBIG`
+    fn inflate(&mut self, data: &[u8], limit: usize) -> Result<Vec<u8>, u16> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&DEFLATE_TAIL);
+
+        let start = self.decompress.total_in();
+        let mut output = Vec::with_capacity((data.len() * 2).min(limit) + 64);
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = output.len();
+            self.decompress.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync).map_err(|_| CLOSE_INVALID)?;
+
+            if output.len() > limit {
+                return Err(CLOSE_TOOBIG);
+            }
+
+            let done = (self.decompress.total_in() - start) as usize == input.len();
+            if (done && output.len() < output.capacity()) || ((self.decompress.total_in() - start) as usize == consumed && output.len() == produced) {
+                break;
+            }
+        }
+
+        if self.reset {
+            self.decompress.reset(false);
+        }
+
+        Ok(output)
+    }
+}
+
+/// Returns the code of a received close frame, codes which can't be sent
+/// and reasons which aren't UTF-8 are answered with `1002` (RFC 6455, section 7.4)
+fn close_code(payload: &[u8]) -> u16 {
+    if payload.is_empty() {
+        return CLOSE_NOSTATUS;
+    }
+
+    if payload.len() < 2 || std::str::from_utf8(&payload[2..]).is_err() {
+        return CLOSE_PROTOCOL;
+    }
+
+    match u16::from_be_bytes([payload[0], payload[1]]) {
+        code @ (1000..=1003 | 1007..=1014 | 3000..=4999) => code,
+        _ => CLOSE_PROTOCOL,
+    }
+}
+
+/// Writes queued frames until a close frame has been written, an overflow of the queue closes the connection
+async fn transmit<W>(writer: &mut W, mut receiver: mpsc::Receiver<Outgoing>, mut deflater: Option<Deflater>, overflow: &Notify)
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let outgoing = tokio::select! {
+            biased;
+            _ = overflow.notified() => Outgoing::Close(CLOSE_POLICY, "overflow".to_string()),
+            outgoing = receiver.recv() => match outgoing {
+                Some(outgoing) => outgoing,
+                None => break,
+            },
+        };
+
+        let (frame, close) = match outgoing {
+            Outgoing::Message(opcode, payload) => match deflater.as_mut() {
+                Some(deflater) => {
+                    let mut frame = encode_frame(opcode, &deflater.deflate(&payload));
+                    frame[0] |= RSV1;
+                    (frame, false)
+                }
+                None => (encode_frame(opcode, &payload), false),
+            },
+            Outgoing::Ping => (encode_frame(OPCODE_PING, &[]), false),
+            Outgoing::Pong(payload) => (encode_frame(OPCODE_PONG, &payload), false),
+            Outgoing::Close(code, reason) => {
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(reason.as_bytes());
+                (encode_frame(OPCODE_CLOSE, &payload), true)
+            }
+        };
+
+        if writer.write_all(&frame).await.is_err() || writer.flush().await.is_err() || close {
+            break;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+impl From<&str> for WebSocketMessage {
+    fn from(value: &str) -> Self {
+        WebSocketMessage::Text(value.to_string())
+    }
+}
+
+impl From<String> for WebSocketMessage {
+    fn from(value: String) -> Self {
+        WebSocketMessage::Text(value)
+    }
+}
+
+impl From<Vec<u8>> for WebSocketMessage {
+    fn from(value: Vec<u8>) -> Self {
+        WebSocketMessage::Binary(value)
+    }
+}
+
+impl From<FrameworkValue> for WebSocketMessage {
+    fn from(value: FrameworkValue) -> Self {
+        WebSocketMessage::Json(value)
+    }
+}
+
+impl From<serde_json::Value> for WebSocketMessage {
+    fn from(value: serde_json::Value) -> Self {
+        WebSocketMessage::Json(value.into())
+    }
+}
+
+impl WebSocketMessage {
+    /// Decodes a complete message, `@json` routes parse text messages and
+    /// `CONF._wsencodedecode` URI-decodes them (Total.js clients with `encodedecode`)
+    fn decode(route: &Route, opcode: u8, payload: Vec<u8>, encodedecode: bool) -> Result<Self, u16> {
+        if opcode == OPCODE_BINARY {
+            return Ok(WebSocketMessage::Binary(payload));
+        }
+
+        let mut text = String::from_utf8(payload).map_err(|_| CLOSE_INVALID)?;
+        if encodedecode {
+            text = decode_uri(&text);
+        }
+
+        if route.has_flag("@json") {
+            let value: serde_json::Value = serde_json::from_str(&text).map_err(|_| CLOSE_INVALID)?;
+            Ok(WebSocketMessage::Json(value.into()))
+        } else {
+            Ok(WebSocketMessage::Text(text))
+        }
+    }
+
+    fn encode(self, encodedecode: bool) -> Outgoing {
+        let text = match self {
+            WebSocketMessage::Binary(data) => return Outgoing::Message(OPCODE_BINARY, data),
+            WebSocketMessage::Text(text) => text,
+            WebSocketMessage::Json(value) => serde_json::Value::from(value).to_string(),
+        };
+
+        let text = if encodedecode { encode_uri(&text) } else { text };
+        Outgoing::Message(OPCODE_TEXT, text.into_bytes())
+    }
+}
+
+impl WebSocketConnection {
+    fn queue(&self, outgoing: Outgoing) -> bool {
+        match self.sender.try_send(outgoing) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                // The client doesn't read fast enough
+                self.overflow.notify_one();
+                self.closing.notify_one();
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Sends a message, returns `false` if the connection has been closed
+    /// or `QUEUE_SIZE` frames are waiting (the connection is closed with `1008`)
+    pub fn send(&self, message: impl Into<WebSocketMessage>) -> bool {
+        self.queue(message.into().encode(self.encodedecode))
+    }
+
+    /// Starts the closing handshake, the client has `CLOSE_TIMEOUT` to answer
+    pub fn close(&self, code: u16, reason: &str) {
+        // The close frame payload is limited to 125 bytes
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        self.queue(Outgoing::Close(code, reason[..end].to_string()));
+        self.closing.notify_one();
+    }
+
+    /// Returns the round trip of the last answered ping
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.lock().unwrap()
+    }
+
+    /// The client is too slow or hasn't answered the last ping in `max`
+    fn stale(&self, max: Duration) -> bool {
+        let waiting = self.ping.lock().unwrap().map(|sent| sent.elapsed()).unwrap_or_default();
+        waiting > max || self.latency().is_some_and(|latency| latency > max)
+    }
+
+    /// Sends a ping unless the previous one is still unanswered
+    fn ping(&self) -> bool {
+        let mut ping = self.ping.lock().unwrap();
+        if ping.is_some() || !self.queue(Outgoing::Ping) {
+            return false;
+        }
+        *ping = Some(Instant::now());
+        true
+    }
+
+    /// Measures the latency, unsolicited pongs are ignored
+    fn pong(&self) {
+        if let Some(sent) = self.ping.lock().unwrap().take() {
+            *self.latency.lock().unwrap() = Some(sent.elapsed());
+        }
+    }
+
+    /// Returns a value of the connection state
+    pub fn get(&self, key: &str) -> Option<FrameworkValue> {
+        self.state.lock().unwrap().get(key).cloned()
+    }
+
+    /// Stores a value in the connection state
+    pub fn set(&self, key: &str, value: impl Into<FrameworkValue>) {
+        self.state.lock().unwrap().insert(key.to_string(), value.into());
+    }
+}
+
+impl WebSocketRoute {
+    pub fn on_open<H>(&self, handler: H)
+    where
+        H: Fn(Arc<WebSocketConnection>) + Send + Sync + 'static,
+    {
+        *self.on_open.write().unwrap() = Some(Arc::new(handler));
+    }
+
+    pub fn on_message<H>(&self, handler: H)
+    where
+        H: Fn(Arc<WebSocketConnection>, WebSocketMessage) + Send + Sync + 'static,
+    {
+        *self.on_message.write().unwrap() = Some(Arc::new(handler));
+    }
+
+    pub fn on_close<H>(&self, handler: H)
+    where
+        H: Fn(Arc<WebSocketConnection>, u16) + Send + Sync + 'static,
+    {
+        *self.on_close.write().unwrap() = Some(Arc::new(handler));
+    }
+
+    /// Returns connected clients
+    pub fn clients(&self) -> Vec<Arc<WebSocketConnection>> {
+        self.clients.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn find(&self, id: &str) -> Option<Arc<WebSocketConnection>> {
+        self.clients.read().unwrap().get(id).cloned()
+    }
+
+    /// Sends a message to all connected clients
+    pub fn send(&self, message: impl Into<WebSocketMessage>) -> usize {
+        self.send_filter(message, |_| true)
+    }
+
+    /// Sends a message to clients accepted by the filter, returns the number of recipients
+    pub fn send_filter<F>(&self, message: impl Into<WebSocketMessage>, filter: F) -> usize
+    where
+        F: Fn(&WebSocketConnection) -> bool,
+    {
+        let message = message.into();
+        self.clients()
+            .iter()
+            .filter(|client| filter(client))
+            .filter(|client| client.send(message.clone()))
+            .count()
+    }
+
+    /// Reads messages until the connection is closed, returns the close code
+    async fn receive<R>(&self, reader: &mut R, client: &Arc<WebSocketConnection>, limit: usize, mut inflater: Option<Inflater>) -> u16
+    where
+        R: AsyncRead + Unpin,
+    {
+        // Opcode and payload of a fragmented message
+        let mut pending: Option<(u8, Vec<u8>)> = None;
+        let mut compressed = false;
+
+        loop {
+            let received = pending.as_ref().map(|(_, payload)| payload.len()).unwrap_or(0);
+            let frame = match read_frame(reader, limit.saturating_sub(received)).await {
+                Ok(frame) => frame,
+                Err(code) => return code,
+            };
+
+            // RSV1 is allowed only on the first frame of a message with negotiated compression
+            let first = matches!(frame.opcode, OPCODE_TEXT | OPCODE_BINARY);
+            if frame.rsv == RSV1 && first && inflater.is_some() {
+                compressed = true;
+            } else if frame.rsv != 0 {
+                return CLOSE_PROTOCOL;
+            } else if first {
+                compressed = false;
+            }
+
+            let (opcode, payload) = match frame.opcode {
+                OPCODE_PING => {
+                    client.queue(Outgoing::Pong(frame.payload));
+                    continue;
+                }
+                OPCODE_PONG => {
+                    client.pong();
+                    continue;
+                }
+                OPCODE_CLOSE => return close_code(&frame.payload),
+                OPCODE_TEXT | OPCODE_BINARY if pending.is_none() => (frame.opcode, frame.payload),
+                OPCODE_CONTINUATION => match pending.take() {
+                    Some((opcode, mut payload)) => {
+                        payload.extend_from_slice(&frame.payload);
+                        (opcode, payload)
+                    }
+                    None => return CLOSE_PROTOCOL,
+                },
+                _ => return CLOSE_PROTOCOL,
+            };
+
+            if !frame.fin {
+                pending = Some((opcode, payload));
+                continue;
+            }
+
+            let payload = match inflater.as_mut() {
+                Some(inflater) if compressed => match inflater.inflate(&payload, limit) {
+                    Ok(payload) => payload,
+                    Err(code) => return code,
+                },
+                _ => payload,
+            };
+
+            let message = match WebSocketMessage::decode(&self.route, opcode, payload, client.encodedecode) {
+                Ok(message) => message,
+                Err(code) => return code,
+            };
+
+            let handler = self.on_message.read().unwrap().clone();
+            if let Some(handler) = handler {
+                if std::panic::catch_unwind(AssertUnwindSafe(|| handler(client.clone(), message))).is_err() {
+                    return CLOSE_ERROR;
+                }
+            }
+        }
+    }
+}
+
+impl Framework {
+    /// Registers a WebSocket route, e.g. `F.websocket("SOCKET /chat/{room}/ @json <64kB")`,
+    /// `+SOCKET` requires a user resolved by `on_auth()`
+    pub fn websocket(&self, declaration: &str) -> Arc<WebSocketRoute> {
+        let declaration = declaration.trim();
+        let (method, rest) = declaration.split_once(char::is_whitespace).unwrap_or((declaration, ""));
+        let name = method.trim_start_matches(['+', '-']);
+
+        if !name.eq_ignore_ascii_case("SOCKET") {
+            panic!("ROUTE(\"{}\"): the declaration must start with \"SOCKET\"", declaration);
+        }
+
+        let prefix = &method[..method.len() - name.len()];
+        let mut route = Route::parse(&format!("{}GET {}", prefix, rest)).unwrap_or_else(|err| panic!("ROUTE(\"{}\"): {}", declaration, err));
+        route.method = "SOCKET".to_string();
+        route.declaration = declaration.to_string();
+
+        let websocket = Arc::new(WebSocketRoute {
+            route,
+            clients: RwLock::new(HashMap::new()),
+            on_open: RwLock::new(None),
+            on_message: RwLock::new(None),
+            on_close: RwLock::new(None),
+        });
+
+        let mut routes = self.routes.write().unwrap();
+        if websocket.route.is_static() {
+            routes.websocketscache.insert(websocket.route.path.clone(), websocket.clone());
+        }
+        routes.websockets.push(websocket.clone());
+        routes.websockets.sort_by_key(|websocket| std::cmp::Reverse(websocket.route.priority));
+        websocket
+    }
+
+    /// Validates the upgrade request and creates the connection
+    async fn handshake(&'static self, request: Request, route: &WebSocketRoute, params: HashMap<String, FrameworkValue>) -> Result<(Arc<WebSocketConnection>, mpsc::Receiver<Outgoing>, Response), Response> {
+        if self.is_blocked(&request.ip) || self.is_banned(&request.ip) {
+            self.stats.write().unwrap().request.blocked += 1;
+            return Err(Response::throw(403));
+        }
+
+        // Only the handshake occupies a request slot
+        let _guard = self.ddos(&request.ip)?;
+
+        let key = request
+            .header("sec-websocket-key")
+            .filter(|key| STANDARD.decode(key.trim()).is_ok_and(|key| key.len() == 16))
+            .map(String::from);
+
+        let key = match key {
+            Some(key) if request.method == "GET" => key,
+            _ => return Err(Response::throw(400)),
+        };
+
+        if request.header("sec-websocket-version").map(str::trim) != Some("13") {
+            let mut response = Response::throw(426);
+            response.set_header("Sec-WebSocket-Version", "13");
+            return Err(response);
+        }
+
+        let (compress, encodedecode) = {
+            let conf = CONF.read().unwrap();
+            (conf._wscompress, conf._wsencodedecode)
+        };
+
+        let deflate = match request.header("sec-websocket-extensions") {
+            Some(extensions) if compress => negotiate(extensions),
+            _ => None,
+        };
+
+        let mut response = Response::new(101);
+        response.set_header("Upgrade", "websocket");
+        response.set_header("Connection", "Upgrade");
+        response.set_header("Sec-WebSocket-Accept", &accept_key(&key));
+
+        if let Some((_, extension)) = &deflate {
+            response.set_header("Sec-WebSocket-Extensions", extension);
+        }
+
+        let (sender, _) = oneshot::channel();
+        let mut controller = Controller::new(self, request, None, params, sender).map_err(Response::throw)?;
+        controller.load_session().await;
+        self.authorize(&mut controller, &route.route.auth).map_err(Response::throw)?;
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let client = Arc::new(WebSocketConnection {
+            id: format!("ws{}", NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            ip: std::mem::take(&mut controller.ip),
+            url: std::mem::take(&mut controller.url),
+            path: std::mem::take(&mut controller.path),
+            headers: std::mem::take(&mut controller.headers),
+            query: std::mem::take(&mut controller.query),
+            params: std::mem::take(&mut controller.params),
+            user: controller.user.take(),
+            session: controller.session.take(),
+            language: std::mem::take(&mut controller.language),
+            state: Mutex::new(HashMap::new()),
+            deflate: deflate.map(|(deflate, _)| deflate),
+            encodedecode,
+            ping: Mutex::new(None),
+            latency: Mutex::new(None),
+            sender,
+            closing: Notify::new(),
+            overflow: Notify::new(),
+        });
+
+        Ok((client, receiver, response))
+    }
+
+    /// Upgrades the connection and serves the WebSocket client until it's closed
+    pub(crate) async fn upgrade<R, W>(&'static self, mut reader: R, mut writer: W, request: Request, route: Arc<WebSocketRoute>, params: HashMap<String, FrameworkValue>)
+    where
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin + Send,
+    {
+        self.stats.write().unwrap().request.websocket += 1;
+
+        let ip = request.ip.clone();
+        let head = request.head();
+
+        let (client, receiver, response) = match self.handshake(request, &route, params).await {
+            Ok(accepted) => accepted,
+            Err(response) => {
+                self.count_violation(&ip, response.status).await;
+                let response = if response.throw { self.render_error(response, head).await } else { response };
+                let _ = write_response(&mut writer, response, false, false).await;
+                let _ = writer.shutdown().await;
+                return;
+            }
+        };
+
+        if write_response(&mut writer, response, false, true).await.is_err() {
+            return;
+        }
+
+        self.stats.write().unwrap().response.websocket += 1;
+        route.clients.write().unwrap().insert(client.id.clone(), client.clone());
+
+        let handler = route.on_open.read().unwrap().clone();
+        if let Some(handler) = handler {
+            if std::panic::catch_unwind(AssertUnwindSafe(|| handler(client.clone()))).is_err() {
+                client.close(CLOSE_ERROR, "");
+            }
+        }
+
+        let limit = route.route.size.unwrap_or_else(|| CONF.read().unwrap()._wsmaxsize * 1024);
+
+        // Signals the start of the closing to the writer
+        let closing = Notify::new();
+
+        let reading = async {
+            let code = tokio::select! {
+                code = route.receive(&mut reader, &client, limit, client.deflate.map(Inflater::new)) => code,
+                _ = async {
+                    client.closing.notified().await;
+                    closing.notify_one();
+                    tokio::time::sleep(CLOSE_TIMEOUT).await;
+                } => CLOSE_ABNORMAL,
+            };
+
+            // Answers the close frame of the client (ignored when the server has started the closing)
+            let reply = if matches!(code, CLOSE_NOSTATUS | CLOSE_ABNORMAL) { CLOSE_NORMAL } else { code };
+            client.queue(Outgoing::Close(reply, String::new()));
+            closing.notify_one();
+            code
+        };
+
+        // A client which doesn't read blocks the writer, it's dropped `CLOSE_TIMEOUT` after the closing has started
+        let writing = async {
+            tokio::select! {
+                _ = transmit(&mut writer, receiver, client.deflate.map(Deflater::new), &client.overflow) => {}
+                _ = async {
+                    closing.notified().await;
+                    tokio::time::sleep(CLOSE_TIMEOUT).await;
+                } => {}
+            }
+        };
+
+        let (code, _) = tokio::join!(reading, writing);
+
+        route.clients.write().unwrap().remove(&client.id);
+
+        let handler = route.on_close.read().unwrap().clone();
+        if let Some(handler) = handler {
+            let _ = std::panic::catch_unwind(AssertUnwindSafe(|| handler(client, code)));
+        }
+    }
+
+    /// Pings all WebSocket clients and closes those whose latency exceeds `CONF._wsmaxlatency`
+    /// or which haven't answered the previous ping in that time
+    pub fn websocket_ping(&self) {
+        let max = match CONF.read().unwrap()._wsmaxlatency {
+            0 => None,
+            max => Some(Duration::from_millis(max as u64)),
+        };
+
+        let websockets = self.routes.read().unwrap().websockets.clone();
+        let (mut pinged, mut cleaned) = (0, 0);
+
+        for client in websockets.iter().flat_map(|websocket| websocket.clients()) {
+            if max.is_some_and(|max| client.stale(max)) {
+                client.close(CLOSE_AWAY, "latency");
+                cleaned += 1;
+            } else if client.ping() {
+                pinged += 1;
+            }
+        }
+
+        let mut stats = self.stats.write().unwrap();
+        stats.other.websocketping += pinged;
+        stats.other.websocketcleaner += cleaned;
+    }
+
+    /// Runs `websocket_ping()` periodically until the server is closed
+    pub(crate) async fn websocket_pinger(&'static self, mut signal: watch::Receiver<bool>) {
+        // Skips the immediate first tick, there are no clients yet
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.websocket_ping(),
+                _ = signal.changed() => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_accept_key() {
+        // Example of RFC 6455, section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn validates_close_codes() {
+        assert_eq!(close_code(b""), CLOSE_NOSTATUS);
+        assert_eq!(close_code(&[0x03]), CLOSE_PROTOCOL);
+        assert_eq!(close_code(&[&1000u16.to_be_bytes()[..], b"bye"].concat()), 1000);
+        assert_eq!(close_code(&4000u16.to_be_bytes()), 4000);
+
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 2000, 5000] {
+            assert_eq!(close_code(&code.to_be_bytes()), CLOSE_PROTOCOL);
+        }
+
+        assert_eq!(close_code(&[&1000u16.to_be_bytes()[..], &[0xFF, 0xFE]].concat()), CLOSE_PROTOCOL);
+    }
+
+    #[tokio::test]
+    async fn reads_masked_frames() {
+        let mask = [1u8, 2, 3, 4];
+        let mut raw = vec![0x81, 0x80 | 5];
+        raw.extend_from_slice(&mask);
+        raw.extend(b"Hello".iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+
+        let frame = read_frame(&mut raw.as_slice(), 1024).await.ok().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        assert_eq!(frame.payload, b"Hello");
+
+        assert_eq!(read_frame(&mut raw.as_slice(), 4).await.err(), Some(CLOSE_TOOBIG));
+        assert_eq!(read_frame(&mut [0x81u8, 0x05].as_slice(), 1024).await.err(), Some(CLOSE_PROTOCOL));
+        assert_eq!(read_frame(&mut [0x09u8, 0x80].as_slice(), 1024).await.err(), Some(CLOSE_PROTOCOL));
+    }
+
+    #[test]
+    fn negotiates_permessage_deflate() {
+        let (deflate, header) = negotiate("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(deflate, Deflate::default());
+        assert_eq!(header, "permessage-deflate");
+
+        let (deflate, header) = negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover; client_no_context_takeover").unwrap();
+        assert!(deflate.server_no_context_takeover && deflate.client_no_context_takeover);
+        assert_eq!(header, "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+
+        assert_eq!(negotiate("permessage-deflate; server_max_window_bits=15").unwrap().1, "permessage-deflate; server_max_window_bits=15");
+        assert!(negotiate("x-webkit-deflate-frame").is_none());
+        assert!(negotiate("permessage-deflate; unknown").is_none());
+        assert!(negotiate("permessage-deflate; client_no_context_takeover; client_no_context_takeover").is_none());
+    }
+
+    #[test]
+    fn compresses_messages_with_context_takeover() {
+        for takeover in [true, false] {
+            let deflate = Deflate { server_no_context_takeover: !takeover, client_no_context_takeover: !takeover };
+            let mut deflater = Deflater::new(deflate);
+            let mut inflater = Inflater::new(deflate);
+
+            let message = "Hello Total.js ".repeat(20);
+            let first = deflater.deflate(message.as_bytes());
+            let second = deflater.deflate(message.as_bytes());
+            assert!(!first.ends_with(&DEFLATE_TAIL));
+
+            // The second message refers to the first one when the context is kept
+            assert_eq!(second.len() < first.len(), takeover);
+
+            assert_eq!(inflater.inflate(&first, 1024).unwrap(), message.as_bytes());
+            assert_eq!(inflater.inflate(&second, 1024).unwrap(), message.as_bytes());
+            assert_eq!(Inflater::new(deflate).inflate(&first, 100), Err(CLOSE_TOOBIG));
+        }
+    }
+
+    #[test]
+    fn encodes_and_decodes_uri_messages() {
+        let route = Route::parse("GET /chat/ @json").unwrap();
+        let encoded = b"%7B%22text%22%3A%22Hello%20%E2%9C%93%22%7D".to_vec();
+
+        let message = WebSocketMessage::decode(&route, OPCODE_TEXT, encoded.clone(), true).unwrap();
+        assert_eq!(message, WebSocketMessage::Json(serde_json::json!({ "text": "Hello ✓" }).into()));
+        assert!(matches!(message.encode(true), Outgoing::Message(OPCODE_TEXT, payload) if payload == encoded));
+
+        assert_eq!(WebSocketMessage::decode(&route, OPCODE_TEXT, encoded, false), Err(CLOSE_INVALID));
+    }
+
+    #[test]
+    fn encodes_frame_lengths() {
+        assert_eq!(encode_frame(OPCODE_TEXT, b"Hi"), [0x81, 2, b'H', b'i']);
+        assert_eq!(&encode_frame(OPCODE_BINARY, &[0; 300])[..4], [0x82, 126, 1, 44]);
+        assert_eq!(&encode_frame(OPCODE_BINARY, &[0; 70000])[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 17, 112]);
+    }
+}
@@ -0,0 +1,421 @@
+// Total-rs XML parser and serializer
+// The MIT License
+// Copyright 2012-2023 (c) Louis Bertson <louisbertsonpetersirka@gmail.com>
+
+use std::collections::HashMap;
+
+use crate::types::FrameworkValue;
+
+/// Maximum nesting of elements
+const MAX_DEPTH: usize = 128;
+
+/// Key of the text of elements with attributes or children
+pub const XML_TEXT: &str = "#text";
+
+/// Decodes the predefined and numeric entities, unknown entities are kept
+fn decode_entities(value: &str) -> String {
+    if !value.contains('&') {
+        return value.to_string();
+    }
+
+    let mut output = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(index) = rest.find('&') {
+        output.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        // Entities are short, searching further would make the decoding quadratic
+        let decoded = rest.as_bytes()[..rest.len().min(13)].iter().position(|c| *c == b';').and_then(|end| {
+            let entity = &rest[1..end];
+            let decoded = match entity {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => match entity.strip_prefix('#') {
+                    Some(code) => match code.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => code.parse().ok(),
+                    }
+                    .and_then(char::from_u32),
+                    None => None,
+                },
+            };
+            decoded.map(|decoded| (decoded, end))
+        });
+
+        match decoded {
+            Some((decoded, end)) => {
+                output.push(decoded);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Escapes text and attribute values
+fn escape(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '&' => output.push_str("&amp;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&apos;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+/// Adds a value to an element, repeated names create arrays
+fn append(map: &mut HashMap<String, FrameworkValue>, name: String, value: FrameworkValue) {
+    match map.get_mut(&name) {
+        Some(FrameworkValue::Array(items)) => items.push(value),
+        Some(existing) => {
+            let previous = std::mem::take(existing);
+            *existing = FrameworkValue::Array(vec![previous, value]);
+        }
+        None => {
+            map.insert(name, value);
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{} at position {}", message, self.position)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips everything up to and including `end`
+    fn skip_to(&mut self, end: &str) -> Result<(), String> {
+        match self.rest().find(end) {
+            Some(index) => {
+                self.position += index + end.len();
+                Ok(())
+            }
+            None => Err(self.error(&format!("missing \"{}\"", end))),
+        }
+    }
+
+    fn expect(&mut self, value: &str) -> Result<(), String> {
+        if self.rest().starts_with(value) {
+            self.position += value.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("\"{}\" expected", value)))
+        }
+    }
+
+    /// Skips whitespace, declarations, processing instructions, comments and the doctype
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("<?") {
+                self.skip_to("?>")?;
+            } else if rest.starts_with("<!--") {
+                self.skip_to("-->")?;
+            } else if rest.starts_with("<!DOCTYPE") || rest.starts_with("<!doctype") {
+                self.skip_to(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let rest = self.rest();
+        let length = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '=' | '<'))
+            .unwrap_or(rest.len());
+
+        if length == 0 {
+            return Err(self.error("name expected"));
+        }
+
+        self.position += length;
+        Ok(rest[..length].to_string())
+    }
+
+    fn element(&mut self, depth: usize) -> Result<(String, FrameworkValue), String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("too deep nesting"));
+        }
+
+        self.expect("<")?;
+        let name = self.name()?;
+        let mut map = HashMap::new();
+
+        loop {
+            self.skip_whitespace();
+
+            if self.rest().starts_with("/>") {
+                self.position += 2;
+                let value = if map.is_empty() { FrameworkValue::String(String::new()) } else { FrameworkValue::Object(map) };
+                return Ok((name, value));
+            }
+
+            if self.rest().starts_with('>') {
+                self.position += 1;
+                break;
+            }
+
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+
+            let quote = match self.rest().chars().next() {
+                Some(quote @ ('"' | '\'')) => quote,
+                _ => return Err(self.error("quoted attribute value expected")),
+            };
+
+            self.position += 1;
+            let end = self.rest().find(quote).ok_or_else(|| self.error("unclosed attribute value"))?;
+            let value = decode_entities(&self.rest()[..end]);
+            self.position += end + 1;
+            map.insert(format!("@{}", attribute), FrameworkValue::String(value));
+        }
+
+        let mut text = String::new();
+        let mut children = false;
+
+        loop {
+            let rest = self.rest();
+
+            if rest.is_empty() {
+                return Err(self.error(&format!("unclosed element <{}>", name)));
+            }
+
+            if rest.starts_with("</") {
+                self.position += 2;
+                let closing = self.name()?;
+                if closing != name {
+                    return Err(self.error(&format!("</{}> expected", name)));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                break;
+            }
+
+            if rest.starts_with("<![CDATA[") {
+                self.position += 9;
+                let end = self.rest().find("]]>").ok_or_else(|| self.error("unclosed CDATA"))?;
+                text.push_str(&self.rest()[..end]);
+                self.position += end + 3;
+            } else if rest.starts_with("<!--") {
+                self.skip_to("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_to("?>")?;
+            } else if rest.starts_with('<') {
+                let (child, value) = self.element(depth + 1)?;
+                append(&mut map, child, value);
+                children = true;
+            } else {
+                let end = rest.find('<').unwrap_or(rest.len());
+                text.push_str(&decode_entities(&rest[..end]));
+                self.position += end;
+            }
+        }
+
+        let text = text.trim();
+
+        if map.is_empty() && !children {
+            return Ok((name, FrameworkValue::String(text.to_string())));
+        }
+
+        if !text.is_empty() {
+            map.insert(XML_TEXT.to_string(), FrameworkValue::String(text.to_string()));
+        }
+
+        Ok((name, FrameworkValue::Object(map)))
+    }
+}
+
+/// Parses XML into `{ root: ... }`, attributes are stored as `@name`, text of elements
+/// with attributes or children as `#text` and repeated elements as arrays
+pub fn parse_xml(text: &str) -> Result<FrameworkValue, String> {
+    let mut parser = Parser { text, position: 0 };
+
+    // Byte order mark
+    if parser.rest().starts_with('\u{feff}') {
+        parser.position += '\u{feff}'.len_utf8();
+    }
+
+    parser.skip_misc()?;
+
+    if !parser.rest().starts_with('<') {
+        return Err(parser.error("root element expected"));
+    }
+
+    let (name, value) = parser.element(0)?;
+    parser.skip_misc()?;
+
+    if !parser.rest().is_empty() {
+        return Err(parser.error("unexpected content after the root element"));
+    }
+
+    let mut root = HashMap::new();
+    root.insert(name, value);
+    Ok(FrameworkValue::Object(root))
+}
+
+/// Replaces characters which can't be in element and attribute names with `_`
+fn escape_name(name: &str) -> String {
+    let mut output: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':') { c } else { '_' })
+        .collect();
+
+    if !output.starts_with(|c: char| c.is_alphabetic() || c == '_' || c == ':') {
+        output.insert(0, '_');
+    }
+    output
+}
+
+fn write_element(output: &mut String, name: &str, value: &FrameworkValue) {
+    let name = &escape_name(name);
+    match value {
+        FrameworkValue::Array(items) => {
+            for item in items {
+                write_element(output, name, item);
+            }
+        }
+        FrameworkValue::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+
+            output.push('<');
+            output.push_str(name);
+
+            for key in keys.iter().filter(|key| key.starts_with('@')) {
+                output.push_str(&format!(" {}=\"{}\"", escape_name(&key[1..]), escape(&map[*key].to_string())));
+            }
+
+            output.push('>');
+
+            if let Some(text) = map.get(XML_TEXT) {
+                output.push_str(&escape(&text.to_string()));
+            }
+
+            for key in keys.iter().filter(|key| !key.starts_with('@') && key.as_str() != XML_TEXT) {
+                write_element(output, key, &map[*key]);
+            }
+
+            output.push_str(&format!("</{}>", name));
+        }
+        FrameworkValue::Null => output.push_str(&format!("<{} />", name)),
+        value => output.push_str(&format!("<{}>{}</{}>", name, escape(&value.to_string()), name)),
+    }
+}
+
+/// Serializes a value as XML, the inverse of `parse_xml()` (object keys are sorted)
+pub fn to_xml(root: &str, value: &FrameworkValue) -> String {
+    let mut output = String::new();
+    write_element(&mut output, root, value);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> FrameworkValue {
+        FrameworkValue::String(value.to_string())
+    }
+
+    #[test]
+    fn parses_elements_attributes_and_text() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<!-- order from a partner -->
+<order id="15" currency='EUR'>
+    <customer>Peter &amp; Louis</customer>
+    <item sku="A1">Keyboard</item>
+    <item sku="B2"><![CDATA[<Mouse & Pad>]]></item>
+    <note>&#x2713; &#65;&lt;&gt;</note>
+    <empty />
+    Express
+</order>"#;
+
+        let FrameworkValue::Object(root) = parse_xml(xml).unwrap() else { panic!("object expected") };
+        let FrameworkValue::Object(order) = &root["order"] else { panic!("object expected") };
+
+        assert_eq!(order["@id"], string("15"));
+        assert_eq!(order["@currency"], string("EUR"));
+        assert_eq!(order["customer"], string("Peter & Louis"));
+        assert_eq!(order["note"], string("✓ A<>"));
+        assert_eq!(order["empty"], string(""));
+        assert_eq!(order[XML_TEXT], string("Express"));
+
+        let FrameworkValue::Array(items) = &order["item"] else { panic!("array expected") };
+        let FrameworkValue::Object(second) = &items[1] else { panic!("object expected") };
+        assert_eq!(second["@sku"], string("B2"));
+        assert_eq!(second[XML_TEXT], string("<Mouse & Pad>"));
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(parse_xml("").is_err());
+        assert!(parse_xml("plain text").is_err());
+        assert!(parse_xml("<a><b></a>").is_err());
+        assert!(parse_xml("<a>unclosed").is_err());
+        assert!(parse_xml("<a></a><b></b>").is_err());
+        assert!(parse_xml("<a b=c></a>").is_err());
+        assert!(parse_xml(&"<a>".repeat(200)).is_err());
+    }
+
+    #[test]
+    fn serializes_parsed_documents() {
+        let xml = r#"<user id="1"><name>Peter &amp; Co</name><role>admin</role><role>editor</role></user>"#;
+        let FrameworkValue::Object(root) = parse_xml(xml).unwrap() else { panic!("object expected") };
+
+        assert_eq!(to_xml("user", &root["user"]), xml);
+        assert_eq!(parse_xml(&to_xml("user", &root["user"])).unwrap(), FrameworkValue::Object(root));
+        assert_eq!(to_xml("count", &FrameworkValue::Number(5)), "<count>5</count>");
+        assert_eq!(to_xml("nothing", &FrameworkValue::Null), "<nothing />");
+    }
+
+    #[test]
+    fn escapes_invalid_names() {
+        let mut map = HashMap::new();
+        map.insert("a><script".to_string(), string("x"));
+        map.insert("1st item".to_string(), string("y"));
+        map.insert("@on load".to_string(), string("z"));
+
+        let xml = to_xml("user data", &FrameworkValue::Object(map));
+        assert_eq!(xml, r#"<user_data on_load="z"><_1st_item>y</_1st_item><a__script>x</a__script></user_data>"#);
+        assert!(parse_xml(&xml).is_ok());
+    }
+
+    #[test]
+    fn decodes_long_text_with_ampersands() {
+        let text = format!("{}&amp;", "& no entity ".repeat(10_000));
+        assert!(decode_entities(&text).ends_with("no entity &"));
+        assert_eq!(decode_entities("&verylongentityname;"), "&verylongentityname;");
+    }
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use common::{request, start};
+
+const JSON: &[(&str, &str)] = &[("Content-Type", "application/json")];
+
+#[tokio::test]
+async fn operations_dispatch_to_actions() {
+    let (framework, server) = start().await;
+
+    framework.action("Users/read", |options| {
+        let id = options.params.get("id").and_then(|id| id.as_i64()).unwrap_or_default();
+        let sort = options.query.get("sort").cloned().unwrap_or_default();
+        options.callback(serde_json::json!({ "id": id, "sort": sort }))
+    });
+    framework.action("Users/save", |options| {
+        let model = options.model.clone();
+        match model.as_str() {
+            Some(_) => options.invalid("name is required"),
+            None => options.success(model),
+        }
+    });
+    framework.route_actions("API /api/ users_read/{id:number} --> Users/read");
+    framework.route_actions("API /api/ +users_save --> Users/save");
+
+    let body = br#"{"schema":"users_read/42?sort=name"}"#;
+    let (status, _, body) = request(server.port(), "POST", "/api/", JSON, body).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, br#"{"id":42,"sort":"name"}"#);
+
+    // Unknown operations and invalid envelopes
+    let (status, _, _) = request(server.port(), "POST", "/api/", JSON, br#"{"schema":"unknown"}"#).await;
+    assert_eq!(status, 404);
+    let (status, _, _) = request(server.port(), "POST", "/api/", JSON, br#"{"data":{}}"#).await;
+    assert_eq!(status, 400);
+
+    // `+` operations require a user
+    let (status, _, _) = request(server.port(), "POST", "/api/", JSON, br#"{"schema":"users_save","data":{"name":"Peter"}}"#).await;
+    assert_eq!(status, 401);
+
+    framework.on_auth(|_| Some("admin".into()));
+    let (status, _, body) = request(server.port(), "POST", "/api/", JSON, br#"{"schema":"users_save","data":{"name":"Peter"}}"#).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, br#"{"success":true,"value":{"name":"Peter"}}"#);
+
+    let (status, _, body) = request(server.port(), "POST", "/api/", JSON, br#"{"schema":"users_save","data":"Peter"}"#).await;
+    assert_eq!(status, 400);
+    assert_eq!(body, br#"[{"error":"name is required"}]"#);
+
+    assert_eq!(framework.stats.read().unwrap().request.operation, 6);
+}
+
+#[tokio::test]
+async fn routes_run_action_chains() {
+    let (framework, server) = start().await;
+
+    framework.action("First", |options| options.callback("first"));
+    framework.action("Second", |options| options.callback("second"));
+    framework.route_actions("GET /chain/ --> First (response) Second");
+    framework.route_actions("GET /missing/ --> First Unknown");
+
+    let (status, _, body) = request(server.port(), "GET", "/chain/", &[], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, br#""first""#);
+
+    let (status, _, _) = request(server.port(), "GET", "/missing/", &[], b"").await;
+    assert_eq!(status, 501);
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+use common::{request, start_with};
+use total5::*;
+
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("total5-bans-{}", std::process::id()))
+}
+
+#[tokio::test]
+async fn bans_are_listed_and_persisted() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+    framework.route("GET /", |ctrl| ctrl.plain("home"));
+
+    framework.ban("127.0.0.1", Duration::from_secs(60)).await;
+    framework.ban("10.0.0.5", Duration::from_secs(30)).await;
+
+    let (status, _, _) = request(server.port(), "GET", "/", &[], b"").await;
+    assert_eq!(status, 403);
+
+    let bans = framework.bans();
+    assert_eq!(bans.iter().map(|ban| ban.ip.as_str()).collect::<Vec<_>>(), ["10.0.0.5", "127.0.0.1"]);
+
+    // A new process restores the active bans
+    let (restored, other) = start_with(|| TPath::new(directory())).await;
+    assert!(restored.is_banned("10.0.0.5"));
+    assert_eq!(restored.bans().len(), 2);
+    other.close();
+
+    assert!(framework.unban("127.0.0.1").await);
+    assert!(!framework.unban("127.0.0.1").await);
+    let (status, _, _) = request(server.port(), "GET", "/", &[], b"").await;
+    assert_eq!(status, 200);
+
+    // Expired bans are ignored
+    framework.ban("10.0.0.6", Duration::from_millis(10)).await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!framework.is_banned("10.0.0.6"));
+    framework.bans_cleanup().await;
+    assert!(!framework.temporary.read().unwrap().bans.contains_key("10.0.0.6"));
+
+    // Permanent bans don't overflow and survive restarts too
+    framework.ban("10.0.0.7", Duration::MAX).await;
+    assert_eq!(framework.bans().last().unwrap().expires, chrono::DateTime::<chrono::Utc>::MAX_UTC);
+    let (restored, other) = start_with(|| TPath::new(directory())).await;
+    assert!(restored.is_banned("10.0.0.7"));
+    other.close();
+
+    framework.unban("10.0.0.7").await;
+    framework.unban("10.0.0.5").await;
+    let saved = std::fs::read_to_string(framework.path.databases(Some("bans.json"))).unwrap();
+    assert_eq!(saved, "[]");
+}
+
+#[tokio::test]
+async fn repeated_errors_ban_the_ip() {
+    let directory = || TPath::new(std::env::temp_dir().join(format!("total5-autoban-{}", std::process::id())));
+    let (framework, server) = start_with(directory).await;
+    framework.route("GET /private/", |ctrl| ctrl.throw401());
+
+    CONF.write().unwrap()._banlimit = 3;
+    CONF.write().unwrap()._banexpire = "5 minutes".to_string();
+
+    assert_eq!(request(server.port(), "GET", "/private/", &[], b"").await.0, 401);
+    assert_eq!(request(server.port(), "GET", "/missing/", &[], b"").await.0, 404);
+    assert_eq!(request(server.port(), "GET", "/private/", &[], b"").await.0, 401);
+
+    CONF.write().unwrap()._banlimit = 0;
+
+    assert_eq!(request(server.port(), "GET", "/private/", &[], b"").await.0, 403);
+
+    let bans = framework.bans();
+    assert_eq!(bans.len(), 1);
+    assert_eq!(bans[0].ip, "127.0.0.1");
+    assert!(bans[0].expires > chrono::Utc::now() + chrono::Duration::minutes(4));
+    assert_eq!(framework.stats.read().unwrap().request.blocked, 1);
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::{request, start};
+use total5::*;
+
+#[tokio::test]
+async fn blocks_configured_and_runtime_entries() {
+    let (framework, server) = start().await;
+    framework.route("GET /", |ctrl| ctrl.plain("home"));
+
+    let (status, _, _) = request(server.port(), "GET", "/", &[], b"").await;
+    assert_eq!(status, 200);
+
+    assert!(framework.block("127.0.0.0/8"));
+    assert!(!framework.block("not an ip"));
+    let (status, _, _) = request(server.port(), "GET", "/", &[], b"").await;
+    assert_eq!(status, 403);
+
+    assert!(framework.unblock("127.0.0.0/8"));
+    let (status, _, _) = request(server.port(), "GET", "/", &[], b"").await;
+    assert_eq!(status, 200);
+
+    CONF.write().unwrap()._blacklist = "10.0.0.1, 127.0.0.1".to_string();
+    let (status, _, _) = request(server.port(), "GET", "/", &[], b"").await;
+    CONF.write().unwrap()._blacklist = String::new();
+    assert_eq!(status, 403);
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.request.blocked, 2);
+    assert_eq!(stats.response.error403, 2);
+}
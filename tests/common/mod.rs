@@ -0,0 +1,154 @@
+#![allow(dead_code)]
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use total5::*;
+
+/// Starts a server on a free port with its own framework instance
+pub async fn start() -> (&'static Framework, HttpServer) {
+    let framework: &'static Framework = Box::leak(Box::new(Framework::default()));
+    let server = framework.http("test").await.unwrap();
+    (framework, server)
+}
+
+/// Starts a server with its own directories, e.g. `start_with(|| TPath::new(std::env::temp_dir().join("app")))`
+pub async fn start_with(path: fn() -> TPath) -> (&'static Framework, HttpServer) {
+    let framework: &'static Framework = Box::leak(Box::new(Framework {
+        path: once_cell::sync::Lazy::new(path),
+        ..Framework::default()
+    }));
+    let server = framework.http("test").await.unwrap();
+    (framework, server)
+}
+
+/// Sends a raw request and reads the whole response (the connection must be closed by the server)
+pub async fn send(port: u16, raw: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream.write_all(raw).await.unwrap();
+    let mut output = Vec::new();
+    stream.read_to_end(&mut output).await.unwrap();
+    output
+}
+
+/// Performs a request with `Connection: close`, returns the status, the head and the body
+pub async fn request(port: u16, method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> (u16, String, Vec<u8>) {
+    let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", method, url);
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !body.is_empty() {
+        raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    raw.push_str("\r\n");
+
+    let mut raw = raw.into_bytes();
+    raw.extend_from_slice(body);
+    parse(&send(port, &raw).await)
+}
+
+/// Splits a raw response into the status, the head and the body
+pub fn parse(output: &[u8]) -> (u16, String, Vec<u8>) {
+    let index = output.windows(4).position(|window| window == b"\r\n\r\n").expect("incomplete response");
+    let head = String::from_utf8_lossy(&output[..index]).to_string();
+    let status = head.split(' ').nth(1).and_then(|status| status.parse().ok()).unwrap_or(0);
+    let body = &output[index + 4..];
+
+    if header(&head, "transfer-encoding") == Some("chunked") {
+        return (status, head, dechunk(body));
+    }
+
+    (status, head, body.to_vec())
+}
+
+/// Decodes a chunked body
+pub fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    while let Some(index) = body.windows(2).position(|window| window == b"\r\n") {
+        let size = usize::from_str_radix(std::str::from_utf8(&body[..index]).unwrap().trim(), 16).unwrap();
+        if size == 0 {
+            break;
+        }
+        output.extend_from_slice(&body[index + 2..index + 2 + size]);
+        body = &body[index + 4 + size..];
+    }
+    output
+}
+
+/// Returns a header value of a response head
+pub fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Opens a WebSocket connection, returns the stream and the response head
+pub async fn ws_connect(port: u16, path: &str, headers: &[(&str, &str)]) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut raw = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+        path
+    );
+    for (name, value) in headers {
+        raw.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    raw.push_str("\r\n");
+    stream.write_all(raw.as_bytes()).await.unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8_lossy(&head).to_string())
+}
+
+/// Sends a masked frame, `first` contains FIN, RSV and the opcode
+pub async fn ws_write(stream: &mut TcpStream, first: u8, payload: &[u8]) {
+    let mask = [0x12u8, 0x34, 0x56, 0x78];
+    let mut frame = vec![first];
+    match payload.len() {
+        length if length < 126 => frame.push(0x80 | length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+    stream.write_all(&frame).await.unwrap();
+}
+
+/// Sends a complete text message
+pub async fn ws_send(stream: &mut TcpStream, text: &str) {
+    ws_write(stream, 0x81, text.as_bytes()).await
+}
+
+/// Reads an unmasked frame, returns the first byte (FIN, RSV and the opcode) and the payload
+pub async fn ws_read(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await.unwrap();
+    let length = match head[1] & 0x7F {
+        126 => {
+            let mut bytes = [0u8; 2];
+            stream.read_exact(&mut bytes).await.unwrap();
+            u16::from_be_bytes(bytes) as usize
+        }
+        127 => {
+            let mut bytes = [0u8; 8];
+            stream.read_exact(&mut bytes).await.unwrap();
+            u64::from_be_bytes(bytes) as usize
+        }
+        length => length as usize,
+    };
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await.unwrap();
+    (head[0], payload)
+}
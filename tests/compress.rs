@@ -0,0 +1,129 @@
+mod common;
+
+use std::io::Read;
+use std::path::PathBuf;
+use common::{header, request};
+use once_cell::sync::Lazy;
+use total5::*;
+
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("total5-compress-{}", std::process::id()))
+}
+
+async fn start() -> (&'static Framework, HttpServer) {
+    let framework: &'static Framework = Box::leak(Box::new(Framework {
+        path: Lazy::new(|| TPath::new(directory())),
+        ..Framework::default()
+    }));
+
+    framework.route("GET /text/", |ctrl| ctrl.plain("Total.js ".repeat(500)));
+    framework.route("GET /tiny/", |ctrl| ctrl.plain("small"));
+    framework.route("GET /large/", |ctrl| ctrl.plain("Total.js ".repeat(50_000)));
+    framework.route("GET /image/", |ctrl| ctrl.binary(vec![7; 4096], "image/png"));
+
+    let server = framework.http("test").await.unwrap();
+    (framework, server)
+}
+
+fn gunzip(data: &[u8]) -> String {
+    let mut output = String::new();
+    flate2::read::GzDecoder::new(data).read_to_string(&mut output).unwrap();
+    output
+}
+
+fn unbrotli(data: &[u8]) -> String {
+    let mut output = String::new();
+    brotli::Decompressor::new(data, 4096).read_to_string(&mut output).unwrap();
+    output
+}
+
+#[tokio::test]
+async fn negotiates_encodings() {
+    let (_, server) = start().await;
+    let text = "Total.js ".repeat(500);
+
+    let (status, head, body) = request(server.port(), "GET", "/text/", &[("Accept-Encoding", "gzip, deflate, br")], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "content-encoding"), Some("br"));
+    assert_eq!(header(&head, "vary"), Some("Accept-Encoding"));
+    assert_eq!(unbrotli(&body), text);
+
+    let (_, head, body) = request(server.port(), "GET", "/text/", &[("Accept-Encoding", "br;q=0, gzip")], b"").await;
+    assert_eq!(header(&head, "content-encoding"), Some("gzip"));
+    assert_eq!(gunzip(&body), text);
+
+    let (_, head, body) = request(server.port(), "GET", "/text/", &[("Accept-Encoding", "deflate")], b"").await;
+    assert_eq!(header(&head, "content-encoding"), Some("deflate"));
+    let mut output = String::new();
+    flate2::read::DeflateDecoder::new(&body[..]).read_to_string(&mut output).unwrap();
+    assert_eq!(output, text);
+
+    let (_, head, body) = request(server.port(), "GET", "/text/", &[], b"").await;
+    assert_eq!(header(&head, "content-encoding"), None);
+    assert_eq!(body, text.as_bytes());
+
+    // Large bodies are compressed on the blocking thread pool
+    let (_, head, body) = request(server.port(), "GET", "/large/", &[("Accept-Encoding", "gzip")], b"").await;
+    assert_eq!(header(&head, "content-encoding"), Some("gzip"));
+    assert_eq!(gunzip(&body), "Total.js ".repeat(50_000));
+}
+
+#[tokio::test]
+async fn skips_tiny_and_compressed_bodies() {
+    let (_, server) = start().await;
+
+    let (_, head, body) = request(server.port(), "GET", "/tiny/", &[("Accept-Encoding", "gzip")], b"").await;
+    assert_eq!(header(&head, "content-encoding"), None);
+    assert_eq!(body, b"small");
+
+    let (_, head, body) = request(server.port(), "GET", "/image/", &[("Accept-Encoding", "gzip")], b"").await;
+    assert_eq!(header(&head, "content-encoding"), None);
+    assert_eq!(body.len(), 4096);
+}
+
+#[tokio::test]
+async fn caches_compressed_static_files() {
+    let (framework, server) = start().await;
+
+    // The directory of a previous run with the same process id
+    let cache = framework.path.tmp(Some("compressed"));
+    let _ = std::fs::remove_dir_all(&cache);
+
+    let script = "function hello() { return 'world'; }\n".repeat(100);
+    let public = framework.path.public(None);
+    std::fs::create_dir_all(&public).unwrap();
+    std::fs::write(public.join("app.js"), &script).unwrap();
+
+    let (status, head, body) = request(server.port(), "GET", "/app.js", &[("Accept-Encoding", "gzip")], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "content-encoding"), Some("gzip"));
+    assert_eq!(header(&head, "content-length"), Some(body.len().to_string().as_str()));
+    assert_eq!(gunzip(&body), script);
+
+    let cached: Vec<_> = std::fs::read_dir(&cache).unwrap().map(|entry| entry.unwrap().path()).collect();
+    assert_eq!(cached.len(), 1);
+    assert!(cached[0].to_string_lossy().ends_with(".gz"));
+
+    // The cached copy is reused
+    let modified = std::fs::metadata(&cached[0]).unwrap().modified().unwrap();
+    let (_, _, again) = request(server.port(), "GET", "/app.js", &[("Accept-Encoding", "gzip")], b"").await;
+    assert_eq!(again, body);
+    assert_eq!(std::fs::metadata(&cached[0]).unwrap().modified().unwrap(), modified);
+    assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+
+    // Each encoding has its own ETag, both of them are revalidated
+    let (_, head, _) = request(server.port(), "GET", "/app.js", &[], b"").await;
+    let identity = header(&head, "etag").unwrap().to_string();
+    let (_, head, _) = request(server.port(), "GET", "/app.js", &[("Accept-Encoding", "br")], b"").await;
+    let brotli = header(&head, "etag").unwrap().to_string();
+    assert_eq!(brotli, format!("{}-br\"", identity.trim_end_matches('"')));
+
+    let (status, head, _) = request(server.port(), "GET", "/app.js", &[("Accept-Encoding", "br"), ("If-None-Match", &brotli)], b"").await;
+    assert_eq!(status, 304);
+    assert_eq!(header(&head, "etag"), Some(brotli.as_str()));
+    let (status, head, _) = request(server.port(), "GET", "/app.js", &[("If-None-Match", &identity)], b"").await;
+    assert_eq!(status, 304);
+    assert_eq!(header(&head, "etag"), Some(identity.as_str()));
+
+    let _ = std::fs::remove_dir_all(directory());
+}
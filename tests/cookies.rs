@@ -0,0 +1,52 @@
+mod common;
+
+use common::{request, start};
+use total5::*;
+
+/// Returns `name=value` pairs of all `Set-Cookie` headers
+fn cookies(head: &str) -> Vec<String> {
+    head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"))
+        .map(|(_, value)| value.trim().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn sets_and_verifies_cookies() {
+    let (framework, server) = start().await;
+
+    framework.route("GET /login/", |mut ctrl| {
+        ctrl.cookie("lang", "sk", "", CookieOptions { httponly: Some(false), ..Default::default() });
+        ctrl.cookie_signed("user", "15", "1 day", CookieOptions::default());
+        ctrl.cookie_encrypted("token", "secret value", "1 day", CookieOptions::default());
+        ctrl.plain("ok")
+    });
+
+    framework.route("GET /profile/", |ctrl| {
+        let lang = ctrl.get_cookie("lang").map(|value| value.to_string());
+        let user = ctrl.get_cookie_signed("user");
+        let token = ctrl.get_cookie_encrypted("token");
+        ctrl.json(serde_json::json!({ "lang": lang, "user": user, "token": token }))
+    });
+
+    let (status, head, _) = request(server.port(), "GET", "/login/", &[], b"").await;
+    assert_eq!(status, 200);
+
+    let set = cookies(&head);
+    assert_eq!(set.len(), 3);
+    assert_eq!(set[0], "lang=sk; Path=/; SameSite=Lax");
+    assert!(set[1].starts_with("user=15.") && set[1].contains("; Expires=") && set[1].ends_with("; SameSite=Lax; HttpOnly"));
+    assert!(!set[2].contains("secret"));
+
+    let pairs: Vec<&str> = set.iter().map(|cookie| cookie.split(';').next().unwrap()).collect();
+    let (status, _, body) = request(server.port(), "GET", "/profile/", &[("Cookie", &pairs.join("; "))], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(String::from_utf8(body).unwrap(), r#"{"lang":"sk","token":"secret value","user":"15"}"#);
+
+    // Changed values are rejected
+    let first = if pairs[2][6..].starts_with('A') { 'B' } else { 'A' };
+    let tampered = format!("lang=en; {}; token={}{}", pairs[1].replacen("=15.", "=1.", 1), first, &pairs[2][7..]);
+    let (_, _, body) = request(server.port(), "GET", "/profile/", &[("Cookie", &tampered)], b"").await;
+    assert_eq!(String::from_utf8(body).unwrap(), r#"{"lang":"en","token":null,"user":null}"#);
+}
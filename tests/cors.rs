@@ -0,0 +1,75 @@
+mod common;
+
+use common::{header, request, start};
+use total5::*;
+
+#[tokio::test]
+async fn global_origins_from_config() {
+    let (framework, server) = start().await;
+    framework.route("GET /products/", |ctrl| ctrl.json(serde_json::json!([])));
+    framework.route("GET /catalog/", |ctrl| ctrl.plain("product ".repeat(200)));
+
+    CONF.write().unwrap()._cors = "https://totaljs.com, *.totaljs.com".to_string();
+
+    let preflight = [
+        ("Origin", "https://app.totaljs.com"),
+        ("Access-Control-Request-Method", "post"),
+        ("Access-Control-Request-Headers", "content-type, x-token"),
+    ];
+    let (status, head, _) = request(server.port(), "OPTIONS", "/products/", &preflight, b"").await;
+    assert_eq!(status, 204);
+    assert_eq!(header(&head, "access-control-allow-origin"), Some("https://app.totaljs.com"));
+    assert_eq!(header(&head, "access-control-allow-methods"), Some("POST"));
+    assert_eq!(header(&head, "access-control-allow-headers"), Some("content-type, x-token"));
+    assert_eq!(header(&head, "vary"), Some("Origin"));
+
+    let (status, head, _) = request(server.port(), "OPTIONS", "/products/", &[("Origin", "https://evil.io"), ("Access-Control-Request-Method", "GET")], b"").await;
+    assert_eq!(status, 403);
+    assert_eq!(header(&head, "access-control-allow-origin"), None);
+    assert_eq!(header(&head, "vary"), Some("Origin"));
+
+    let (status, head, _) = request(server.port(), "GET", "/products/", &[("Origin", "https://totaljs.com")], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "access-control-allow-origin"), Some("https://totaljs.com"));
+
+    // Compressible responses vary by both headers
+    let (_, head, _) = request(server.port(), "GET", "/catalog/", &[("Origin", "https://totaljs.com")], b"").await;
+    assert_eq!(header(&head, "vary"), Some("Origin, Accept-Encoding"));
+
+    // Responses without the allowed origin vary by `Origin` too, so caches don't mix the variants
+    let (status, head, _) = request(server.port(), "GET", "/products/", &[("Origin", "https://evil.io")], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "access-control-allow-origin"), None);
+    assert_eq!(header(&head, "vary"), Some("Origin"));
+
+    let (_, head, _) = request(server.port(), "GET", "/products/", &[], b"").await;
+    assert_eq!(header(&head, "vary"), Some("Origin"));
+
+    CONF.write().unwrap()._cors = String::new();
+
+    assert_eq!(framework.stats.read().unwrap().request.options, 2);
+}
+
+#[tokio::test]
+async fn route_policies_override_config() {
+    let (framework, server) = start().await;
+    framework.route("GET /public/feed/", |ctrl| ctrl.plain("feed"));
+    framework.route("GET /account/", |ctrl| ctrl.plain("account"));
+
+    framework.cors("/public/*", &["*"], false);
+    framework.cors("/account/", &["https://admin.totaljs.com"], true);
+
+    let (_, head, _) = request(server.port(), "GET", "/public/feed/", &[("Origin", "https://anyone.io")], b"").await;
+    assert_eq!(header(&head, "access-control-allow-origin"), Some("*"));
+    assert_eq!(header(&head, "vary"), None);
+
+    let preflight = [("Origin", "https://admin.totaljs.com"), ("Access-Control-Request-Method", "GET")];
+    let (status, head, _) = request(server.port(), "OPTIONS", "/account/", &preflight, b"").await;
+    assert_eq!(status, 204);
+    assert_eq!(header(&head, "access-control-allow-origin"), Some("https://admin.totaljs.com"));
+    assert_eq!(header(&head, "access-control-allow-credentials"), Some("true"));
+
+    let (_, head, _) = request(server.port(), "GET", "/account/", &[("Origin", "https://anyone.io")], b"").await;
+    assert_eq!(header(&head, "access-control-allow-origin"), None);
+    assert_eq!(header(&head, "vary"), Some("Origin"));
+}
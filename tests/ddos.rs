@@ -0,0 +1,85 @@
+mod common;
+
+use std::time::Duration;
+use common::{header, request, start};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use total5::*;
+
+#[tokio::test]
+async fn limits_requests_per_ip() {
+    let (framework, server) = start().await;
+    let port = server.port();
+
+    framework.route("GET /", |ctrl| ctrl.plain("home"));
+    framework.route("GET /slow/", |ctrl| {
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(1600)).await;
+            ctrl.plain("slow")
+        });
+    });
+
+    CONF.write().unwrap()._httpreqlimit = 2;
+
+    // Requests per window
+    assert_eq!(request(port, "GET", "/", &[], b"").await.0, 200);
+    assert_eq!(request(port, "GET", "/", &[], b"").await.0, 200);
+    let (status, head, _) = request(port, "GET", "/", &[], b"").await;
+    assert_eq!(status, 503);
+    assert_eq!(header(&head, "retry-after"), Some("1"));
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert_eq!(request(port, "GET", "/", &[], b"").await.0, 200);
+
+    // Requests in progress outlive the window
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let slow = tokio::spawn(async move {
+        tokio::join!(request(port, "GET", "/slow/", &[], b""), request(port, "GET", "/slow/", &[], b""))
+    });
+
+    tokio::time::sleep(Duration::from_millis(1250)).await;
+    assert_eq!(request(port, "GET", "/", &[], b"").await.0, 503);
+
+    let (first, second) = slow.await.unwrap();
+    assert_eq!((first.0, second.0), (200, 200));
+    assert_eq!(framework.temporary.read().unwrap().ddos["127.0.0.1"].pending, 0);
+
+    // Proxied requests hold their slot until the body of the upstream has been streamed
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 1024];
+                let _ = stream.read(&mut buffer).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n").await;
+                tokio::time::sleep(Duration::from_millis(1600)).await;
+                let _ = stream.write_all(b"hello").await;
+            });
+        }
+    });
+    framework.proxy("/proxy/", &format!("http://127.0.0.1:{}/", upstream));
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let proxied = tokio::spawn(async move {
+        tokio::join!(request(port, "GET", "/proxy/", &[], b""), request(port, "GET", "/proxy/", &[], b""))
+    });
+
+    tokio::time::sleep(Duration::from_millis(1250)).await;
+    assert_eq!(request(port, "GET", "/", &[], b"").await.0, 503);
+
+    let (first, second) = proxied.await.unwrap();
+    assert_eq!((first.2, second.2), (b"hello".to_vec(), b"hello".to_vec()));
+    assert_eq!(framework.temporary.read().unwrap().ddos["127.0.0.1"].pending, 0);
+
+    CONF.write().unwrap()._httpreqlimit = 0;
+
+    // Expired idle entries are removed
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    framework.ddos_cleanup();
+    assert!(framework.temporary.read().unwrap().ddos.is_empty());
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.response.ddos, 3);
+    assert_eq!(stats.response.error503, 3);
+}
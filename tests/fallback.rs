@@ -0,0 +1,84 @@
+mod common;
+
+use common::{header, request, start};
+
+#[tokio::test]
+async fn unknown_url_uses_404_fallback() {
+    let (framework, server) = start().await;
+
+    framework.fallback(404, |mut ctrl| {
+        ctrl.set_header("X-Fallback", "yes");
+        let path = ctrl.path.clone();
+        ctrl.html(format!("<h1>Missing {}</h1>", path))
+    });
+
+    let (status, head, body) = request(server.port(), "GET", "/unknown/", &[], b"").await;
+    assert_eq!(status, 404);
+    assert_eq!(header(&head, "x-fallback"), Some("yes"));
+    assert_eq!(header(&head, "content-type"), Some("text/html; charset=utf-8"));
+    assert_eq!(body, b"<h1>Missing /unknown/</h1>");
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.response.error404, 1);
+    assert_eq!(stats.response.custom, 1);
+}
+
+#[tokio::test]
+async fn panicking_handler_uses_500_fallback() {
+    let (framework, server) = start().await;
+
+    framework.route("GET /crash/", |_| panic!("handler failed"));
+    framework.route("GET /alive/", |ctrl| ctrl.plain("alive"));
+    framework.fallback(500, |ctrl| ctrl.json(serde_json::json!({ "crashed": true })));
+
+    for _ in 0..2 {
+        let (status, _, body) = request(server.port(), "GET", "/crash/", &[], b"").await;
+        assert_eq!(status, 500);
+        assert_eq!(body, br#"{"crashed":true}"#);
+    }
+
+    // The server keeps serving after the panics
+    let (status, _, body) = request(server.port(), "GET", "/alive/", &[], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"alive");
+
+    assert_eq!(framework.stats.read().unwrap().response.error500, 2);
+}
+
+#[tokio::test]
+async fn thrown_errors_use_fallbacks() {
+    let (framework, server) = start().await;
+
+    framework.route("GET /admin/", |ctrl| ctrl.throw403());
+    framework.fallback(403, |ctrl| ctrl.plain("forbidden area"));
+
+    let (status, _, body) = request(server.port(), "GET", "/admin/", &[], b"").await;
+    assert_eq!(status, 403);
+    assert_eq!(body, b"forbidden area");
+
+    // A fallback throwing again ends with the default page
+    framework.route("GET /conflict/", |ctrl| ctrl.throw409());
+    framework.fallback(409, |ctrl| ctrl.throw500());
+
+    let (status, head, _) = request(server.port(), "GET", "/conflict/", &[], b"").await;
+    assert_eq!(status, 409);
+    assert_eq!(header(&head, "content-type"), Some("text/html; charset=utf-8"));
+}
+
+#[tokio::test]
+async fn default_error_page_follows_accept_and_xhr() {
+    let (_, server) = start().await;
+
+    let (status, head, body) = request(server.port(), "GET", "/missing/", &[("Accept", "text/html,*/*")], b"").await;
+    assert_eq!(status, 404);
+    assert_eq!(header(&head, "content-type"), Some("text/html; charset=utf-8"));
+    assert!(String::from_utf8_lossy(&body).contains("<h1>404</h1>"));
+
+    let (status, head, body) = request(server.port(), "GET", "/missing/", &[("Accept", "application/json")], b"").await;
+    assert_eq!(status, 404);
+    assert_eq!(header(&head, "content-type"), Some("application/json; charset=utf-8"));
+    assert_eq!(body, br#"[{"error":"Not Found","status":404}]"#);
+
+    let (_, head, _) = request(server.port(), "GET", "/missing/", &[("X-Requested-With", "XMLHttpRequest")], b"").await;
+    assert_eq!(header(&head, "content-type"), Some("application/json; charset=utf-8"));
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{parse, request, send, start};
+
+#[tokio::test]
+async fn oversize_requests_are_refused() {
+    let (framework, server) = start().await;
+
+    framework.route("POST /upload/", |ctrl| {
+        let size = ctrl.payload.len();
+        ctrl.plain(size.to_string())
+    });
+    framework.route("POST /large/ <1MB", |ctrl| {
+        let size = ctrl.payload.len();
+        ctrl.plain(size.to_string())
+    });
+
+    // `CONF._httpmaxsize` is 256 kB by default
+    let body = vec![b'a'; 300 * 1024];
+    let (status, _, _) = request(server.port(), "POST", "/upload/", &[], &body).await;
+    assert_eq!(status, 413);
+
+    // The route limit overrides the global one
+    let (status, _, body) = request(server.port(), "POST", "/large/", &[], &body).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"307200");
+
+    let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {}\r\n\r\n", "a".repeat(20 * 1024));
+    let (status, _, _) = parse(&send(server.port(), raw.as_bytes()).await);
+    assert_eq!(status, 431);
+    assert_eq!(framework.stats.read().unwrap().response.error431, 1);
+}
+
+#[tokio::test]
+async fn too_many_or_too_long_keys_are_refused() {
+    let (framework, server) = start().await;
+
+    framework.route("POST /form/", |ctrl| ctrl.empty());
+    let urlencoded = &[("Content-Type", "application/x-www-form-urlencoded")];
+
+    let query: Vec<String> = (0..40).map(|index| format!("k{}=1", index)).collect();
+    let (status, _, _) = request(server.port(), "GET", &format!("/?{}", query.join("&")), &[], b"").await;
+    assert_eq!(status, 400);
+
+    let (status, _, _) = request(server.port(), "GET", &format!("/?{}=1", "k".repeat(26)), &[], b"").await;
+    assert_eq!(status, 400);
+
+    let body = query.join("&");
+    let (status, _, _) = request(server.port(), "POST", "/form/", urlencoded, body.as_bytes()).await;
+    assert_eq!(status, 400);
+
+    let (status, _, _) = request(server.port(), "POST", "/form/", urlencoded, b"name=Peter&age=30").await;
+    assert_eq!(status, 204);
+}
+
+#[tokio::test]
+async fn refused_requests_use_fallbacks() {
+    let (framework, server) = start().await;
+    framework.route("POST /upload/", |ctrl| ctrl.empty());
+    framework.fallback(413, |ctrl| ctrl.plain("Too large"));
+
+    let body = vec![b'a'; 300 * 1024];
+    let (status, _, body) = request(server.port(), "POST", "/upload/", &[], &body).await;
+    assert_eq!(status, 413);
+    assert_eq!(body, b"Too large");
+}
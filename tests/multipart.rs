@@ -0,0 +1,150 @@
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+use common::{request, start_with};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use total5::*;
+
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("total5-multipart-{}", std::process::id()))
+}
+
+fn multipart(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, filename, data) in parts {
+        body.extend_from_slice(b"--boundary42\r\n");
+        match filename {
+            Some(filename) => body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: image/png\r\n\r\n", name, filename).as_bytes()),
+            None => body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name).as_bytes()),
+        }
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(b"--boundary42--\r\n");
+    body
+}
+
+const MULTIPART: &[(&str, &str)] = &[("Content-Type", "multipart/form-data; boundary=boundary42")];
+
+fn uploads() -> usize {
+    std::fs::read_dir(directory().join("tmp")).map(|entries| entries.count()).unwrap_or(0)
+}
+
+#[tokio::test]
+async fn uploads_files_and_removes_them_after_the_request() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+
+    framework.route("POST /upload/ <1MB", |ctrl| {
+        let file = &ctrl.files[0];
+        let output = serde_json::json!({
+            "title": ctrl.field("title").and_then(|value| value.as_str()).unwrap_or_default(),
+            "name": file.name,
+            "filename": file.filename,
+            "type": file.content_type,
+            "size": file.size,
+            "data": String::from_utf8(file.read().unwrap()).unwrap(),
+            "exists": file.path.exists(),
+        });
+        ctrl.json(output)
+    });
+
+    let body = multipart(&[("title", None, b"Logo"), ("logo", Some("logo.png"), b"PNG DATA")]);
+    let (status, _, body) = request(server.port(), "POST", "/upload/", MULTIPART, &body).await;
+    assert_eq!(status, 200);
+
+    let output: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(output, serde_json::json!({
+        "title": "Logo",
+        "name": "logo",
+        "filename": "logo.png",
+        "type": "image/png",
+        "size": 8,
+        "data": "PNG DATA",
+        "exists": true,
+    }));
+
+    assert_eq!(uploads(), 0);
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.request.upload, 1);
+    assert_eq!(stats.performance.upload, 1);
+}
+
+#[tokio::test]
+async fn moved_files_are_kept() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+    let target = directory().join("moved/avatar.png");
+
+    framework.route("POST /avatar/ <1MB", |mut ctrl| {
+        let target = directory().join("moved/avatar.png");
+        ctrl.files[0].move_to(&target).unwrap();
+        ctrl.empty()
+    });
+
+    let body = multipart(&[("avatar", Some("me.png"), b"AVATAR")]);
+    let (status, _, _) = request(server.port(), "POST", "/avatar/", MULTIPART, &body).await;
+    assert_eq!(status, 204);
+    assert_eq!(std::fs::read(&target).unwrap(), b"AVATAR");
+}
+
+#[tokio::test]
+async fn route_limits_and_invalid_bodies() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+
+    framework.route("POST /small/ <1kb", |ctrl| ctrl.empty());
+
+    let body = multipart(&[("file", Some("large.png"), &[0; 2048])]);
+    let (status, _, _) = request(server.port(), "POST", "/small/", MULTIPART, &body).await;
+    assert_eq!(status, 413);
+
+    let body = b"--boundary42\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.png\"\r\n\r\nno closing delimiter";
+    let (status, _, _) = request(server.port(), "POST", "/small/", MULTIPART, body).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn limits_text_fields() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+    framework.route("POST /fields/ <1MB", |ctrl| {
+        let value = ctrl.field("k32").and_then(|value| value.as_str()).unwrap_or_default().to_string();
+        ctrl.plain(value)
+    });
+
+    // `_httpmaxkeys` is 33 and `_httpmaxkey` is 25 by default
+    let names: Vec<String> = (0..34).map(|index| format!("k{}", index)).collect();
+    let parts: Vec<(&str, Option<&str>, &[u8])> = names.iter().map(|name| (name.as_str(), None, &b"value"[..])).collect();
+
+    let (status, _, body) = request(server.port(), "POST", "/fields/", MULTIPART, &multipart(&parts[..33])).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"value");
+
+    let (status, _, _) = request(server.port(), "POST", "/fields/", MULTIPART, &multipart(&parts)).await;
+    assert_eq!(status, 400);
+
+    let name = "k".repeat(26);
+    let (status, _, _) = request(server.port(), "POST", "/fields/", MULTIPART, &multipart(&[(&name, None, b"value")])).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn removes_files_of_unfinished_uploads() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+    framework.route("POST /slow/ <1MB", |ctrl| ctrl.empty());
+
+    // The body stops in the middle of the file and the request times out
+    let body = multipart(&[("file", Some("slow.png"), &[1; 1024])]);
+    let head = format!("POST /slow/ HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=boundary42\r\nContent-Length: {}\r\n\r\n", body.len());
+    CONF.write().unwrap()._httptimeout = 1;
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port())).await.unwrap();
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&body[..body.len() / 2]).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(uploads() > 0);
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    CONF.write().unwrap()._httptimeout = 5;
+    assert_eq!(uploads(), 0);
+}
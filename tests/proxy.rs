@@ -0,0 +1,201 @@
+mod common;
+
+use std::time::Duration;
+use common::{header, parse, request, send, start, ws_connect, ws_read, ws_send};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use total5::*;
+
+/// Reads the streamed response until it contains `expected`, returns the head and the raw body
+async fn read_until(stream: &mut TcpStream, expected: &str) -> (String, String) {
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        let read = stream.read(&mut buffer).await.unwrap();
+        assert!(read > 0, "the connection has been closed");
+        output.extend_from_slice(&buffer[..read]);
+
+        let text = String::from_utf8_lossy(&output);
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            if body.contains(expected) {
+                return (head.to_string(), body.to_string());
+            }
+        }
+    }
+}
+
+/// Starts an upstream server with routes under `/v2/`
+async fn upstream() -> (&'static Framework, HttpServer) {
+    let (framework, server) = start().await;
+
+    framework.route("GET /v2/users/{id}/", |ctrl| {
+        let header = |name: &str| ctrl.header(name).unwrap_or_default().to_string();
+        let info = serde_json::json!({
+            "url": ctrl.url,
+            "host": header("host"),
+            "forwarded": header("x-forwarded-for"),
+            "proto": header("x-forwarded-proto"),
+            "token": header("x-token"),
+        });
+        ctrl.json(info)
+    });
+
+    framework.route("POST /v2/echo/", |ctrl| {
+        let body = String::from_utf8_lossy(&ctrl.payload).to_string();
+        ctrl.plain(body)
+    });
+
+    framework.route("GET /v2/live/", |ctrl| {
+        let events = ctrl.sse();
+        tokio::spawn(async move {
+            events.data("first").await;
+            events.closed().await;
+        });
+    });
+
+    let echo = framework.websocket("SOCKET /v2/socket/");
+    echo.on_message(|client, message| {
+        client.send(message);
+    });
+
+    (framework, server)
+}
+
+#[tokio::test]
+async fn forwards_requests() {
+    let (_, upstream) = upstream().await;
+    let (framework, server) = start().await;
+
+    let proxy = framework.proxy("/old-api/", &format!("http://127.0.0.1:{}/v2/", upstream.port()));
+    proxy.before(|request| {
+        if request.path.contains("/private/") {
+            return Some(Response::new(401));
+        }
+        request.headers.insert("x-token".to_string(), "123".to_string());
+        None
+    });
+    proxy.after(|_, response| response.set_header("X-Proxy", "total5"));
+
+    let (status, head, body) = request(server.port(), "GET", "/old-api/users/5/?page=2", &[], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "x-proxy"), Some("total5"));
+
+    let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(info["url"], "/v2/users/5/?page=2");
+    assert_eq!(info["host"], format!("127.0.0.1:{}", upstream.port()));
+    assert_eq!(info["forwarded"], "127.0.0.1");
+    assert_eq!(info["proto"], "http");
+    assert_eq!(info["token"], "123");
+
+    // Chunked bodies are forwarded and the connection is reused for the next request
+    let raw = b"POST /old-api/echo/ HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n\
+POST /old-api/echo/ HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld";
+    let output = String::from_utf8(send(server.port(), raw).await).unwrap();
+    let (first, second) = output.split_at(output.rfind("HTTP/1.1 200 ").unwrap());
+    assert_eq!(parse(first.as_bytes()).2, b"hello");
+    assert_eq!(parse(second.as_bytes()).2, b"world");
+
+    // Forwarded bodies are limited by `_httpmaxsize` (256 kB)
+    let raw = b"POST /old-api/echo/ HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1048576\r\n\r\n";
+    assert!(send(server.port(), raw).await.starts_with(b"HTTP/1.1 413 "));
+
+    // The `before` hook answers instead of the upstream
+    let (status, _, _) = request(server.port(), "GET", "/old-api/private/", &[], b"").await;
+    assert_eq!(status, 401);
+
+    // Responses are streamed
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port())).await.unwrap();
+    stream.write_all(b"GET /old-api/live/ HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let (head, body) = read_until(&mut stream, "data: first\n\n").await;
+    assert_eq!(header(&head, "content-type"), Some("text/event-stream; charset=utf-8"));
+    assert_eq!(header(&head, "transfer-encoding"), Some("chunked"));
+    assert_eq!(body, "d\r\ndata: first\n\n\r\n");
+
+    framework.proxy("/down/", "http://127.0.0.1:1/");
+    let (status, _, _) = request(server.port(), "GET", "/down/", &[], b"").await;
+    assert_eq!(status, 502);
+
+    assert_eq!(framework.stats.read().unwrap().response.proxy, 4);
+}
+
+#[tokio::test]
+async fn rejects_dot_segments() {
+    let (_, upstream) = upstream().await;
+    let (framework, server) = start().await;
+    framework.proxy("/old-api/", &format!("http://127.0.0.1:{}/v2/", upstream.port()));
+
+    for url in ["/old-api/../admin/", "/old-api/users/%2e%2e/%2E%2E/admin/", "/old-api/./users/5/"] {
+        let (status, _, _) = request(server.port(), "GET", url, &[], b"").await;
+        assert_eq!(status, 400, "{}", url);
+    }
+
+    let (status, _, _) = request(server.port(), "GET", "/old-api/users/5/", &[], b"").await;
+    assert_eq!(status, 200);
+}
+
+#[tokio::test]
+async fn proxies_websockets() {
+    let (_, upstream) = upstream().await;
+    let (framework, server) = start().await;
+    framework.proxy("/ws/", &format!("http://127.0.0.1:{}/v2/", upstream.port()));
+
+    let (mut client, head) = ws_connect(server.port(), "/ws/socket/", &[]).await;
+    assert!(head.starts_with("HTTP/1.1 101 "));
+    assert_eq!(header(&head, "sec-websocket-accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    ws_send(&mut client, "Hello").await;
+    assert_eq!(ws_read(&mut client).await, (0x81, b"Hello".to_vec()));
+
+    // Upstream errors are returned as they are
+    let (_, head) = ws_connect(server.port(), "/ws/unknown/", &[]).await;
+    assert!(head.starts_with("HTTP/1.1 404 "));
+}
+
+#[tokio::test]
+async fn times_out_slow_upstreams() {
+    // Accepts connections and never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+
+    let (framework, server) = start().await;
+    framework.proxy("/slow/", &format!("http://127.0.0.1:{}/", port));
+
+    CONF.write().unwrap()._proxytimeout = 1;
+    let started = std::time::Instant::now();
+    let (status, _, _) = request(server.port(), "GET", "/slow/", &[], b"").await;
+    CONF.write().unwrap()._proxytimeout = 5;
+
+    assert_eq!(status, 504);
+    assert!(started.elapsed() < Duration::from_secs(3));
+}
+
+#[tokio::test]
+async fn aborts_truncated_responses() {
+    // Promises 100 bytes and closes the connection after 5
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await;
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nshort").await;
+        }
+    });
+
+    let (framework, server) = start().await;
+    framework.proxy("/broken/", &format!("http://127.0.0.1:{}/", port));
+
+    // The connection is closed without the last chunk even though the client wants to keep it
+    let raw = b"GET /broken/ HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let output = tokio::time::timeout(Duration::from_secs(5), send(server.port(), raw)).await.unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.starts_with("HTTP/1.1 200 "));
+    assert!(output.ends_with("5\r\nshort\r\n"));
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use std::path::PathBuf;
+use chrono::{Duration, Utc};
+use common::{header, request, start, start_with};
+use total5::*;
+
+/// Returns the `name=value` part of the `Set-Cookie` header
+fn cookie(head: &str) -> String {
+    header(head, "set-cookie").unwrap().split(';').next().unwrap().to_string()
+}
+
+fn register(framework: &'static Framework) {
+    framework.route("GET /login/", |mut ctrl| {
+        ctrl.session = Some(FrameworkValue::from("peter"));
+        ctrl.plain("ok")
+    });
+
+    framework.route("GET /profile/", |ctrl| {
+        let user = ctrl.session.clone().map(|session| session.to_string()).unwrap_or_default();
+        ctrl.plain(user)
+    });
+
+    framework.route("GET /logout/", |mut ctrl| {
+        ctrl.session = None;
+        ctrl.plain("bye")
+    });
+}
+
+#[tokio::test]
+async fn sessions_are_kept_in_memory() {
+    let (framework, server) = start().await;
+    register(framework);
+
+    let (_, head, _) = request(server.port(), "GET", "/login/", &[], b"").await;
+    let session = cookie(&head);
+    assert!(session.starts_with("__session="));
+    assert_eq!(framework.stats.read().unwrap().performance.online, 1);
+
+    let (_, head, body) = request(server.port(), "GET", "/profile/", &[("Cookie", &session)], b"").await;
+    assert_eq!(body, b"peter");
+    assert_eq!(header(&head, "set-cookie"), None);
+
+    // A forged cookie doesn't load the session
+    let (_, _, body) = request(server.port(), "GET", "/profile/", &[("Cookie", "__session=abc")], b"").await;
+    assert_eq!(body, b"");
+
+    let (_, head, _) = request(server.port(), "GET", "/logout/", &[("Cookie", &session)], b"").await;
+    assert!(header(&head, "set-cookie").unwrap().contains("Expires=Thu, 01 Jan 1970"));
+    assert_eq!(framework.stats.read().unwrap().performance.online, 0);
+
+    let (_, _, body) = request(server.port(), "GET", "/profile/", &[("Cookie", &session)], b"").await;
+    assert_eq!(body, b"");
+}
+
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("total5-sessions-{}", std::process::id()))
+}
+
+#[tokio::test]
+async fn sessions_are_stored_in_files() {
+    let (framework, server) = start_with(|| TPath::new(directory())).await;
+    framework.set_session_storage(FileSessions::new(&framework.path));
+    register(framework);
+
+    let (_, head, _) = request(server.port(), "GET", "/login/", &[], b"").await;
+    let session = cookie(&head);
+
+    // Another backend instance reads the same files
+    framework.set_session_storage(FileSessions::new(&framework.path));
+    let (_, _, body) = request(server.port(), "GET", "/profile/", &[("Cookie", &session)], b"").await;
+    assert_eq!(body, b"peter");
+
+    framework.session_cleanup();
+    assert_eq!(framework.stats.read().unwrap().performance.online, 1);
+
+    request(server.port(), "GET", "/logout/", &[("Cookie", &session)], b"").await;
+    framework.session_cleanup();
+    assert_eq!(framework.stats.read().unwrap().performance.online, 0);
+
+    server.close();
+    let _ = std::fs::remove_dir_all(directory());
+}
+
+#[test]
+fn idle_sessions_expire() {
+    let storage = MemorySessions::default();
+    let session = |id: &str, expires| Session { id: id.to_string(), data: FrameworkValue::Null, expires };
+
+    storage.set(session("active", Utc::now() + Duration::minutes(5)));
+    storage.set(session("idle", Utc::now() - Duration::seconds(1)));
+
+    assert!(storage.get("active").is_some());
+    assert!(storage.get("idle").is_none());
+    assert_eq!(storage.clean(), 1);
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use std::time::Duration;
+use common::{dechunk, header, start};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use total5::*;
+
+/// Reads the response until the body contains `expected`, returns the head and the decoded body
+async fn read_until(stream: &mut TcpStream, expected: &str) -> (String, String) {
+    let mut output = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        let read = stream.read(&mut buffer).await.unwrap();
+        assert!(read > 0, "the connection has been closed");
+        output.extend_from_slice(&buffer[..read]);
+
+        if let Some(index) = output.windows(4).position(|window| window == b"\r\n\r\n") {
+            let body = String::from_utf8(dechunk(&output[index + 4..])).unwrap();
+            if body.contains(expected) {
+                return (String::from_utf8_lossy(&output[..index]).to_string(), body);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn streams_events_until_disconnect() {
+    let (framework, server) = start().await;
+    let (disconnected, mut detected) = mpsc::unbounded_channel();
+
+    framework.route("GET /events/", move |ctrl| {
+        let events = ctrl.sse();
+        let disconnected = disconnected.clone();
+
+        tokio::spawn(async move {
+            let resumed = events.last_event_id.clone().unwrap_or_default();
+            events.send(SseEvent { event: Some("resume".to_string()), retry: Some(1000), data: resumed, ..Default::default() }).await;
+
+            let mut id = 0;
+            loop {
+                id += 1;
+                let event = SseEvent { id: Some(id.to_string()), data: format!("tick\n{}", id), ..Default::default() };
+                if !events.send(event).await {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            assert!(events.is_closed());
+            disconnected.send(id).unwrap();
+        });
+    });
+
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port())).await.unwrap();
+    stream.write_all(b"GET /events/ HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 41\r\n\r\n").await.unwrap();
+
+    let (head, body) = read_until(&mut stream, "id: 2\n").await;
+    assert!(head.starts_with("HTTP/1.1 200 "));
+    assert_eq!(header(&head, "content-type"), Some("text/event-stream; charset=utf-8"));
+    assert_eq!(header(&head, "cache-control"), Some("no-cache"));
+    assert!(body.starts_with("event: resume\nretry: 1000\ndata: 41\n\nid: 1\ndata: tick\ndata: 1\n\nid: 2\n"));
+
+    // The producer stops when the client goes away
+    drop(stream);
+    let id = tokio::time::timeout(Duration::from_secs(5), detected.recv()).await.unwrap().unwrap();
+    assert!(id >= 2);
+
+    assert_eq!(framework.stats.read().unwrap().response.sse, 1);
+}
@@ -0,0 +1,173 @@
+mod common;
+
+use std::path::PathBuf;
+use std::sync::Once;
+use common::{header, request};
+use once_cell::sync::Lazy;
+use total5::*;
+
+fn directory() -> PathBuf {
+    std::env::temp_dir().join(format!("total5-static-{}", std::process::id()))
+}
+
+/// Starts a server serving `TPath::public` from a temporary directory
+async fn start() -> (&'static Framework, HttpServer) {
+    let framework: &'static Framework = Box::leak(Box::new(Framework {
+        path: Lazy::new(|| TPath::new(directory())),
+        ..Framework::default()
+    }));
+
+    // The files are written once, rewriting them would change the ETags of running tests
+    static FILES: Once = Once::new();
+    FILES.call_once(|| {
+        let public = framework.path.public(None);
+        std::fs::create_dir_all(public.join("css")).unwrap();
+        std::fs::write(public.join("css/site.css"), "body{color:red}").unwrap();
+        std::fs::write(public.join("secret.env"), "TOKEN=1").unwrap();
+        std::fs::write(public.join("data.bin"), [1, 2, 3]).unwrap();
+        std::fs::write(public.join("video.mp4"), (0..=255u8).cycle().take(10_000).collect::<Vec<u8>>()).unwrap();
+    });
+
+    let server = framework.http("test").await.unwrap();
+    (framework, server)
+}
+
+#[tokio::test]
+async fn serves_files_with_validators() {
+    let (framework, server) = start().await;
+
+    let (status, head, body) = request(server.port(), "GET", "/css/site.css?v=1", &[], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, b"body{color:red}");
+    assert_eq!(header(&head, "content-type"), Some("text/css; charset=utf-8"));
+    assert_eq!(header(&head, "cache-control"), Some("public, max-age=60"));
+
+    let etag = header(&head, "etag").unwrap().to_string();
+    let modified = header(&head, "last-modified").unwrap().to_string();
+
+    let (status, head, body) = request(server.port(), "GET", "/css/site.css", &[("If-None-Match", &etag)], b"").await;
+    assert_eq!(status, 304);
+    assert!(body.is_empty());
+    assert_eq!(header(&head, "etag"), Some(etag.as_str()));
+
+    let (status, _, _) = request(server.port(), "GET", "/css/site.css", &[("If-Modified-Since", &modified)], b"").await;
+    assert_eq!(status, 304);
+
+    let (status, _, _) = request(server.port(), "GET", "/css/site.css", &[("If-None-Match", "\"other\"")], b"").await;
+    assert_eq!(status, 200);
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.response.file, 2);
+    assert_eq!(stats.response.notmodified, 2);
+}
+
+#[tokio::test]
+async fn refuses_unknown_extensions_and_traversal() {
+    let (_, server) = start().await;
+
+    let (status, _, _) = request(server.port(), "GET", "/secret.env", &[], b"").await;
+    assert_eq!(status, 404);
+
+    let (status, _, _) = request(server.port(), "GET", "/css/../../../etc/hosts.txt", &[], b"").await;
+    assert_eq!(status, 404);
+
+    let (status, _, _) = request(server.port(), "GET", "/css/%2e%2e/%2e%2e/site.css", &[], b"").await;
+    assert_eq!(status, 404);
+
+    let (status, _, _) = request(server.port(), "GET", "/missing.css", &[], b"").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn checks_mime_types() {
+    let (_, server) = start().await;
+    CONF.write().unwrap()._httpfiles.insert("bin".to_string(), true);
+
+    // Allowed extensions without a known MIME type are refused by `_httpchecktypes`
+    let (status, _, _) = request(server.port(), "GET", "/data.bin", &[], b"").await;
+    assert_eq!(status, 404);
+
+    CONF.write().unwrap()._httpchecktypes = false;
+    let (status, head, body) = request(server.port(), "GET", "/data.bin", &[], b"").await;
+    CONF.write().unwrap()._httpchecktypes = true;
+
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "content-type"), Some("application/octet-stream"));
+    assert_eq!(body, [1, 2, 3]);
+}
+
+#[tokio::test]
+async fn serves_byte_ranges() {
+    let (framework, server) = start().await;
+    let video: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+
+    let (status, head, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=100-199")], b"").await;
+    assert_eq!(status, 206);
+    assert_eq!(header(&head, "content-range"), Some("bytes 100-199/10000"));
+    assert_eq!(header(&head, "content-length"), Some("100"));
+    assert_eq!(body, &video[100..200]);
+
+    let (status, head, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=-10")], b"").await;
+    assert_eq!(status, 206);
+    assert_eq!(header(&head, "content-range"), Some("bytes 9990-9999/10000"));
+    assert_eq!(body, &video[9990..]);
+
+    let (status, head, _) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=20000-")], b"").await;
+    assert_eq!(status, 416);
+    assert_eq!(header(&head, "content-range"), Some("bytes */10000"));
+
+    // A stale `If-Range` returns the whole file
+    let (status, _, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=0-9"), ("If-Range", "\"old\"")], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(body.len(), 10_000);
+
+    assert_eq!(framework.stats.read().unwrap().response.streaming, 2);
+}
+
+#[tokio::test]
+async fn open_ranges_are_limited_by_the_buffer() {
+    let (_, server) = start().await;
+
+    CONF.write().unwrap()._httprangebuffer = 4;
+    let (status, head, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=1000-")], b"").await;
+    CONF.write().unwrap()._httprangebuffer = 5120;
+
+    assert_eq!(status, 206);
+    assert_eq!(header(&head, "content-range"), Some("bytes 1000-5095/10000"));
+    assert_eq!(body.len(), 4096);
+}
+
+#[tokio::test]
+async fn serves_multiple_ranges() {
+    let (_, server) = start().await;
+
+    let (status, head, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=0-3, 256-259")], b"").await;
+    assert_eq!(status, 206);
+
+    let content_type = header(&head, "content-type").unwrap();
+    let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert_eq!(body.matches(&format!("--{}\r\n", boundary)).count(), 2);
+    assert!(body.contains("Content-Range: bytes 0-3/10000"));
+    assert!(body.contains("Content-Range: bytes 256-259/10000"));
+    assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+
+    // Overlapping ranges are merged
+    let (status, head, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=0-3, 2-5")], b"").await;
+    assert_eq!(status, 206);
+    assert_eq!(header(&head, "content-range"), Some("bytes 0-5/10000"));
+    assert_eq!(body, [0, 1, 2, 3, 4, 5]);
+
+    // Too many ranges return the whole file
+    let ranges = (0..20).map(|index| format!("{}-{}", index * 100, index * 100 + 1)).collect::<Vec<_>>().join(",");
+    let (status, _, body) = request(server.port(), "GET", "/video.mp4", &[("Range", &format!("bytes={}", ranges))], b"").await;
+    assert_eq!(status, 200);
+    assert_eq!(body.len(), 10_000);
+
+    // Parts are streamed from the disk
+    let video: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+    let (status, _, body) = request(server.port(), "GET", "/video.mp4", &[("Range", "bytes=0-0, 2-9999")], b"").await;
+    assert_eq!(status, 206);
+    assert!(String::from_utf8_lossy(&body).contains("Content-Range: bytes 2-9999/10000"));
+    assert!(body.windows(9998).any(|part| part == &video[2..]));
+}
@@ -0,0 +1,76 @@
+#![cfg(unix)]
+
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use total5::*;
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("total5-{}-{}", name, std::process::id()))
+        .join("nested")
+        .join("app.socket")
+}
+
+async fn request(path: &PathBuf, raw: &str) -> String {
+    let mut stream = UnixStream::connect(path).await.unwrap();
+    stream.write_all(raw.as_bytes()).await.unwrap();
+    let mut output = String::new();
+    stream.read_to_string(&mut output).await.unwrap();
+    output
+}
+
+#[tokio::test]
+async fn http_over_unixsocket() {
+    let path = socket_path("unixsocket");
+    let _ = std::fs::remove_dir_all(path.parent().unwrap().parent().unwrap());
+
+    CONF.write().unwrap()._unixsocket = path.to_string_lossy().to_string();
+
+    let framework: &'static Framework = Box::leak(Box::new(Framework::default()));
+    let server = framework.http("test").await.unwrap();
+
+    assert_eq!(server.unixsocket(), Some(path.as_path()));
+    assert!(server.address().is_none());
+
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+
+    let output = request(&path, "GET /unknown/ HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 10.0.0.1\r\nConnection: close\r\n\r\n").await;
+    assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(output.contains("Connection: close\r\n"));
+
+    {
+        let stats = framework.stats.read().unwrap();
+        assert_eq!(stats.request.request, 1);
+        assert_eq!(stats.request.get, 1);
+        assert_eq!(stats.request.pending, 0);
+    }
+
+    server.close();
+    server.wait().await;
+    assert!(!path.exists());
+
+    // Other files are never removed
+    std::fs::write(&path, b"data").unwrap();
+    assert!(framework.http("test").await.is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"data");
+    std::fs::remove_file(&path).unwrap();
+
+    // A stale socket left by a previous process must not prevent the start
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let server = framework.http("test").await.unwrap();
+    let output = request(&path, "HEAD / HTTP/1.0\r\n\r\n").await;
+    assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(output.ends_with("\r\n\r\n"));
+
+    server.close();
+    server.wait().await;
+
+    CONF.write().unwrap()._unixsocket = String::new();
+    let _ = std::fs::remove_dir_all(path.parent().unwrap().parent().unwrap());
+}
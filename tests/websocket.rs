@@ -0,0 +1,280 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use common::{header, request, start, ws_connect, ws_read, ws_send, ws_write};
+use total5::*;
+
+#[tokio::test]
+async fn broadcasts_json_messages() {
+    let (framework, server) = start().await;
+
+    let chat = framework.websocket("SOCKET /chat/{room}/ @json");
+    let room = chat.clone();
+
+    chat.on_open(|client| {
+        let name = client.query.get("name").cloned().unwrap_or_default();
+        client.set("name", name);
+    });
+
+    chat.on_message(move |client, message| {
+        let WebSocketMessage::Json(value) = message else { return };
+        let name = client.get("name").unwrap_or_default().to_string();
+        let output = serde_json::json!({ "from": name, "room": client.params["room"].to_string(), "message": serde_json::Value::from(value) });
+
+        // Everybody else in the room
+        room.send_filter(output, |other| other.id != client.id);
+    });
+
+    let (mut peter, head) = ws_connect(server.port(), "/chat/lobby/?name=peter", &[]).await;
+    assert!(head.starts_with("HTTP/1.1 101 "));
+    assert_eq!(header(&head, "sec-websocket-accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert_eq!(header(&head, "connection"), Some("Upgrade"));
+    assert_eq!(header(&head, "content-length"), None);
+
+    let (mut louis, _) = ws_connect(server.port(), "/chat/lobby/?name=louis", &[]).await;
+
+    // Waits for both clients to be registered
+    while chat.clients().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    ws_send(&mut peter, r#"{"text":"Hello"}"#).await;
+    let (first, payload) = ws_read(&mut louis).await;
+    assert_eq!(first, 0x81);
+    assert_eq!(String::from_utf8(payload).unwrap(), r#"{"from":"peter","message":{"text":"Hello"},"room":"lobby"}"#);
+
+    // Fragmented messages are assembled
+    ws_write(&mut louis, 0x01, br#"{"text":"#).await;
+    ws_write(&mut louis, 0x80, br#""Hi"}"#).await;
+    let (_, payload) = ws_read(&mut peter).await;
+    assert_eq!(String::from_utf8(payload).unwrap(), r#"{"from":"louis","message":{"text":"Hi"},"room":"lobby"}"#);
+
+    assert_eq!(chat.send(FrameworkValue::from("news")), 2);
+    assert_eq!(ws_read(&mut peter).await.1, b"\"news\"");
+
+    // Invalid JSON closes the connection with 1007
+    ws_send(&mut peter, "{").await;
+    let (first, payload) = ws_read(&mut peter).await;
+    assert_eq!(first, 0x88);
+    assert_eq!(payload, 1007u16.to_be_bytes());
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.request.websocket, 2);
+    assert_eq!(stats.response.websocket, 2);
+}
+
+#[tokio::test]
+async fn closes_connections() {
+    let (framework, server) = start().await;
+    let codes = Arc::new(Mutex::new(Vec::new()));
+
+    let echo = framework.websocket("SOCKET /echo/ <1kB");
+    echo.on_message(|client, message| {
+        match message {
+            WebSocketMessage::Text(text) if text == "bye" => client.close(4000, "bye"),
+            message => {
+                client.send(message);
+            }
+        }
+    });
+
+    let closed = codes.clone();
+    echo.on_close(move |_, code| closed.lock().unwrap().push(code));
+
+    let (mut client, _) = ws_connect(server.port(), "/echo/", &[]).await;
+
+    ws_write(&mut client, 0x82, &[1, 2, 3]).await;
+    assert_eq!(ws_read(&mut client).await, (0x82, vec![1, 2, 3]));
+
+    // Ping is answered with the same payload
+    ws_write(&mut client, 0x89, b"ping").await;
+    assert_eq!(ws_read(&mut client).await, (0x8A, b"ping".to_vec()));
+
+    // The client closes the connection
+    ws_write(&mut client, 0x88, &1000u16.to_be_bytes()).await;
+    assert_eq!(ws_read(&mut client).await, (0x88, 1000u16.to_be_bytes().to_vec()));
+
+    // The server closes the connection
+    let (mut client, _) = ws_connect(server.port(), "/echo/", &[]).await;
+    ws_send(&mut client, "bye").await;
+    let (first, payload) = ws_read(&mut client).await;
+    assert_eq!(first, 0x88);
+    assert_eq!(payload, [&4000u16.to_be_bytes()[..], b"bye"].concat());
+    ws_write(&mut client, 0x88, &4000u16.to_be_bytes()).await;
+
+    // Invalid close codes are answered with 1002
+    let (mut client, _) = ws_connect(server.port(), "/echo/", &[]).await;
+    ws_write(&mut client, 0x88, &1005u16.to_be_bytes()).await;
+    assert_eq!(ws_read(&mut client).await, (0x88, 1002u16.to_be_bytes().to_vec()));
+
+    // Messages above the route limit (`<1kB`) close the connection with 1009
+    let (mut client, _) = ws_connect(server.port(), "/echo/", &[]).await;
+    ws_send(&mut client, &"x".repeat(2048)).await;
+    assert_eq!(ws_read(&mut client).await, (0x88, 1009u16.to_be_bytes().to_vec()));
+
+    while codes.lock().unwrap().len() < 4 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(*codes.lock().unwrap(), [1000, 4000, 1002, 1009]);
+    assert!(echo.clients().is_empty());
+}
+
+#[tokio::test]
+async fn rejects_invalid_handshakes() {
+    let (framework, server) = start().await;
+    framework.websocket("+SOCKET /private/");
+    framework.websocket("SOCKET /public/");
+
+    let (_, head) = ws_connect(server.port(), "/private/", &[]).await;
+    assert!(head.starts_with("HTTP/1.1 401 "));
+
+    let (_, head) = ws_connect(server.port(), "/public/", &[("Sec-WebSocket-Version", "8")]).await;
+    assert!(head.starts_with("HTTP/1.1 426 "));
+    assert_eq!(header(&head, "sec-websocket-version"), Some("13"));
+
+    let upgrade = [("Upgrade", "websocket"), ("Connection", "Upgrade"), ("Sec-WebSocket-Version", "13")];
+    let (status, _, _) = request(server.port(), "GET", "/public/", &upgrade, b"").await;
+    assert_eq!(status, 400);
+
+    // Unknown WebSocket paths are regular requests
+    let (_, head) = ws_connect(server.port(), "/unknown/", &[]).await;
+    assert!(head.starts_with("HTTP/1.1 404 "));
+}
+
+#[tokio::test]
+async fn compresses_messages() {
+    use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+    let (framework, server) = start().await;
+    let echo = framework.websocket("SOCKET /compressed/");
+    echo.on_message(|client, message| {
+        client.send(message);
+    });
+
+    let offer = [("Sec-WebSocket-Extensions", "permessage-deflate; client_max_window_bits; client_no_context_takeover")];
+    let (mut client, head) = ws_connect(server.port(), "/compressed/", &offer).await;
+    assert_eq!(header(&head, "sec-websocket-extensions"), Some("permessage-deflate; client_no_context_takeover"));
+
+    let message = "Hello Total.js ".repeat(50);
+    let mut compress = Compress::new(Compression::default(), false);
+    let mut compressed = Vec::with_capacity(1024);
+    compress.compress_vec(message.as_bytes(), &mut compressed, FlushCompress::Sync).unwrap();
+    compressed.truncate(compressed.len() - 4);
+
+    // RSV1 marks the compressed message
+    ws_write(&mut client, 0xC1, &compressed).await;
+    let (first, payload) = ws_read(&mut client).await;
+    assert_eq!(first, 0xC1);
+    assert!(payload.len() < message.len());
+
+    let mut decompress = Decompress::new(false);
+    let mut output = Vec::with_capacity(2048);
+    decompress.decompress_vec(&[payload, vec![0, 0, 0xFF, 0xFF]].concat(), &mut output, FlushDecompress::Sync).unwrap();
+    assert_eq!(output, message.as_bytes());
+
+    // Uncompressed messages are still accepted
+    ws_send(&mut client, "plain").await;
+    assert_eq!(ws_read(&mut client).await.0, 0xC1);
+
+    // Clients without the extension get uncompressed frames
+    let (mut client, head) = ws_connect(server.port(), "/compressed/", &[]).await;
+    assert_eq!(header(&head, "sec-websocket-extensions"), None);
+    ws_send(&mut client, "plain").await;
+    assert_eq!(ws_read(&mut client).await, (0x81, b"plain".to_vec()));
+}
+
+#[tokio::test]
+async fn closes_unresponsive_clients() {
+    let (framework, server) = start().await;
+    let route = framework.websocket("SOCKET /live/");
+
+    let (mut alive, _) = ws_connect(server.port(), "/live/", &[]).await;
+    let (mut dead, _) = ws_connect(server.port(), "/live/", &[]).await;
+    while route.clients().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    framework.websocket_ping();
+    assert_eq!(ws_read(&mut alive).await, (0x89, vec![]));
+    assert_eq!(ws_read(&mut dead).await, (0x89, vec![]));
+
+    // The pong measures the latency
+    ws_write(&mut alive, 0x8A, b"").await;
+    let latency = loop {
+        if let Some(latency) = route.clients().iter().find_map(|client| client.latency()) {
+            break latency;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    };
+    assert!(latency < Duration::from_secs(1));
+
+    // The unanswered ping exceeds the maximum latency
+    CONF.write().unwrap()._wsmaxlatency = 100;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    framework.websocket_ping();
+    CONF.write().unwrap()._wsmaxlatency = 2000;
+
+    assert_eq!(ws_read(&mut alive).await, (0x89, vec![]));
+    assert_eq!(ws_read(&mut dead).await, (0x88, [&1001u16.to_be_bytes()[..], b"latency"].concat()));
+    ws_write(&mut dead, 0x88, &1001u16.to_be_bytes()).await;
+
+    while route.clients().len() > 1 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let stats = framework.stats.read().unwrap();
+    assert_eq!(stats.other.websocketping, 3);
+    assert_eq!(stats.other.websocketcleaner, 1);
+}
+
+#[tokio::test]
+async fn closes_clients_with_full_queues() {
+    let (framework, server) = start().await;
+    let route = framework.websocket("SOCKET /flood/");
+
+    let (mut client, _) = ws_connect(server.port(), "/flood/", &[]).await;
+    while route.clients().is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    // Nothing is written in the meantime, the queue holds 256 frames
+    let sent = (0..1000).filter(|_| route.send("message") == 1).count();
+    assert_eq!(sent, 256);
+
+    // The close frame skips the queued messages
+    assert_eq!(ws_read(&mut client).await, (0x88, [&1008u16.to_be_bytes()[..], b"overflow"].concat()));
+    ws_write(&mut client, 0x88, &1008u16.to_be_bytes()).await;
+
+    while !route.clients().is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn drops_clients_which_stop_reading() {
+    let (framework, server) = start().await;
+    let route = framework.websocket("SOCKET /stuck/");
+
+    let codes = Arc::new(Mutex::new(Vec::new()));
+    let closed = codes.clone();
+    route.on_close(move |_, code| closed.lock().unwrap().push(code));
+
+    // The client never reads, so the writer gets stuck once the socket buffers are full
+    let (_client, _) = ws_connect(server.port(), "/stuck/", &[]).await;
+    while route.clients().is_empty() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    let started = std::time::Instant::now();
+    while route.send(vec![0u8; 64 * 1024]) == 1 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    // The queue overflows and the connection is dropped after the close timeout (5 seconds)
+    while !route.clients().is_empty() {
+        assert!(started.elapsed() < Duration::from_secs(15), "the client hasn't been dropped");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(*codes.lock().unwrap(), [1006]);
+}
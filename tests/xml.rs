@@ -0,0 +1,28 @@
+mod common;
+
+use common::{header, request, start};
+use total5::*;
+
+#[tokio::test]
+async fn parses_xml_bodies_and_answers_with_xml() {
+    let (framework, server) = start().await;
+
+    framework.route("POST /partners/orders/", |ctrl| {
+        let order = match &ctrl.body {
+            FrameworkValue::Object(root) => root.get("order").cloned().unwrap_or_default(),
+            _ => FrameworkValue::Null,
+        };
+        ctrl.xml_value("received", order)
+    });
+
+    let body = br#"<?xml version="1.0"?><order id="7"><item>Keyboard &amp; Mouse</item></order>"#;
+    let (status, head, body) = request(server.port(), "POST", "/partners/orders/", &[("Content-Type", "application/xml")], body).await;
+    assert_eq!(status, 200);
+    assert_eq!(header(&head, "content-type"), Some("text/xml; charset=utf-8"));
+    assert_eq!(body, br#"<?xml version="1.0" encoding="utf-8"?><received id="7"><item>Keyboard &amp; Mouse</item></received>"#);
+
+    let (status, _, _) = request(server.port(), "POST", "/partners/orders/", &[("Content-Type", "text/xml")], b"<order>").await;
+    assert_eq!(status, 400);
+
+    assert_eq!(framework.stats.read().unwrap().response.xml, 1);
+}